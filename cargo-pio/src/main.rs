@@ -131,6 +131,50 @@ enum Command {
         #[structopt(required = false, allow_hyphen_values = true, last = true)]
         pio_args: Vec<OsString>,
     },
+    /// Compares the memory usage of two ELF builds and fails if any growth exceeds the given thresholds
+    SizeDiff {
+        /// The ELF file of the old build
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+
+        /// The ELF file of the new build
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+
+        /// Linker map file of the old build, to compare sizes by crate and ESP-IDF component
+        ///
+        /// Requires --new-map as well
+        #[structopt(long, parse(from_os_str))]
+        old_map: Option<PathBuf>,
+
+        /// Linker map file of the new build, to compare sizes by crate and ESP-IDF component
+        ///
+        /// Requires --old-map as well
+        #[structopt(long, parse(from_os_str))]
+        new_map: Option<PathBuf>,
+
+        /// Linker script declaring the memory regions of the target (can be repeated)
+        ///
+        /// If not specified, the memory configuration of the new map file is used (if any)
+        #[structopt(long = "linker-script", parse(from_os_str))]
+        linker_scripts: Vec<PathBuf>,
+
+        /// Maximum growth in bytes of any memory region
+        #[structopt(long)]
+        max_region_growth: Option<u64>,
+
+        /// Maximum growth in bytes of any section
+        #[structopt(long)]
+        max_section_growth: Option<u64>,
+
+        /// Maximum growth in bytes of any symbol
+        #[structopt(long)]
+        max_symbol_growth: Option<u64>,
+
+        /// Maximum growth in bytes of any crate or ESP-IDF component
+        #[structopt(long)]
+        max_library_growth: Option<u64>,
+    },
     /// Invokes commands specific for the ESP-IDF SDK
     Espidf {
         #[structopt(flatten)]
@@ -242,6 +286,16 @@ enum EspidfCommand {
         #[structopt(long, short = "e")]
         environment: Option<String>,
     },
+    /// Prints information about an ESP application or bootloader image and verifies its checksum and SHA-256 digest
+    ImageInfo {
+        /// The image file
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+
+        /// ELF file the image should have been generated from
+        #[structopt(long, parse(from_os_str))]
+        elf: Option<PathBuf>,
+    },
 }
 
 fn parse_build_std(s: &str) -> cargo::BuildStd {
@@ -465,6 +519,61 @@ fn main() -> Result<()> {
                 },
             )
         }
+        Command::Espidf {
+            cmd: EspidfCommand::ImageInfo { image, elf },
+            ..
+        } => {
+            let info = esp_image::info::ImageInfo::from_file(image)?;
+
+            print!("{}", info);
+
+            info.verify()?;
+
+            if let Some(elf) = elf {
+                info.verify_elf(elf)?;
+
+                println!("Image matches the ELF file");
+            }
+
+            Ok(())
+        }
+        Command::SizeDiff {
+            old,
+            new,
+            old_map,
+            new_map,
+            linker_scripts,
+            max_region_growth,
+            max_section_growth,
+            max_symbol_growth,
+            max_library_growth,
+        } => {
+            let mut regions = Vec::new();
+            for linker_script in &linker_scripts {
+                regions.extend(size::MemoryRegion::from_linker_script_file(linker_script)?);
+            }
+
+            if regions.is_empty() {
+                if let Some(new_map) = &new_map {
+                    regions =
+                        size::MemoryRegion::from_linker_map(&ldmap::LinkerMap::from_file(new_map)?);
+                }
+            }
+
+            let old = size::diff::Snapshot::from_files(old, old_map.as_deref(), &regions)?;
+            let new = size::diff::Snapshot::from_files(new, new_map.as_deref(), &regions)?;
+
+            let diff = size::diff::SizeDiff::new(&old, &new)?;
+
+            print!("{}", diff);
+
+            diff.check(&size::diff::Thresholds {
+                region: max_region_growth,
+                section: max_section_growth,
+                symbol: max_symbol_growth,
+                library: max_library_growth,
+            })
+        }
     }
 }
 
@@ -3,22 +3,117 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{cmp, env};
 
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 use xmas_elf::ElfFile;
 
+mod ihex;
+mod srec;
+pub mod uf2;
+
 pub const VAR_BIN_FILE: &str = "EMBUILD_GENERATED_BIN_FILE";
 
+/// The output format of [`Bingen`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// A raw binary image starting at the [base address](Bingen::base_addr), with the
+    /// gaps between segments filled with the [fill byte](Bingen::fill).
+    Binary,
+    /// An Intel HEX file.
+    IntelHex,
+    /// A Motorola S-record file.
+    SRecord,
+    /// A UF2 file for USB mass storage bootloaders.
+    Uf2 {
+        /// The family id of the target, see the `FAMILY_ID_*` constants in [`uf2`].
+        family_id: Option<u32>,
+        /// The number of payload bytes per block, at most [`uf2::MAX_PAYLOAD_SIZE`]
+        /// (usually [`uf2::DEFAULT_PAYLOAD_SIZE`]).
+        payload_size: u32,
+    },
+}
+
+impl Format {
+    /// The conventional file extension of this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Binary => "bin",
+            Self::IntelHex => "hex",
+            Self::SRecord => "srec",
+            Self::Uf2 { .. } => "uf2",
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::Binary
+    }
+}
+
 pub struct Bingen {
     elf: PathBuf,
+    format: Format,
+    base_addr: u64,
+    fill: u8,
+    max_gap: Option<u64>,
+    lma: bool,
 }
 
 impl Bingen {
     pub fn new(elf: impl Into<PathBuf>) -> Self {
-        Self { elf: elf.into() }
+        Self {
+            elf: elf.into(),
+            format: Format::default(),
+            base_addr: 0,
+            fill: 0,
+            max_gap: None,
+            lma: false,
+        }
+    }
+
+    /// Set the output format.
+    #[must_use]
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set the address the binary output starts at (defaults to `0`).
+    #[must_use]
+    pub fn base_addr(mut self, base_addr: u64) -> Self {
+        self.base_addr = base_addr;
+        self
+    }
+
+    /// Set the byte used to fill the gaps between segments in the binary and UF2 output
+    /// (defaults to `0`).
+    #[must_use]
+    pub fn fill(mut self, fill: u8) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Start a new region whenever the gap between two segments is larger than
+    /// `max_gap` bytes.
+    ///
+    /// Each region is written to its own file by [`Bingen::run_for_regions`].
+    #[must_use]
+    pub fn max_gap(mut self, max_gap: Option<u64>) -> Self {
+        self.max_gap = max_gap;
+        self
+    }
+
+    /// Whether to place segments at their load (physical) address instead of their
+    /// virtual address.
+    #[must_use]
+    pub fn lma(mut self, lma: bool) -> Self {
+        self.lma = lma;
+        self
     }
 
     pub fn run(&self) -> Result<PathBuf> {
-        let output_file = PathBuf::from(env::var("OUT_DIR")?).join("binary.bin");
+        let output_file =
+            PathBuf::from(env::var("OUT_DIR")?).join(format!("binary.{}", self.format.extension()));
 
         self.run_for_file(&output_file)?;
 
@@ -35,45 +130,199 @@ impl Bingen {
         self.write(&mut File::create(output_file)?)
     }
 
+    /// Write every region to its own file next to `output_file`, named
+    /// `<stem>.<address>.<extension>`, and return the start address and path of each.
+    pub fn run_for_regions(&self, output_file: impl AsRef<Path>) -> Result<Vec<(u64, PathBuf)>> {
+        let output_file = output_file.as_ref();
+
+        eprintln!("Input: {:?}", self.elf);
+
+        let elf_data = fs::read(&self.elf)?;
+        let elf = ElfFile::new(&elf_data).map_err(Error::msg)?;
+
+        let sorted = self.segments(&elf)?;
+
+        self.regions(&sorted)
+            .into_iter()
+            .map(|(addr, region)| {
+                let region_file = output_file.with_file_name(format!(
+                    "{}.{addr:08x}.{}",
+                    output_file
+                        .file_stem()
+                        .unwrap_or_default()
+                        .to_string_lossy(),
+                    self.format.extension()
+                ));
+
+                eprintln!("Output: {region_file:?}");
+
+                self.write_segments(&elf, addr, region, &mut File::create(&region_file)?)?;
+
+                Ok((addr, region_file))
+            })
+            .collect()
+    }
+
     pub fn write(&self, output: &mut impl Write) -> Result<()> {
         eprintln!("Input: {:?}", self.elf);
 
         let elf_data = fs::read(&self.elf)?;
         let elf = ElfFile::new(&elf_data).map_err(Error::msg)?;
 
-        let mut sorted = segments::segments(&elf).collect::<Vec<_>>();
+        let sorted = self.segments(&elf)?;
+
+        let regions = self.regions(&sorted);
+        if self.format == Format::Binary && regions.len() > 1 {
+            bail!(
+                "The segments of {:?} form {} separate regions, use `Bingen::run_for_regions` instead",
+                self.elf,
+                regions.len()
+            );
+        }
+
+        self.write_segments(&elf, self.base_addr, &sorted, output)
+    }
+
+    fn segments<'a>(&self, elf: &'a ElfFile<'a>) -> Result<Vec<segments::CodeSegment<'a>>> {
+        let mut sorted = segments::segments(elf, self.lma).collect::<Vec<_>>();
         sorted.sort();
 
-        let mut offset: u64 = 0;
-        for segment in sorted {
-            let buf = [0_u8; 4096];
-            while offset < segment.addr {
-                let delta = cmp::min(buf.len() as u64, segment.addr - offset) as usize;
+        if let Some(segment) = sorted.first() {
+            if segment.addr < self.base_addr {
+                bail!(
+                    "Segment at 0x{:x} is below the base address 0x{:x}",
+                    segment.addr,
+                    self.base_addr
+                );
+            }
+        }
+
+        Ok(sorted)
+    }
+
+    /// Split `sorted` into regions separated by gaps larger than `max_gap`, along with
+    /// the start address of each region.
+    fn regions<'a, 'b>(
+        &self,
+        sorted: &'b [segments::CodeSegment<'a>],
+    ) -> Vec<(u64, &'b [segments::CodeSegment<'a>])> {
+        let mut regions = Vec::new();
+        let mut start = 0;
 
-                output.write_all(&buf[0..delta])?;
+        for index in 1..sorted.len() {
+            let prev = &sorted[index - 1];
+            let gap = sorted[index]
+                .addr
+                .saturating_sub(prev.addr + prev.data.len() as u64);
 
-                offset += delta as u64;
+            if matches!(self.max_gap, Some(max_gap) if gap > max_gap) {
+                regions.push(&sorted[start..index]);
+                start = index;
             }
+        }
+
+        if start < sorted.len() {
+            regions.push(&sorted[start..]);
+        }
+
+        regions
+            .into_iter()
+            .enumerate()
+            .map(|(index, region)| {
+                let addr = if index == 0 {
+                    self.base_addr
+                } else {
+                    region[0].addr
+                };
+
+                (addr, region)
+            })
+            .collect()
+    }
+
+    fn write_segments(
+        &self,
+        elf: &ElfFile,
+        start_addr: u64,
+        sorted: &[segments::CodeSegment],
+        output: &mut impl Write,
+    ) -> Result<()> {
+        self.format.write_segments(
+            &self.elf.file_name().unwrap_or_default().to_string_lossy(),
+            start_addr,
+            self.fill,
+            Some(elf.header.pt2.entry_point()),
+            sorted,
+            output,
+        )
+    }
+}
+
+impl Format {
+    /// Write the sorted `segments` in this format.
+    ///
+    /// `name` is only used by the S-record header, `start_addr` and `fill` only by the
+    /// binary and UF2 formats.
+    pub(crate) fn write_segments(
+        &self,
+        name: &str,
+        start_addr: u64,
+        fill: u8,
+        entry: Option<u64>,
+        sorted: &[segments::CodeSegment],
+        output: &mut impl Write,
+    ) -> Result<()> {
+        match *self {
+            Format::Binary => write_binary(start_addr, fill, sorted, output),
+            Format::IntelHex => ihex::write(sorted, entry, output),
+            Format::SRecord => srec::write(sorted, name, entry, output),
+            Format::Uf2 {
+                family_id,
+                payload_size,
+            } => uf2::write(sorted, family_id, payload_size, fill, output),
+        }
+    }
+}
+
+fn write_binary(
+    start_addr: u64,
+    fill: u8,
+    sorted: &[segments::CodeSegment],
+    output: &mut impl Write,
+) -> Result<()> {
+    let buf = [fill; 4096];
+
+    let mut offset = start_addr;
+    for segment in sorted {
+        if offset > segment.addr {
+            bail!("Segment at 0x{:x} overlaps the previous one", segment.addr);
+        }
 
-            output.write_all(segment.data)?;
-            offset += segment.data.len() as u64;
+        while offset < segment.addr {
+            let delta = cmp::min(buf.len() as u64, segment.addr - offset) as usize;
+
+            output.write_all(&buf[0..delta])?;
+
+            offset += delta as u64;
         }
 
-        Ok(())
+        output.write_all(segment.data)?;
+        offset += segment.data.len() as u64;
     }
+
+    Ok(())
 }
 
-mod segments {
+pub(crate) mod segments {
     use std::cmp::Ordering;
 
     use xmas_elf::program::{SegmentData, Type};
     use xmas_elf::ElfFile;
 
     /// A segment of code from the source elf
-    #[derive(Debug, Ord, Eq)]
+    #[derive(Debug, Eq)]
     pub struct CodeSegment<'a> {
         pub addr: u64,
-        pub size: u64,
         pub data: &'a [u8],
     }
 
@@ -85,23 +334,75 @@ mod segments {
 
     impl PartialOrd for CodeSegment<'_> {
         fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-            self.addr.partial_cmp(&other.addr)
+            Some(self.cmp(other))
         }
     }
 
-    pub fn segments<'a>(elf: &'a ElfFile<'a>) -> impl Iterator<Item = CodeSegment<'a>> + 'a {
+    impl Ord for CodeSegment<'_> {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.addr.cmp(&other.addr)
+        }
+    }
+
+    /// Get all non-empty LOAD segments at their virtual address, or their physical
+    /// address if `lma` is `true`.
+    pub fn segments<'a>(
+        elf: &'a ElfFile<'a>,
+        lma: bool,
+    ) -> impl Iterator<Item = CodeSegment<'a>> + 'a {
         elf.program_iter()
             .filter(|header| {
                 header.file_size() > 0 && header.get_type() == Ok(Type::Load) && header.offset() > 0
             })
             .flat_map(move |header| {
-                let addr = header.virtual_addr();
-                let size = header.file_size();
+                let addr = if lma {
+                    header.physical_addr()
+                } else {
+                    header.virtual_addr()
+                };
                 let data = match header.get_data(elf) {
                     Ok(SegmentData::Undefined(data)) => data,
                     _ => return None,
                 };
-                Some(CodeSegment { addr, data, size })
+                Some(CodeSegment { addr, data })
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::segments::CodeSegment;
+    use super::*;
+
+    #[test]
+    fn regions() {
+        let segments = [
+            CodeSegment {
+                addr: 0x0800_0000,
+                data: &[1, 2],
+            },
+            CodeSegment {
+                addr: 0x0800_0004,
+                data: &[3],
+            },
+            CodeSegment {
+                addr: 0x2000_0000,
+                data: &[4],
+            },
+        ];
+
+        let bingen = Bingen::new("test.elf")
+            .base_addr(0x0800_0000)
+            .fill(0xff)
+            .max_gap(Some(0x100));
+
+        let regions = bingen.regions(&segments);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].0, 0x0800_0000);
+        assert_eq!(regions[1].0, 0x2000_0000);
+
+        let mut output = Vec::new();
+        write_binary(regions[0].0, 0xff, regions[0].1, &mut output).unwrap();
+        assert_eq!(output, [1, 2, 0xff, 0xff, 3]);
+    }
+}
@@ -0,0 +1,116 @@
+//! Intel HEX output.
+
+use std::io::Write;
+
+use anyhow::{bail, Result};
+
+use super::segments::CodeSegment;
+
+/// Maximum number of data bytes per data record.
+const RECORD_LEN: usize = 16;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Write `segments` as Intel HEX records, emitting an extended linear address record
+/// whenever the upper 16 bits of the address change.
+pub fn write(segments: &[CodeSegment], entry: Option<u64>, output: &mut impl Write) -> Result<()> {
+    let mut upper = None;
+
+    for segment in segments {
+        if segment.addr + segment.data.len() as u64 > 0x1_0000_0000 {
+            bail!(
+                "Segment at 0x{:x} does not fit in the 32-bit address space of Intel HEX",
+                segment.addr
+            );
+        }
+
+        let mut addr = segment.addr as u32;
+        let mut data = segment.data;
+
+        while !data.is_empty() {
+            let addr_upper = (addr >> 16) as u16;
+            if upper != Some(addr_upper) {
+                write_record(
+                    output,
+                    EXTENDED_LINEAR_ADDRESS,
+                    0,
+                    &addr_upper.to_be_bytes(),
+                )?;
+                upper = Some(addr_upper);
+            }
+
+            // Records must not cross a 64K boundary
+            let to_boundary = 0x1_0000 - (addr & 0xffff) as usize;
+            let len = RECORD_LEN.min(to_boundary).min(data.len());
+
+            write_record(output, DATA, addr as u16, &data[..len])?;
+
+            data = &data[len..];
+            addr = addr.wrapping_add(len as u32);
+        }
+    }
+
+    if let Some(entry) = entry {
+        write_record(
+            output,
+            START_LINEAR_ADDRESS,
+            0,
+            &(entry as u32).to_be_bytes(),
+        )?;
+    }
+
+    write_record(output, END_OF_FILE, 0, &[])
+}
+
+fn write_record(output: &mut impl Write, typ: u8, addr: u16, data: &[u8]) -> Result<()> {
+    let [addr_hi, addr_lo] = addr.to_be_bytes();
+
+    let checksum = data
+        .iter()
+        .fold(
+            (data.len() as u8)
+                .wrapping_add(addr_hi)
+                .wrapping_add(addr_lo)
+                .wrapping_add(typ),
+            |sum, byte| sum.wrapping_add(*byte),
+        )
+        .wrapping_neg();
+
+    write!(output, ":{:02X}{:04X}{:02X}", data.len(), addr, typ)?;
+    for byte in data {
+        write!(output, "{byte:02X}")?;
+    }
+    writeln!(output, "{checksum:02X}")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_address() {
+        let data = [0x01, 0x02, 0x03, 0x04];
+        let segments = [CodeSegment {
+            addr: 0x0800_fffe,
+            data: &data,
+        }];
+
+        let mut output = Vec::new();
+        write(&segments, Some(0x0800_0000), &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            ":020000040800F2\n\
+             :02FFFE000102FE\n\
+             :020000040801F1\n\
+             :020000000304F7\n\
+             :0400000508000000EF\n\
+             :00000001FF\n"
+        );
+    }
+}
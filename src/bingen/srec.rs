@@ -0,0 +1,125 @@
+//! Motorola S-record output.
+
+use std::io::Write;
+
+use anyhow::{anyhow, bail, Result};
+
+use super::segments::CodeSegment;
+
+/// Maximum number of data bytes per data record.
+const RECORD_LEN: usize = 16;
+
+/// Maximum number of bytes of the header, as the byte count of a record also covers its
+/// address and checksum.
+const MAX_HEADER_LEN: usize = 0xff - 3;
+
+/// Write `segments` as S-records, using the smallest of the S1/S2/S3 address widths
+/// that can represent every address (including `entry`).
+///
+/// `header` is truncated to the 252 bytes that fit in the S0 record.
+pub fn write(
+    segments: &[CodeSegment],
+    header: &str,
+    entry: Option<u64>,
+    output: &mut impl Write,
+) -> Result<()> {
+    let max_addr = segments
+        .iter()
+        .map(|segment| (segment.addr + segment.data.len() as u64).saturating_sub(1))
+        .chain(entry)
+        .max()
+        .unwrap_or(0);
+
+    let (data_type, end_type, addr_len) = if max_addr <= 0xffff {
+        (1, 9, 2)
+    } else if max_addr <= 0xff_ffff {
+        (2, 8, 3)
+    } else if max_addr <= 0xffff_ffff {
+        (3, 7, 4)
+    } else {
+        bail!("Address 0x{max_addr:x} does not fit in the 32-bit address space of S-records");
+    };
+
+    let header = header.as_bytes();
+    write_record(output, 0, 0, 2, &header[..header.len().min(MAX_HEADER_LEN)])?;
+
+    let mut records = 0_u32;
+    for segment in segments {
+        for (index, chunk) in segment.data.chunks(RECORD_LEN).enumerate() {
+            let addr = segment.addr + (index * RECORD_LEN) as u64;
+
+            write_record(output, data_type, addr as u32, addr_len, chunk)?;
+            records += 1;
+        }
+    }
+
+    if records <= 0xffff {
+        write_record(output, 5, records, 2, &[])?;
+    } else if records <= 0xff_ffff {
+        write_record(output, 6, records, 3, &[])?;
+    }
+
+    write_record(output, end_type, entry.unwrap_or(0) as u32, addr_len, &[])
+}
+
+fn write_record(
+    output: &mut impl Write,
+    typ: u8,
+    addr: u32,
+    addr_len: usize,
+    data: &[u8],
+) -> Result<()> {
+    let addr = &addr.to_be_bytes()[4 - addr_len..];
+    let count = u8::try_from(addr.len() + data.len() + 1)
+        .map_err(|_| anyhow!("S-record with {} data bytes is too long", data.len()))?;
+
+    let checksum = !addr
+        .iter()
+        .chain(data)
+        .fold(count, |sum, byte| sum.wrapping_add(*byte));
+
+    write!(output, "S{typ}{count:02X}")?;
+    for byte in addr.iter().chain(data) {
+        write!(output, "{byte:02X}")?;
+    }
+    writeln!(output, "{checksum:02X}")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_width() {
+        let data = [0x01, 0x02, 0x03, 0x04];
+        let segments = [CodeSegment {
+            addr: 0x0001_0000,
+            data: &data,
+        }];
+
+        let mut output = Vec::new();
+        write(&segments, "test", Some(0x0001_0000), &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "S00700007465737438\n\
+             S20801000001020304EC\n\
+             S5030001FB\n\
+             S804010000FA\n"
+        );
+    }
+
+    #[test]
+    fn long_header() {
+        let mut output = Vec::new();
+        write(&[], &"h".repeat(300), None, &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let header = output.lines().next().unwrap();
+
+        assert_eq!(&header[..4], "S0FF");
+        assert_eq!(header.len(), 4 + 2 * 0xff);
+    }
+}
@@ -0,0 +1,148 @@
+//! UF2 output, as consumed by USB mass storage bootloaders.
+//!
+//! See <https://github.com/microsoft/uf2> for the format specification.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{bail, Result};
+
+use super::segments::CodeSegment;
+
+/// The default number of payload bytes per block.
+pub const DEFAULT_PAYLOAD_SIZE: u32 = 256;
+
+/// The maximum number of payload bytes per block.
+pub const MAX_PAYLOAD_SIZE: u32 = 476;
+
+pub const FAMILY_ID_RP2040: u32 = 0xe48b_ff56;
+pub const FAMILY_ID_NRF52: u32 = 0x1b57_745f;
+pub const FAMILY_ID_NRF52840: u32 = 0xada5_2840;
+pub const FAMILY_ID_SAMD21: u32 = 0x68ed_2b88;
+pub const FAMILY_ID_SAMD51: u32 = 0x5511_4460;
+pub const FAMILY_ID_STM32F4: u32 = 0x5775_5a57;
+pub const FAMILY_ID_ESP32S2: u32 = 0xbfdd_4eee;
+pub const FAMILY_ID_ESP32S3: u32 = 0xc47e_5767;
+pub const FAMILY_ID_ESP32C3: u32 = 0xd42b_a06c;
+
+const MAGIC_START0: u32 = 0x0a32_4655;
+const MAGIC_START1: u32 = 0x9e5d_5157;
+const MAGIC_END: u32 = 0x0ab1_6f30;
+
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+const BLOCK_LEN: usize = 512;
+const DATA_OFFSET: usize = 32;
+
+/// Write `segments` as UF2 blocks of `payload_size` bytes each, aligned to
+/// `payload_size`. Bytes of a block not covered by any segment are set to `fill`.
+pub(crate) fn write(
+    segments: &[CodeSegment],
+    family_id: Option<u32>,
+    payload_size: u32,
+    fill: u8,
+    output: &mut impl Write,
+) -> Result<()> {
+    if payload_size == 0 || payload_size > MAX_PAYLOAD_SIZE {
+        bail!("UF2 payload size must be between 1 and {MAX_PAYLOAD_SIZE}, got {payload_size}");
+    }
+
+    let payload_size = payload_size as u64;
+
+    let mut blocks = BTreeMap::<u64, Vec<u8>>::new();
+    for segment in segments {
+        let mut addr = segment.addr;
+        let mut data = segment.data;
+
+        while !data.is_empty() {
+            let block_addr = addr - addr % payload_size;
+            let offset = (addr - block_addr) as usize;
+            let len = data.len().min(payload_size as usize - offset);
+
+            blocks
+                .entry(block_addr)
+                .or_insert_with(|| vec![fill; payload_size as usize])[offset..offset + len]
+                .copy_from_slice(&data[..len]);
+
+            addr += len as u64;
+            data = &data[len..];
+        }
+    }
+
+    if let Some(addr) = blocks.keys().next_back() {
+        if addr + payload_size > 0x1_0000_0000 {
+            bail!("Address 0x{addr:x} does not fit in the 32-bit address space of UF2");
+        }
+    }
+
+    let num_blocks = blocks.len() as u32;
+
+    for (block_no, (addr, data)) in blocks.into_iter().enumerate() {
+        let mut block = [0_u8; BLOCK_LEN];
+
+        let header = [
+            MAGIC_START0,
+            MAGIC_START1,
+            if family_id.is_some() {
+                FLAG_FAMILY_ID_PRESENT
+            } else {
+                0
+            },
+            addr as u32,
+            payload_size as u32,
+            block_no as u32,
+            num_blocks,
+            family_id.unwrap_or(0),
+        ];
+
+        for (index, word) in header.iter().enumerate() {
+            block[index * 4..index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        block[DATA_OFFSET..DATA_OFFSET + data.len()].copy_from_slice(&data);
+        block[BLOCK_LEN - 4..].copy_from_slice(&MAGIC_END.to_le_bytes());
+
+        output.write_all(&block)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        let data = [0xaa; 0x102];
+        let segments = [CodeSegment {
+            addr: 0x1000_00ff,
+            data: &data,
+        }];
+
+        let mut output = Vec::new();
+        write(&segments, Some(FAMILY_ID_RP2040), 256, 0xff, &mut output).unwrap();
+
+        assert_eq!(output.len(), 3 * BLOCK_LEN);
+
+        let word = |block: usize, index: usize| {
+            let offset = block * BLOCK_LEN + index * 4;
+            u32::from_le_bytes(output[offset..offset + 4].try_into().unwrap())
+        };
+
+        assert_eq!(word(0, 0), MAGIC_START0);
+        assert_eq!(word(0, 2), FLAG_FAMILY_ID_PRESENT);
+        assert_eq!(word(0, 3), 0x1000_0000);
+        assert_eq!(word(1, 3), 0x1000_0100);
+        assert_eq!(word(2, 3), 0x1000_0200);
+        assert_eq!(word(2, 5), 2);
+        assert_eq!(word(2, 6), 3);
+        assert_eq!(word(0, 7), FAMILY_ID_RP2040);
+        assert_eq!(word(0, 127), MAGIC_END);
+
+        assert_eq!(output[DATA_OFFSET + 0xfe], 0xff);
+        assert_eq!(output[DATA_OFFSET + 0xff], 0xaa);
+        assert_eq!(output[2 * BLOCK_LEN + DATA_OFFSET], 0xaa);
+        assert_eq!(output[2 * BLOCK_LEN + DATA_OFFSET + 1], 0xff);
+    }
+}
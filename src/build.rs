@@ -14,6 +14,7 @@ use crate::utils::OsStrExt;
 const C_INCLUDE_ARGS_VAR: &str = "EMBUILD_C_INCLUDE_ARGS";
 const LINK_ARGS_VAR: &str = "EMBUILD_LINK_ARGS";
 const CFG_ARGS_VAR: &str = "EMBUILD_CFG_ARGS";
+const CHECK_CFG_ARGS_VAR: &str = "EMBUILD_CHECK_CFG_ARGS";
 
 /// The name of a [`cargo::set_metadata`] variable where build scripts can store the
 /// contents of their `PATH` environment variable which contains tools used by the
@@ -198,6 +199,17 @@ impl LinkArgsBuilder {
         self
     }
 
+    /// Make the linker write a map file to `path`, which can be parsed with
+    /// [`LinkerMap`](crate::ldmap::LinkerMap).
+    ///
+    /// Requires a GNU ld compatible linker that is invoked through the compiler driver
+    /// (i.e. `gcc`).
+    pub fn map_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.linkflags
+            .push(format!("-Wl,-Map={}", path.as_ref().display()));
+        self
+    }
+
     pub fn build(self) -> Result<LinkArgs> {
         let args: Vec<_> = self
             .libdirflags
@@ -394,6 +406,9 @@ impl CfgArgs {
 
     /// Add options from `lib_name` which have been propagated using [`propagate`](CfgArgs::propagate).
     ///
+    /// The [`CheckCfgArgs`] propagated by `lib_name` are added separately with
+    /// [`CheckCfgArgs::output_propagated`].
+    ///
     /// `lib_name` doesn't refer to a crate, library or package name, it refers to a
     /// dependency's `links` property value, which is specified in its package manifest
     /// (`Cargo.toml`).
@@ -401,3 +416,44 @@ impl CfgArgs {
         Self::try_from_env(lib_name).map(|args| args.output())
     }
 }
+
+/// The rustc check-cfg declarations of the cfgs of [`CfgArgs`], e.g. `cfg(esp_idf_foo)`
+/// or `cfg(esp_idf_bar, values(any()))`, so that rustc reports misspelled cfg names.
+#[derive(Clone, Debug, Default)]
+pub struct CheckCfgArgs {
+    pub args: Vec<String>,
+}
+
+impl CheckCfgArgs {
+    /// Load the declarations from `lib_name` which have been propagated using
+    /// [`propagate`](CheckCfgArgs::propagate), see [`CfgArgs::try_from_env`].
+    pub fn try_from_env(lib_name: impl Display) -> Result<Self> {
+        let args = env::var(format!("DEP_{lib_name}_{CHECK_CFG_ARGS_VAR}"))?
+            .split(':')
+            .filter(|spec| !spec.is_empty())
+            .map(Into::into)
+            .collect();
+
+        Ok(Self { args })
+    }
+
+    /// Declare the cfgs to rustc.
+    pub fn output(&self) {
+        for spec in &self.args {
+            cargo::set_rustc_check_cfg(spec);
+        }
+    }
+
+    /// Propagate the declarations to all dependents of this crate, which add them with
+    /// [`CheckCfgArgs::output_propagated`].
+    pub fn propagate(&self) {
+        cargo::set_metadata(CHECK_CFG_ARGS_VAR, self.args.join(":"));
+    }
+
+    /// Add the declarations from `lib_name` which have been propagated using
+    /// [`propagate`](CheckCfgArgs::propagate), usually along with
+    /// [`CfgArgs::output_propagated`].
+    pub fn output_propagated(lib_name: impl Display) -> Result<()> {
+        Self::try_from_env(lib_name).map(|args| args.output())
+    }
+}
@@ -78,11 +78,7 @@ impl Crate {
 
         cargo_toml.lib = Some(Product {
             crate_type: lib_type,
-            ..if let Some(p) = cargo_toml.lib.take() {
-                p
-            } else {
-                Default::default()
-            }
+            ..cargo_toml.lib.take().unwrap_or_default()
         });
 
         self.save_manifest(&cargo_toml)?;
@@ -347,6 +343,12 @@ pub fn set_rustc_cfg(key: impl Display, value: impl AsRef<str>) {
     }
 }
 
+/// Declare a cfg which may be set for this package, e.g. `cfg(name, values(any()))`, so
+/// that rustc checks the conditional compilation using it.
+pub fn set_rustc_check_cfg(spec: impl Display) {
+    println!("cargo:rustc-check-cfg={spec}");
+}
+
 /// Set an environment variable that is available during this packages compilation.
 pub fn set_rustc_env(key: impl Display, value: impl Display) {
     println!("cargo:rustc-env={key}={value}");
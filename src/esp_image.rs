@@ -0,0 +1,770 @@
+//! ESP application image generation.
+//!
+//! Converts an ELF file into the image format loaded by the ESP ROM and second stage
+//! bootloaders, equivalent to `esptool.py elf2image`.
+
+use std::env;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+use sha2::{Digest, Sha256};
+use xmas_elf::ElfFile;
+
+use crate::bingen::segments;
+
+pub mod info;
+
+pub const VAR_ESP_IMAGE_FILE: &str = "EMBUILD_GENERATED_ESP_IMAGE_FILE";
+
+/// The first byte of every ESP image.
+pub const IMAGE_MAGIC: u8 = 0xe9;
+
+/// The initial value of the XOR checksum over all segment data.
+const CHECKSUM_MAGIC: u8 = 0xef;
+
+/// The length of the common header followed by the extended header.
+const HEADER_LEN: usize = 24;
+const SEGMENT_HEADER_LEN: usize = 8;
+
+/// The MMU page size flash-mapped segments have to be aligned to.
+const IROM_ALIGN: usize = 0x10000;
+
+const MAX_SEGMENTS: usize = 16;
+
+/// Write protect pin value which disables the pin.
+const WP_PIN_DISABLED: u8 = 0xee;
+
+/// The image offset of the ELF SHA-256 in the `esp_app_desc_t` of ESP-IDF applications,
+/// which is at the start of their first segment.
+pub const APP_ELF_SHA256_OFFSET: usize = 0xb0;
+
+/// An ESP chip.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Chip {
+    Esp32,
+    Esp32s2,
+    Esp32s3,
+    Esp32c2,
+    Esp32c3,
+    Esp32c6,
+    Esp32h2,
+}
+
+impl Chip {
+    pub const ALL: &'static [Chip] = &[
+        Self::Esp32,
+        Self::Esp32s2,
+        Self::Esp32s3,
+        Self::Esp32c2,
+        Self::Esp32c3,
+        Self::Esp32c6,
+        Self::Esp32h2,
+    ];
+
+    /// The chip id stored in the extended image header.
+    pub fn chip_id(&self) -> u16 {
+        match self {
+            Self::Esp32 => 0,
+            Self::Esp32s2 => 2,
+            Self::Esp32c3 => 5,
+            Self::Esp32s3 => 9,
+            Self::Esp32c2 => 12,
+            Self::Esp32c6 => 13,
+            Self::Esp32h2 => 16,
+        }
+    }
+
+    /// Get the chip with the extended image header chip id `chip_id`.
+    pub fn from_chip_id(chip_id: u16) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|chip| chip.chip_id() == chip_id)
+    }
+
+    /// Whether `addr` is in one of the address ranges mapped to flash through the MMU.
+    pub fn is_flash_addr(&self, addr: u64) -> bool {
+        self.flash_ranges()
+            .iter()
+            .any(|range| range.contains(&addr))
+    }
+
+    fn flash_ranges(&self) -> [Range<u64>; 2] {
+        match self {
+            Self::Esp32 => [0x400d_0000..0x4040_0000, 0x3f40_0000..0x3f80_0000],
+            Self::Esp32s2 => [0x4008_0000..0x40b8_0000, 0x3f00_0000..0x3f3f_0000],
+            Self::Esp32s3 => [0x4200_0000..0x4400_0000, 0x3c00_0000..0x3e00_0000],
+            Self::Esp32c2 => [0x4200_0000..0x4240_0000, 0x3c00_0000..0x3c40_0000],
+            Self::Esp32c3 => [0x4200_0000..0x4280_0000, 0x3c00_0000..0x3c80_0000],
+            Self::Esp32c6 | Self::Esp32h2 => [0x4200_0000..0x4280_0000, 0x4280_0000..0x4300_0000],
+        }
+    }
+
+    /// The flash frequency which is encoded as `0` in the image header.
+    pub fn default_flash_freq(&self) -> FlashFreq {
+        match self {
+            Self::Esp32c2 => FlashFreq::Freq30M,
+            Self::Esp32c6 => FlashFreq::Freq80M,
+            Self::Esp32h2 => FlashFreq::Freq24M,
+            _ => FlashFreq::Freq40M,
+        }
+    }
+
+    fn flash_freqs(&self) -> &'static [(FlashFreq, u8)] {
+        match self {
+            Self::Esp32c2 => &[
+                (FlashFreq::Freq60M, 0xf),
+                (FlashFreq::Freq30M, 0x0),
+                (FlashFreq::Freq20M, 0x1),
+                (FlashFreq::Freq15M, 0x2),
+            ],
+            Self::Esp32c6 => &[
+                (FlashFreq::Freq80M, 0x0),
+                (FlashFreq::Freq40M, 0x0),
+                (FlashFreq::Freq20M, 0x2),
+            ],
+            Self::Esp32h2 => &[
+                (FlashFreq::Freq48M, 0xf),
+                (FlashFreq::Freq24M, 0x0),
+                (FlashFreq::Freq16M, 0x1),
+                (FlashFreq::Freq12M, 0x2),
+            ],
+            _ => &[
+                (FlashFreq::Freq80M, 0xf),
+                (FlashFreq::Freq40M, 0x0),
+                (FlashFreq::Freq26M, 0x1),
+                (FlashFreq::Freq20M, 0x2),
+            ],
+        }
+    }
+
+    /// Encode `freq` as the lower nibble of the image header flash byte.
+    pub fn encode_flash_freq(&self, freq: FlashFreq) -> Result<u8> {
+        self.flash_freqs()
+            .iter()
+            .find(|(f, _)| *f == freq)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| anyhow!("Flash frequency {freq} is not supported by {self}"))
+    }
+
+    /// Decode the lower nibble of the image header flash byte.
+    pub fn decode_flash_freq(&self, value: u8) -> Option<FlashFreq> {
+        if value == 0 {
+            return Some(self.default_flash_freq());
+        }
+
+        self.flash_freqs()
+            .iter()
+            .find(|(_, v)| *v == value)
+            .map(|(freq, _)| *freq)
+    }
+}
+
+impl FromStr for Chip {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|chip| chip.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("Unknown chip '{s}'"))
+    }
+}
+
+impl Display for Chip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Esp32 => "esp32",
+            Self::Esp32s2 => "esp32s2",
+            Self::Esp32s3 => "esp32s3",
+            Self::Esp32c2 => "esp32c2",
+            Self::Esp32c3 => "esp32c3",
+            Self::Esp32c6 => "esp32c6",
+            Self::Esp32h2 => "esp32h2",
+        })
+    }
+}
+
+/// The SPI flash mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FlashMode {
+    Qio,
+    Qout,
+    Dio,
+    Dout,
+}
+
+impl FlashMode {
+    const ALL: &'static [FlashMode] = &[Self::Qio, Self::Qout, Self::Dio, Self::Dout];
+
+    /// Decode the flash mode byte of the image header.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+}
+
+impl FromStr for FlashMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|mode| mode.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("Unknown flash mode '{s}'"))
+    }
+}
+
+impl Display for FlashMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Qio => "qio",
+            Self::Qout => "qout",
+            Self::Dio => "dio",
+            Self::Dout => "dout",
+        })
+    }
+}
+
+/// The SPI flash size.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FlashSize {
+    Size1Mb,
+    Size2Mb,
+    Size4Mb,
+    Size8Mb,
+    Size16Mb,
+    Size32Mb,
+    Size64Mb,
+    Size128Mb,
+}
+
+impl FlashSize {
+    const ALL: &'static [FlashSize] = &[
+        Self::Size1Mb,
+        Self::Size2Mb,
+        Self::Size4Mb,
+        Self::Size8Mb,
+        Self::Size16Mb,
+        Self::Size32Mb,
+        Self::Size64Mb,
+        Self::Size128Mb,
+    ];
+
+    /// Decode the upper nibble of the image header flash byte.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+
+    /// The size in bytes.
+    pub fn bytes(&self) -> u64 {
+        0x10_0000 << (*self as u64)
+    }
+}
+
+impl FromStr for FlashSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|size| size.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("Unknown flash size '{s}'"))
+    }
+}
+
+impl Display for FlashSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}MB", self.bytes() >> 20)
+    }
+}
+
+/// The SPI flash frequency.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FlashFreq {
+    Freq80M,
+    Freq60M,
+    Freq48M,
+    Freq40M,
+    Freq30M,
+    Freq26M,
+    Freq24M,
+    Freq20M,
+    Freq16M,
+    Freq15M,
+    Freq12M,
+}
+
+impl FlashFreq {
+    const ALL: &'static [FlashFreq] = &[
+        Self::Freq80M,
+        Self::Freq60M,
+        Self::Freq48M,
+        Self::Freq40M,
+        Self::Freq30M,
+        Self::Freq26M,
+        Self::Freq24M,
+        Self::Freq20M,
+        Self::Freq16M,
+        Self::Freq15M,
+        Self::Freq12M,
+    ];
+
+    /// The frequency in MHz.
+    pub fn mhz(&self) -> u32 {
+        match self {
+            Self::Freq80M => 80,
+            Self::Freq60M => 60,
+            Self::Freq48M => 48,
+            Self::Freq40M => 40,
+            Self::Freq30M => 30,
+            Self::Freq26M => 26,
+            Self::Freq24M => 24,
+            Self::Freq20M => 20,
+            Self::Freq16M => 16,
+            Self::Freq15M => 15,
+            Self::Freq12M => 12,
+        }
+    }
+}
+
+impl FromStr for FlashFreq {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|freq| freq.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("Unknown flash frequency '{s}'"))
+    }
+}
+
+impl Display for FlashFreq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}m", self.mhz())
+    }
+}
+
+/// A segment of an ESP image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+/// A generator of ESP application images from ELF files.
+pub struct EspImageGen {
+    elf: PathBuf,
+    chip: Chip,
+    flash_mode: FlashMode,
+    flash_size: FlashSize,
+    flash_freq: FlashFreq,
+    min_chip_rev_full: u16,
+    max_chip_rev_full: u16,
+    append_digest: bool,
+    elf_sha256_offset: Option<usize>,
+}
+
+impl EspImageGen {
+    pub fn new(elf: impl Into<PathBuf>, chip: Chip) -> Self {
+        Self {
+            elf: elf.into(),
+            chip,
+            flash_mode: FlashMode::Dio,
+            flash_size: FlashSize::Size2Mb,
+            flash_freq: chip.default_flash_freq(),
+            min_chip_rev_full: 0,
+            max_chip_rev_full: u16::MAX,
+            append_digest: true,
+            elf_sha256_offset: Some(APP_ELF_SHA256_OFFSET),
+        }
+    }
+
+    /// Set the SPI flash mode written to the image header (defaults to DIO).
+    #[must_use]
+    pub fn flash_mode(mut self, flash_mode: FlashMode) -> Self {
+        self.flash_mode = flash_mode;
+        self
+    }
+
+    /// Set the SPI flash size written to the image header (defaults to 2MB).
+    #[must_use]
+    pub fn flash_size(mut self, flash_size: FlashSize) -> Self {
+        self.flash_size = flash_size;
+        self
+    }
+
+    /// Set the SPI flash frequency written to the image header (defaults to
+    /// [`Chip::default_flash_freq`]).
+    #[must_use]
+    pub fn flash_freq(mut self, flash_freq: FlashFreq) -> Self {
+        self.flash_freq = flash_freq;
+        self
+    }
+
+    /// Set the range of supported chip revisions, in `major * 100 + minor` format.
+    #[must_use]
+    pub fn chip_revisions(mut self, min_full: u16, max_full: u16) -> Self {
+        self.min_chip_rev_full = min_full;
+        self.max_chip_rev_full = max_full;
+        self
+    }
+
+    /// Whether to append the SHA-256 digest of the image (defaults to `true`).
+    #[must_use]
+    pub fn append_digest(mut self, append_digest: bool) -> Self {
+        self.append_digest = append_digest;
+        self
+    }
+
+    /// Write the SHA-256 of the ELF file at `offset` in the image (defaults to
+    /// [`APP_ELF_SHA256_OFFSET`], like ESP-IDF does for applications).
+    ///
+    /// The image must contain zeros at `offset`, which ESP-IDF applications reserve in
+    /// their `esp_app_desc_t`. Set it to `None` for images without one, e.g. bootloaders.
+    #[must_use]
+    pub fn elf_sha256_offset(mut self, offset: Option<usize>) -> Self {
+        self.elf_sha256_offset = offset;
+        self
+    }
+
+    pub fn run(&self) -> Result<PathBuf> {
+        let output_file = PathBuf::from(env::var("OUT_DIR")?).join("image.bin");
+
+        self.run_for_file(&output_file)?;
+
+        println!(
+            "cargo:rustc-env={}={}",
+            VAR_ESP_IMAGE_FILE,
+            output_file.display()
+        );
+
+        Ok(output_file)
+    }
+
+    pub fn run_for_file(&self, output_file: impl AsRef<Path>) -> Result<()> {
+        let output_file = output_file.as_ref();
+
+        eprintln!("Output: {output_file:?}");
+
+        self.write(&mut File::create(output_file)?)
+    }
+
+    pub fn write(&self, output: &mut impl Write) -> Result<()> {
+        eprintln!("Input: {:?}", self.elf);
+
+        let elf_data = fs::read(&self.elf)?;
+        let elf = ElfFile::new(&elf_data).map_err(Error::msg)?;
+
+        let segments = segments::segments(&elf, false)
+            .map(|segment| {
+                Ok(Segment {
+                    addr: segment.addr.try_into().map_err(|_| {
+                        anyhow!("Segment address 0x{:x} is out of range", segment.addr)
+                    })?,
+                    data: segment.data.to_vec(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let elf_sha256 = self
+            .elf_sha256_offset
+            .map(|offset| (offset, Sha256::digest(&elf_data).into()));
+
+        let image = self.image(segments, elf.header.pt2.entry_point() as u32, elf_sha256)?;
+
+        output.write_all(&image)?;
+
+        Ok(())
+    }
+
+    /// Build the image from `segments`, laying out flash-mapped segments so that they
+    /// can be mapped by the MMU.
+    pub fn image(
+        &self,
+        mut segments: Vec<Segment>,
+        entry: u32,
+        elf_sha256: Option<(usize, [u8; 32])>,
+    ) -> Result<Vec<u8>> {
+        segments.sort_by_key(|segment| segment.addr);
+
+        let segments = segments
+            .into_iter()
+            .fold(Vec::<Segment>::new(), |mut merged, segment| {
+                match merged.last_mut() {
+                    Some(last)
+                        if last.addr as usize + last.data.len() == segment.addr as usize
+                            && self.chip.is_flash_addr(last.addr as _)
+                                == self.chip.is_flash_addr(segment.addr as _) =>
+                    {
+                        last.data.extend(segment.data);
+                    }
+                    _ => merged.push(segment),
+                }
+
+                merged
+            });
+
+        let (mut flash_segments, mut ram_segments): (Vec<_>, Vec<_>) = segments
+            .into_iter()
+            .map(|mut segment| {
+                pad_to(&mut segment.data, 4);
+                segment
+            })
+            .partition(|segment| self.chip.is_flash_addr(segment.addr as _));
+
+        for pair in flash_segments.windows(2) {
+            if pair[0].addr as usize / IROM_ALIGN == pair[1].addr as usize / IROM_ALIGN {
+                bail!(
+                    "Segments at 0x{:x} and 0x{:x} are mapped to the same flash MMU page",
+                    pair[0].addr,
+                    pair[1].addr
+                );
+            }
+        }
+
+        let mut writer = ImageWriter {
+            image: Vec::new(),
+            checksum: CHECKSUM_MAGIC,
+            segments: 0,
+            elf_sha256,
+        };
+
+        self.write_header(&mut writer.image, entry)?;
+
+        flash_segments.reverse();
+        ram_segments.reverse();
+
+        while let Some(mut segment) = flash_segments.pop() {
+            let pad_len = writer.alignment_needed(&segment);
+
+            if pad_len > 0 {
+                let pad_segment = match ram_segments.last_mut() {
+                    Some(ram_segment) if pad_len > SEGMENT_HEADER_LEN => {
+                        let len = pad_len.min(ram_segment.data.len());
+                        let pad_segment = Segment {
+                            addr: ram_segment.addr,
+                            data: ram_segment.data.drain(..len).collect(),
+                        };
+
+                        ram_segment.addr += len as u32;
+                        if ram_segment.data.is_empty() {
+                            ram_segments.pop();
+                        }
+
+                        pad_segment
+                    }
+                    _ => Segment {
+                        addr: 0,
+                        data: vec![0; pad_len],
+                    },
+                };
+
+                writer.save_segment(pad_segment)?;
+                flash_segments.push(segment);
+            } else {
+                // Work around a bug in older 2nd stage bootloaders, which did not map the
+                // last MMU page if a segment was less than 0x24 bytes over the page boundary
+                let end = writer.image.len() + SEGMENT_HEADER_LEN + segment.data.len();
+                if end % IROM_ALIGN < 0x24 {
+                    let len = segment.data.len() + 0x24 - end % IROM_ALIGN;
+                    segment.data.resize(len, 0);
+                }
+
+                writer.save_segment(segment)?;
+            }
+        }
+
+        while let Some(segment) = ram_segments.pop() {
+            writer.save_segment(segment)?;
+        }
+
+        if writer.segments > MAX_SEGMENTS {
+            bail!(
+                "The image has {} segments, but at most {MAX_SEGMENTS} are supported",
+                writer.segments
+            );
+        }
+
+        let mut image = writer.image;
+
+        image[1] = writer.segments as u8;
+
+        image.resize(image.len() + 15 - image.len() % 16, 0);
+        image.push(writer.checksum);
+
+        if self.append_digest {
+            let digest = Sha256::digest(&image);
+            image.extend_from_slice(&digest);
+        }
+
+        Ok(image)
+    }
+
+    fn write_header(&self, image: &mut Vec<u8>, entry: u32) -> Result<()> {
+        let flash_freq = self.chip.encode_flash_freq(self.flash_freq)?;
+
+        image.extend_from_slice(&[
+            IMAGE_MAGIC,
+            0,
+            self.flash_mode as u8,
+            ((self.flash_size as u8) << 4) | flash_freq,
+        ]);
+        image.extend_from_slice(&entry.to_le_bytes());
+
+        image.extend_from_slice(&[WP_PIN_DISABLED, 0, 0, 0]);
+        image.extend_from_slice(&self.chip.chip_id().to_le_bytes());
+        image.push((self.min_chip_rev_full / 100) as u8);
+        image.extend_from_slice(&self.min_chip_rev_full.to_le_bytes());
+        image.extend_from_slice(&self.max_chip_rev_full.to_le_bytes());
+        image.extend_from_slice(&[0; 4]);
+        image.push(self.append_digest as u8);
+
+        assert_eq!(image.len(), HEADER_LEN);
+
+        Ok(())
+    }
+}
+
+struct ImageWriter {
+    image: Vec<u8>,
+    checksum: u8,
+    segments: usize,
+    elf_sha256: Option<(usize, [u8; 32])>,
+}
+
+impl ImageWriter {
+    /// The amount of padding data needed, so that after the padding segment and the
+    /// header of `segment` the file offset is congruent to the segment address modulo
+    /// the MMU page size.
+    fn alignment_needed(&self, segment: &Segment) -> usize {
+        let align_past =
+            (segment.addr as usize % IROM_ALIGN) as isize - SEGMENT_HEADER_LEN as isize;
+        let pad_len = (IROM_ALIGN - self.image.len() % IROM_ALIGN) as isize + align_past;
+
+        if pad_len == 0 || pad_len == IROM_ALIGN as isize {
+            return 0;
+        }
+
+        // The padding segment has a header as well
+        let pad_len = pad_len - SEGMENT_HEADER_LEN as isize;
+
+        if pad_len < 0 {
+            (pad_len + IROM_ALIGN as isize) as usize
+        } else {
+            pad_len as usize
+        }
+    }
+
+    fn save_segment(&mut self, mut segment: Segment) -> Result<()> {
+        let data_offset = self.image.len() + SEGMENT_HEADER_LEN;
+
+        if let Some((offset, sha256)) = self.elf_sha256 {
+            if (data_offset..data_offset + segment.data.len()).contains(&offset) {
+                let patch = &mut segment.data[offset - data_offset..];
+                if patch.len() < sha256.len() {
+                    bail!("The ELF SHA-256 at offset 0x{offset:x} does not fit in its segment");
+                }
+
+                let patch = &mut patch[..sha256.len()];
+                if patch.iter().any(|byte| *byte != 0) {
+                    bail!("The contents of the segment at ELF SHA-256 offset 0x{offset:x} are not zero");
+                }
+
+                patch.copy_from_slice(&sha256);
+            }
+        }
+
+        self.image.extend_from_slice(&segment.addr.to_le_bytes());
+        self.image
+            .extend_from_slice(&(segment.data.len() as u32).to_le_bytes());
+        self.image.extend_from_slice(&segment.data);
+
+        self.checksum = segment
+            .data
+            .iter()
+            .fold(self.checksum, |checksum, byte| checksum ^ byte);
+        self.segments += 1;
+
+        Ok(())
+    }
+}
+
+fn pad_to(data: &mut Vec<u8>, align: usize) {
+    let len = (data.len() + align - 1) / align * align;
+    data.resize(len, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_ELF: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/esp_image/testdata/app.elf"
+    );
+
+    #[test]
+    fn elf_sha256() {
+        let sha256 = APP_ELF_SHA256_OFFSET..APP_ELF_SHA256_OFFSET + 32;
+
+        let mut image = Vec::new();
+        EspImageGen::new(APP_ELF, Chip::Esp32c3)
+            .write(&mut image)
+            .unwrap();
+        assert_eq!(
+            &image[sha256.clone()],
+            Sha256::digest(fs::read(APP_ELF).unwrap()).as_slice()
+        );
+
+        let mut image = Vec::new();
+        EspImageGen::new(APP_ELF, Chip::Esp32c3)
+            .elf_sha256_offset(None)
+            .write(&mut image)
+            .unwrap();
+        assert_eq!(image[sha256], [0; 32]);
+    }
+
+    #[test]
+    fn flash_segment_alignment() {
+        let image = EspImageGen::new("test.elf", Chip::Esp32c3)
+            .image(
+                vec![
+                    Segment {
+                        addr: 0x3fc8_0000,
+                        data: vec![1; 0x100],
+                    },
+                    Segment {
+                        addr: 0x3c01_0100,
+                        data: vec![2; 0x10],
+                    },
+                ],
+                0x4038_0000,
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(image[0], IMAGE_MAGIC);
+        assert_eq!(u16::from_le_bytes([image[12], image[13]]), 5);
+        assert_eq!(image.len() % 16, 0);
+
+        // The flash segment data starts at a file offset congruent to its address
+        let data_offset = image
+            .windows(0x10)
+            .position(|window| window.iter().all(|byte| *byte == 2))
+            .unwrap();
+        assert_eq!(data_offset % IROM_ALIGN, 0x100);
+
+        let (image, digest) = image.split_at(image.len() - 32);
+        assert_eq!(Sha256::digest(image).as_slice(), digest);
+        assert_eq!(image[1], 3);
+    }
+}
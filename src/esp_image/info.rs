@@ -0,0 +1,502 @@
+//! Inspection and verification of ESP images, equivalent to `esptool.py image_info`.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Error, Result};
+use sha2::{Digest, Sha256};
+use xmas_elf::ElfFile;
+
+use super::{
+    Chip, FlashFreq, FlashMode, FlashSize, CHECKSUM_MAGIC, HEADER_LEN, IMAGE_MAGIC,
+    SEGMENT_HEADER_LEN,
+};
+use crate::bingen::segments;
+
+/// The magic word at the start of `esp_app_desc_t`.
+pub const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+
+/// The offset of the ELF SHA-256 in `esp_app_desc_t`.
+const APP_DESC_ELF_SHA256_OFFSET: usize = 0x90;
+
+/// A segment of a parsed ESP image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageSegment {
+    /// The offset of the segment header in the image file.
+    pub offset: usize,
+    /// The load address.
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+/// The application description (`esp_app_desc_t`) embedded by ESP-IDF at the start of
+/// the first segment of an application image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppDesc {
+    pub secure_version: u32,
+    pub version: String,
+    pub project_name: String,
+    pub time: String,
+    pub date: String,
+    pub idf_version: String,
+    pub elf_sha256: [u8; 32],
+}
+
+impl AppDesc {
+    /// Parse an `esp_app_desc_t` from `data`, or return [`None`] if `data` does not
+    /// start with [`APP_DESC_MAGIC`].
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < APP_DESC_ELF_SHA256_OFFSET + 32 || read_u32(data, 0) != APP_DESC_MAGIC {
+            return None;
+        }
+
+        let string = |offset: usize, len: usize| {
+            let field = &data[offset..offset + len];
+            let end = field.iter().position(|b| *b == 0).unwrap_or(len);
+
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+
+        let mut elf_sha256 = [0; 32];
+        elf_sha256
+            .copy_from_slice(&data[APP_DESC_ELF_SHA256_OFFSET..APP_DESC_ELF_SHA256_OFFSET + 32]);
+
+        Some(Self {
+            secure_version: read_u32(data, 4),
+            version: string(0x10, 32),
+            project_name: string(0x30, 32),
+            time: string(0x50, 16),
+            date: string(0x60, 16),
+            idf_version: string(0x70, 32),
+            elf_sha256,
+        })
+    }
+}
+
+/// A parsed ESP application or bootloader image.
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+    pub chip_id: u16,
+    pub flash_mode: u8,
+    pub flash_size_freq: u8,
+    pub entry: u32,
+    pub min_chip_rev_full: u16,
+    pub max_chip_rev_full: u16,
+    pub segments: Vec<ImageSegment>,
+    /// The checksum stored in the image.
+    pub checksum: u8,
+    /// The checksum calculated over the segment data.
+    pub calculated_checksum: u8,
+    /// The SHA-256 digest appended to the image, if any.
+    pub digest: Option<[u8; 32]>,
+    /// The SHA-256 digest calculated over the image.
+    pub calculated_digest: [u8; 32],
+    pub app_desc: Option<AppDesc>,
+}
+
+impl ImageInfo {
+    /// Parse the image file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        Self::parse(&fs::read(path)?)
+            .map_err(|e| e.context(anyhow!("Could not parse image '{}'", path.display())))
+    }
+
+    /// Parse an image from `data`.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        ensure!(data.len() >= HEADER_LEN, "Image is too short");
+        ensure!(
+            data[0] == IMAGE_MAGIC,
+            "Invalid image magic 0x{:02x}, expected 0x{IMAGE_MAGIC:02x}",
+            data[0]
+        );
+
+        let mut segments = Vec::new();
+        let mut calculated_checksum = CHECKSUM_MAGIC;
+        let mut offset = HEADER_LEN;
+
+        for _ in 0..data[1] {
+            ensure!(
+                data.len() >= offset + SEGMENT_HEADER_LEN,
+                "Segment header at offset 0x{offset:x} is truncated"
+            );
+
+            let addr = read_u32(data, offset);
+            let len = read_u32(data, offset + 4) as usize;
+            let start = offset + SEGMENT_HEADER_LEN;
+
+            ensure!(
+                data.len() >= start + len,
+                "Segment at offset 0x{offset:x} with length 0x{len:x} is truncated"
+            );
+
+            let segment_data = &data[start..start + len];
+            calculated_checksum = segment_data
+                .iter()
+                .fold(calculated_checksum, |checksum, byte| checksum ^ byte);
+
+            segments.push(ImageSegment {
+                offset,
+                addr,
+                data: segment_data.to_vec(),
+            });
+
+            offset = start + len;
+        }
+
+        let checksum_offset = offset + 15 - offset % 16;
+        ensure!(data.len() > checksum_offset, "Image checksum is missing");
+
+        let end = checksum_offset + 1;
+        let digest = if data[23] == 1 {
+            ensure!(data.len() >= end + 32, "Image SHA-256 digest is missing");
+
+            let mut digest = [0; 32];
+            digest.copy_from_slice(&data[end..end + 32]);
+            Some(digest)
+        } else {
+            None
+        };
+
+        let app_desc = segments
+            .first()
+            .and_then(|segment| AppDesc::parse(&segment.data));
+
+        Ok(Self {
+            chip_id: read_u16(data, 12),
+            flash_mode: data[2],
+            flash_size_freq: data[3],
+            entry: read_u32(data, 4),
+            min_chip_rev_full: read_u16(data, 15),
+            max_chip_rev_full: read_u16(data, 17),
+            segments,
+            checksum: data[checksum_offset],
+            calculated_checksum,
+            digest,
+            calculated_digest: Sha256::digest(&data[..end]).into(),
+            app_desc,
+        })
+    }
+
+    /// The chip this image was built for, if known.
+    pub fn chip(&self) -> Option<Chip> {
+        Chip::from_chip_id(self.chip_id)
+    }
+
+    pub fn flash_mode(&self) -> Option<FlashMode> {
+        FlashMode::from_u8(self.flash_mode)
+    }
+
+    pub fn flash_size(&self) -> Option<FlashSize> {
+        FlashSize::from_u8(self.flash_size_freq >> 4)
+    }
+
+    pub fn flash_freq(&self) -> Option<FlashFreq> {
+        self.chip()
+            .and_then(|chip| chip.decode_flash_freq(self.flash_size_freq & 0xf))
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum == self.calculated_checksum
+    }
+
+    /// Whether the appended SHA-256 digest is valid, or [`None`] if there is none.
+    pub fn digest_valid(&self) -> Option<bool> {
+        self.digest.map(|digest| digest == self.calculated_digest)
+    }
+
+    /// Check the checksum and the appended SHA-256 digest of the image.
+    pub fn verify(&self) -> Result<()> {
+        ensure!(
+            self.checksum_valid(),
+            "Invalid image checksum 0x{:02x}, calculated 0x{:02x}",
+            self.checksum,
+            self.calculated_checksum
+        );
+        ensure!(
+            self.digest_valid() != Some(false),
+            "Invalid image SHA-256 digest {}, calculated {}",
+            hex(&self.digest.unwrap_or_default()),
+            hex(&self.calculated_digest)
+        );
+
+        Ok(())
+    }
+
+    /// Check that this image was generated from the ELF file at `elf`.
+    ///
+    /// Every segment must match the contents of the ELF at its load address, and if the
+    /// image contains an [`AppDesc`] with a non-zero ELF SHA-256, it must match the ELF
+    /// file.
+    pub fn verify_elf(&self, elf: impl AsRef<Path>) -> Result<()> {
+        let elf_data = fs::read(elf.as_ref())?;
+        let elf = ElfFile::new(&elf_data).map_err(Error::msg)?;
+        let elf_segments = segments::segments(&elf, false).collect::<Vec<_>>();
+
+        ensure!(
+            self.entry as u64 == elf.header.pt2.entry_point(),
+            "Image entry point 0x{:x} does not match ELF entry point 0x{:x}",
+            self.entry,
+            elf.header.pt2.entry_point()
+        );
+
+        // The ELF SHA-256 stays zero if it was not patched into the image, see
+        // `EspImageGen::elf_sha256_offset`
+        if let Some(app_desc) = self
+            .app_desc
+            .as_ref()
+            .filter(|app_desc| app_desc.elf_sha256 != [0; 32])
+        {
+            let elf_sha256: [u8; 32] = Sha256::digest(&elf_data).into();
+
+            ensure!(
+                app_desc.elf_sha256 == elf_sha256,
+                "Image ELF SHA-256 {} does not match ELF file SHA-256 {}",
+                hex(&app_desc.elf_sha256),
+                hex(&elf_sha256)
+            );
+        }
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            // Zero padding segments are not loaded
+            if segment.addr == 0 {
+                continue;
+            }
+
+            let start = segment.addr as u64;
+            let end = start + segment.data.len() as u64;
+
+            // Addresses not covered by the ELF segments are padded with zeros
+            let mut expected = vec![0; segment.data.len()];
+            for elf_segment in &elf_segments {
+                let elf_end = elf_segment.addr + elf_segment.data.len() as u64;
+                let (from, to) = (start.max(elf_segment.addr), end.min(elf_end));
+
+                if from < to {
+                    expected[(from - start) as usize..(to - start) as usize].copy_from_slice(
+                        &elf_segment.data
+                            [(from - elf_segment.addr) as usize..(to - elf_segment.addr) as usize],
+                    );
+                }
+            }
+
+            // The ELF SHA-256 is patched into the image after linking
+            if index == 0 && self.app_desc.is_some() {
+                let sha256 = APP_DESC_ELF_SHA256_OFFSET..APP_DESC_ELF_SHA256_OFFSET + 32;
+                expected[sha256.clone()].copy_from_slice(&segment.data[sha256]);
+            }
+
+            if let Some(pos) = segment
+                .data
+                .iter()
+                .zip(&expected)
+                .position(|(byte, expected)| byte != expected)
+            {
+                bail!(
+                    "Image segment {} at offset 0x{:x} differs from the ELF at address 0x{:x}",
+                    index + 1,
+                    segment.offset,
+                    start + pos as u64
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chip = self.chip();
+        let unknown = || "unknown".to_owned();
+
+        match chip {
+            Some(chip) => writeln!(f, "Chip: {chip} (id {})", self.chip_id)?,
+            None => writeln!(f, "Chip: unknown (id {})", self.chip_id)?,
+        }
+        writeln!(f, "Entry point: 0x{:08x}", self.entry)?;
+        writeln!(
+            f,
+            "Flash: mode {}, size {}, frequency {}",
+            self.flash_mode()
+                .map(|mode| mode.to_string())
+                .unwrap_or_else(unknown),
+            self.flash_size()
+                .map(|size| size.to_string())
+                .unwrap_or_else(unknown),
+            self.flash_freq()
+                .map(|freq| freq.to_string())
+                .unwrap_or_else(unknown)
+        )?;
+        writeln!(
+            f,
+            "Chip revision: v{}.{} - v{}.{}",
+            self.min_chip_rev_full / 100,
+            self.min_chip_rev_full % 100,
+            self.max_chip_rev_full / 100,
+            self.max_chip_rev_full % 100
+        )?;
+
+        writeln!(f, "Segments: {}", self.segments.len())?;
+        for (index, segment) in self.segments.iter().enumerate() {
+            let kind = if segment.addr == 0 {
+                "padding"
+            } else if chip
+                .map(|chip| chip.is_flash_addr(segment.addr as u64))
+                .unwrap_or(false)
+            {
+                "flash"
+            } else {
+                "ram"
+            };
+
+            writeln!(
+                f,
+                "  Segment {}: len 0x{:05x} load 0x{:08x} file_offs 0x{:08x} [{kind}]",
+                index + 1,
+                segment.data.len(),
+                segment.addr,
+                segment.offset
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Checksum: 0x{:02x} ({})",
+            self.checksum,
+            if self.checksum_valid() {
+                "valid".to_owned()
+            } else {
+                format!("invalid - calculated 0x{:02x}", self.calculated_checksum)
+            }
+        )?;
+
+        if let Some(digest) = &self.digest {
+            writeln!(
+                f,
+                "Validation hash: {} ({})",
+                hex(digest),
+                if self.digest_valid() == Some(true) {
+                    "valid"
+                } else {
+                    "invalid"
+                }
+            )?;
+        }
+
+        if let Some(app_desc) = &self.app_desc {
+            writeln!(f, "Application information:")?;
+            writeln!(f, "  Project name: {}", app_desc.project_name)?;
+            writeln!(f, "  App version: {}", app_desc.version)?;
+            writeln!(f, "  Compile time: {} {}", app_desc.date, app_desc.time)?;
+            writeln!(f, "  ELF file SHA256: {}", hex(&app_desc.elf_sha256))?;
+            writeln!(f, "  ESP-IDF: {}", app_desc.idf_version)?;
+            writeln!(f, "  Secure version: {}", app_desc.secure_version)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{EspImageGen, Segment, APP_ELF_SHA256_OFFSET};
+    use super::*;
+
+    const APP_ELF: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/esp_image/testdata/app.elf"
+    );
+
+    #[test]
+    fn roundtrip() {
+        let mut app_desc = vec![0; 0x100];
+        app_desc[..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        app_desc[0x30..0x34].copy_from_slice(b"test");
+
+        let mut image = EspImageGen::new("test.elf", Chip::Esp32c3)
+            .image(
+                vec![
+                    Segment {
+                        addr: 0x3c00_0020,
+                        data: app_desc,
+                    },
+                    Segment {
+                        addr: 0x3fc8_0000,
+                        data: vec![1; 0x10],
+                    },
+                ],
+                0x4038_0000,
+                Some((0xb0, [0xaa; 32])),
+            )
+            .unwrap();
+
+        let info = ImageInfo::parse(&image).unwrap();
+        info.verify().unwrap();
+
+        assert_eq!(info.chip(), Some(Chip::Esp32c3));
+        assert_eq!(info.entry, 0x4038_0000);
+        assert_eq!(info.segments.len(), 2);
+        assert_eq!(info.flash_mode(), Some(FlashMode::Dio));
+
+        let app_desc = info.app_desc.unwrap();
+        assert_eq!(app_desc.project_name, "test");
+        assert_eq!(app_desc.elf_sha256, [0xaa; 32]);
+
+        let len = image.len();
+        image[len - 40] ^= 1;
+        assert!(ImageInfo::parse(&image).unwrap().verify().is_err());
+    }
+
+    #[test]
+    fn verify_elf() {
+        for elf_sha256_offset in [Some(APP_ELF_SHA256_OFFSET), None] {
+            let mut image = Vec::new();
+            EspImageGen::new(APP_ELF, Chip::Esp32c3)
+                .elf_sha256_offset(elf_sha256_offset)
+                .write(&mut image)
+                .unwrap();
+
+            let mut info = ImageInfo::parse(&image).unwrap();
+            info.verify().unwrap();
+            info.verify_elf(APP_ELF).unwrap();
+
+            assert_eq!(info.app_desc.as_ref().unwrap().project_name, "app");
+
+            let segment = info.segments.last_mut().unwrap();
+            segment.data[0] ^= 1;
+            assert!(info.verify_elf(APP_ELF).is_err());
+        }
+
+        let mut image = Vec::new();
+        EspImageGen::new(APP_ELF, Chip::Esp32c3)
+            .write(&mut image)
+            .unwrap();
+        image[APP_ELF_SHA256_OFFSET] ^= 1;
+
+        let err = ImageInfo::parse(&image)
+            .unwrap()
+            .verify_elf(APP_ELF)
+            .unwrap_err();
+        assert!(err.to_string().contains("does not match ELF file SHA-256"));
+    }
+}
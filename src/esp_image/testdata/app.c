@@ -0,0 +1,36 @@
+/* Test input of the ESP image generator, built with:
+ * gcc -Os -static -nostdlib -fno-pie -no-pie -fno-asynchronous-unwind-tables
+ *     -Wl,-T,app.ld -Wl,--build-id=none -o app.elf app.c
+ */
+
+/* `esp_app_desc_t`, with the ELF SHA-256 left zero for the image generator */
+struct app_desc {
+    unsigned int magic_word;
+    unsigned int secure_version;
+    unsigned int reserv1[2];
+    char version[32];
+    char project_name[32];
+    char time[16];
+    char date[16];
+    char idf_ver[32];
+    unsigned char app_elf_sha256[32];
+    unsigned int reserv2[20];
+};
+
+__attribute__((section(".flash.appdesc"), used))
+const struct app_desc app_desc = {
+    .magic_word = 0xabcd5432,
+    .version = "1.0",
+    .project_name = "app",
+};
+
+int counter = 42;
+
+int get_counter(void) {
+    return counter;
+}
+
+__attribute__((section(".iram1")))
+void app_main(void) {
+    counter = get_counter() + 1;
+}
@@ -21,8 +21,21 @@ use anyhow::{anyhow, Context, Error, Result};
 use crate::python::PYTHON;
 use crate::{cmd, git, path_buf, python};
 
+#[cfg(feature = "elf")]
+pub mod flash_image;
+#[cfg(feature = "elf")]
+mod incremental;
+#[cfg(feature = "elf")]
+pub mod lp_core;
+pub mod nvs;
+pub mod partition;
 #[cfg(feature = "elf")]
 pub mod ulp_fsm;
+#[cfg(feature = "elf")]
+pub mod ulp_riscv;
+
+#[cfg(all(test, unix, feature = "elf"))]
+mod testutils;
 
 pub const DEFAULT_ESP_IDF_REPOSITORY: &str = "https://github.com/espressif/esp-idf.git";
 pub const MANAGED_ESP_IDF_REPOS_DIR_BASE: &str = "esp-idf";
@@ -279,9 +292,9 @@ impl std::fmt::Display for EspIdfVersion {
 ///
 /// Two variations exist:
 /// - Managed
-///     The esp-idf source is installed automatically.
+///   The esp-idf source is installed automatically.
 /// - Custom
-///     A user-provided local clone the esp-idf repository.
+///   A user-provided local clone the esp-idf repository.
 ///
 /// In both cases the [`Installer`] will install all required tools.
 ///
@@ -496,7 +509,7 @@ impl Installer {
 ///
 /// The version string can have the following format:
 /// - `commit:<hash>`: Uses the commit `<hash>` of the `esp-idf` repository. Note that
-///                    this will clone the whole `esp-idf` not just one commit.
+///   this will clone the whole `esp-idf` not just one commit.
 /// - `tag:<tag>`: Uses the tag `<tag>` of the `esp-idf` repository.
 /// - `branch:<branch>`: Uses the branch `<branch>` of the `esp-idf` repository.
 /// - `v<major>.<minor>` or `<major>.<minor>`: Uses the tag `v<major>.<minor>` of the `esp-idf` repository.
@@ -544,4 +557,14 @@ impl EspIdfBuildInfo {
         serde_json::to_writer_pretty(file, self)?;
         Ok(())
     }
+
+    /// Layer the [`sdkconfig_defaults`](Self::sdkconfig_defaults) files the way the
+    /// esp-idf build did, including their variants for the [`mcu`](Self::mcu).
+    #[cfg(feature = "kconfig")]
+    pub fn sdkconfig_defaults_config(&self) -> Result<crate::kconfig::Config> {
+        crate::kconfig::Config::from_defaults(
+            self.sdkconfig_defaults.iter().flatten(),
+            Some(&self.mcu),
+        )
+    }
 }
@@ -0,0 +1,471 @@
+//! Merged flash images.
+//!
+//! Combines the bootloader, the partition table, application images and data
+//! partitions into a single image of the whole flash, for factory programming or
+//! emulators, equivalent to `esptool.py merge_bin`.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use super::partition::{
+    PartitionTable, PartitionType, DEFAULT_PARTITION_TABLE_OFFSET, PARTITION_TABLE_SIZE,
+};
+use super::EspIdfBuildInfo;
+use crate::bingen::segments::CodeSegment;
+use crate::bingen::Format;
+use crate::path_buf;
+
+/// The name of the file in the esp-idf build dir that lists the flash offsets.
+const FLASHER_ARGS_FILENAME: &str = "flasher_args.json";
+
+/// Get the flash offset of the second stage bootloader for `mcu`.
+pub fn bootloader_offset(mcu: &str) -> u32 {
+    match mcu {
+        "esp32" | "esp32s2" => 0x1000,
+        "esp32c5" | "esp32p4" => 0x2000,
+        _ => 0x0,
+    }
+}
+
+/// Parse a flash size such as `4MB`, as used by esptool and the esp-idf.
+pub fn parse_flash_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+
+    size.strip_suffix("MB")
+        .and_then(|mb| mb.parse::<u64>().ok())
+        .map(|mb| mb << 20)
+        .or_else(|| {
+            size.strip_suffix("KB")
+                .and_then(|kb| kb.parse::<u64>().ok())
+                .map(|kb| kb << 10)
+        })
+        .ok_or_else(|| anyhow!("Invalid flash size '{size}'"))
+}
+
+#[derive(Clone, Debug)]
+enum Placement {
+    Offset(u32),
+    Partition {
+        name: String,
+        ty: Option<PartitionType>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Content {
+    placement: Placement,
+    file: PathBuf,
+}
+
+/// A content placed at its flash offset.
+struct Region {
+    offset: u32,
+    name: String,
+    data: Vec<u8>,
+    /// The number of bytes reserved at `offset`, at least the length of `data`.
+    reserved: u64,
+}
+
+/// A builder for a merged flash image.
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct Builder {
+    bootloader: Option<Content>,
+    partition_table: Option<(u32, PartitionTable)>,
+    contents: Vec<Content>,
+    flash_size: Option<u64>,
+    fill: u8,
+    format: Format,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            bootloader: None,
+            partition_table: None,
+            contents: Vec::new(),
+            flash_size: None,
+            fill: 0xff,
+            format: Format::Binary,
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create a builder with the bootloader, partition table and flash size of the
+    /// esp-idf build described by `build_info`.
+    ///
+    /// The offsets are taken from the `flasher_args.json` generated by the esp-idf
+    /// build if it exists, otherwise the defaults for [`EspIdfBuildInfo::mcu`] are used.
+    pub fn from_build_info(build_info: &EspIdfBuildInfo) -> Result<Self> {
+        let build_dir = &build_info.build_dir;
+
+        let mut bootloader = (
+            bootloader_offset(&build_info.mcu),
+            path_buf![build_dir, "bootloader", "bootloader.bin"],
+        );
+        let mut partition_table = (
+            DEFAULT_PARTITION_TABLE_OFFSET,
+            path_buf![build_dir, "partition_table", "partition-table.bin"],
+        );
+        let mut flash_size = None;
+
+        let flasher_args = build_dir.join(FLASHER_ARGS_FILENAME);
+        if flasher_args.exists() {
+            let file = File::open(&flasher_args)
+                .with_context(|| anyhow!("Could not read {}", flasher_args.display()))?;
+            let flasher_args: FlasherArgs = serde_json::from_reader(file)?;
+
+            let entry = |entry: Option<FlasherArgsEntry>, default: &mut (u32, PathBuf)| {
+                if let Some(entry) = entry {
+                    *default = (parse_offset(&entry.offset)?, build_dir.join(entry.file));
+                }
+
+                Ok::<_, anyhow::Error>(())
+            };
+
+            entry(flasher_args.bootloader, &mut bootloader)?;
+            entry(flasher_args.partition_table, &mut partition_table)?;
+
+            if let Some(size) = flasher_args
+                .flash_settings
+                .as_ref()
+                .and_then(|settings| settings.get("flash_size"))
+            {
+                flash_size = Some(parse_flash_size(size)?);
+            }
+        }
+
+        let mut builder = Self::new()
+            .bootloader(bootloader.0, bootloader.1)
+            .partition_table(
+                partition_table.0,
+                PartitionTable::from_binary_file(&partition_table.1)?,
+            );
+        builder.flash_size = flash_size;
+
+        Ok(builder)
+    }
+
+    /// Set the second stage bootloader binary and its flash offset.
+    pub fn bootloader(mut self, offset: u32, file: impl Into<PathBuf>) -> Self {
+        self.bootloader = Some(Content {
+            placement: Placement::Offset(offset),
+            file: file.into(),
+        });
+        self
+    }
+
+    /// Set the partition table and its flash offset.
+    pub fn partition_table(mut self, offset: u32, table: PartitionTable) -> Self {
+        self.partition_table = Some((offset, table));
+        self
+    }
+
+    /// Place the application image `file` in the app partition named `partition`.
+    pub fn app(self, partition: impl Into<String>, file: impl Into<PathBuf>) -> Self {
+        self.partition_content(partition, Some(PartitionType::App), file)
+    }
+
+    /// Place `file` in the non-app partition named `partition`.
+    pub fn data(self, partition: impl Into<String>, file: impl Into<PathBuf>) -> Self {
+        self.partition_content(partition, None, file)
+    }
+
+    /// Place `file` at the flash `offset`, regardless of the partition table.
+    pub fn raw(mut self, offset: u32, file: impl Into<PathBuf>) -> Self {
+        self.contents.push(Content {
+            placement: Placement::Offset(offset),
+            file: file.into(),
+        });
+        self
+    }
+
+    /// Check that all contents fit in a flash of `flash_size` bytes and pad the image
+    /// to that size.
+    pub fn flash_size(mut self, flash_size: Option<u64>) -> Self {
+        self.flash_size = flash_size;
+        self
+    }
+
+    /// Set the byte used to fill the gaps between contents (defaults to `0xff`, the
+    /// value of erased flash).
+    pub fn fill(mut self, fill: u8) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Set the output format (defaults to [`Format::Binary`]).
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn partition_content(
+        mut self,
+        partition: impl Into<String>,
+        ty: Option<PartitionType>,
+        file: impl Into<PathBuf>,
+    ) -> Self {
+        self.contents.push(Content {
+            placement: Placement::Partition {
+                name: partition.into(),
+                ty,
+            },
+            file: file.into(),
+        });
+        self
+    }
+
+    pub fn run_for_file(&self, output_file: impl AsRef<Path>) -> Result<()> {
+        let output_file = output_file.as_ref();
+
+        eprintln!("Output: {output_file:?}");
+
+        self.write(&mut File::create(output_file)?)
+    }
+
+    pub fn write(&self, output: &mut impl Write) -> Result<()> {
+        let regions = self.regions()?;
+
+        let segments = regions
+            .iter()
+            .map(|region| CodeSegment {
+                addr: region.offset as u64,
+                data: &region.data,
+            })
+            .collect::<Vec<_>>();
+
+        self.format
+            .write_segments("flash", 0, self.fill, None, &segments, output)?;
+
+        if let (Format::Binary, Some(flash_size)) = (self.format, self.flash_size) {
+            let end = segments
+                .last()
+                .map(|segment| segment.addr + segment.data.len() as u64)
+                .unwrap_or(0);
+
+            output.write_all(&vec![self.fill; (flash_size - end) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the flash offset of every content, and check that they neither overlap
+    /// nor exceed their partition or the flash size.
+    ///
+    /// Returns the regions sorted by offset.
+    fn regions(&self) -> Result<Vec<Region>> {
+        let mut regions = Vec::new();
+
+        if let Some(bootloader) = &self.bootloader {
+            regions.push(self.region(bootloader, "bootloader")?);
+        }
+
+        if let Some((offset, table)) = &self.partition_table {
+            if let Some(flash_size) = self.flash_size {
+                table.verify(Some(flash_size))?;
+            }
+
+            // The partition table reserves a whole sector
+            regions.push(Region {
+                offset: *offset,
+                name: "partition table".to_owned(),
+                data: table.to_binary()?,
+                reserved: PARTITION_TABLE_SIZE as u64,
+            });
+        }
+
+        let mut used_partitions = HashMap::new();
+        for content in &self.contents {
+            if let Placement::Partition { name, .. } = &content.placement {
+                if let Some(file) = used_partitions.insert(name, &content.file) {
+                    bail!(
+                        "Partition '{name}' has multiple contents: {} and {}",
+                        file.display(),
+                        content.file.display()
+                    );
+                }
+            }
+
+            regions.push(self.region(content, &content.file.display().to_string())?);
+        }
+
+        regions.sort_by_key(|region| region.offset);
+
+        for pair in regions.windows(2) {
+            let Region {
+                offset,
+                name,
+                reserved,
+                ..
+            } = &pair[0];
+            let end = *offset as u64 + reserved;
+
+            ensure!(
+                end <= pair[1].offset as u64,
+                "{name} at {offset:#x} (ending at {end:#x}) overlaps {} at {:#x}",
+                pair[1].name,
+                pair[1].offset
+            );
+        }
+
+        if let (Some(flash_size), Some(region)) = (self.flash_size, regions.last()) {
+            let end = region.offset as u64 + region.data.len() as u64;
+
+            ensure!(
+                end <= flash_size,
+                "{} at {:#x} ends at {end:#x}, beyond the flash size {flash_size:#x}",
+                region.name,
+                region.offset
+            );
+        }
+
+        Ok(regions)
+    }
+
+    fn region(&self, content: &Content, name: &str) -> Result<Region> {
+        let data = fs::read(&content.file)
+            .with_context(|| anyhow!("Could not read {}", content.file.display()))?;
+
+        let offset = match &content.placement {
+            Placement::Offset(offset) => *offset,
+            Placement::Partition {
+                name: partition_name,
+                ty,
+            } => {
+                let table = self
+                    .partition_table
+                    .as_ref()
+                    .map(|(_, table)| table)
+                    .ok_or_else(|| {
+                        anyhow!("Cannot place {name} in partition '{partition_name}' without a partition table")
+                    })?;
+
+                let partition = table.find(partition_name).ok_or_else(|| {
+                    anyhow!("Partition '{partition_name}' does not exist in the partition table")
+                })?;
+
+                match ty {
+                    Some(ty) => ensure!(
+                        partition.ty == *ty,
+                        "Partition '{partition_name}' has type {}, expected {ty}",
+                        partition.ty
+                    ),
+                    None => ensure!(
+                        partition.ty != PartitionType::App,
+                        "Partition '{partition_name}' is an app partition"
+                    ),
+                }
+
+                ensure!(
+                    data.len() as u64 <= partition.size as u64,
+                    "{name} ({:#x} bytes) does not fit in partition '{partition_name}' ({:#x} bytes)",
+                    data.len(),
+                    partition.size
+                );
+
+                partition.offset
+            }
+        };
+
+        Ok(Region {
+            offset,
+            name: name.to_owned(),
+            reserved: data.len() as u64,
+            data,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct FlasherArgs {
+    bootloader: Option<FlasherArgsEntry>,
+    #[serde(rename = "partition-table")]
+    partition_table: Option<FlasherArgsEntry>,
+    flash_settings: Option<HashMap<String, String>>,
+}
+
+#[derive(serde::Deserialize)]
+struct FlasherArgsEntry {
+    offset: String,
+    file: PathBuf,
+}
+
+fn parse_offset(offset: &str) -> Result<u32> {
+    let parsed = match offset.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => offset.parse(),
+    };
+
+    parsed.with_context(|| anyhow!("Invalid offset '{offset}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bootloader = dir.path().join("bootloader.bin");
+        let app = dir.path().join("app.bin");
+        fs::write(&bootloader, [0xe9; 16])?;
+        fs::write(&app, [0xaa; 32])?;
+
+        let table = PartitionTable::from_csv(
+            "nvs, data, nvs, 0x9000, 0x6000\nfactory, app, factory, 0x10000, 0x10000\n",
+            DEFAULT_PARTITION_TABLE_OFFSET,
+        )?;
+
+        let builder = Builder::new()
+            .bootloader(0x1000, &bootloader)
+            .partition_table(DEFAULT_PARTITION_TABLE_OFFSET, table.clone())
+            .app("factory", &app)
+            .flash_size(Some(0x20000));
+
+        let mut output = Vec::new();
+        builder.write(&mut output)?;
+
+        assert_eq!(output.len(), 0x20000);
+        assert!(output[..0x1000].iter().all(|b| *b == 0xff));
+        assert_eq!(&output[0x1000..0x1010], &[0xe9; 16]);
+        let table_bin = table.to_binary()?;
+        assert_eq!(&output[0x8000..0x8000 + table_bin.len()], &table_bin[..]);
+        assert_eq!(&output[0x10000..0x10020], &[0xaa; 32]);
+
+        assert!(builder
+            .clone()
+            .data("factory", &app)
+            .write(&mut Vec::new())
+            .is_err());
+        assert!(builder
+            .clone()
+            .raw(0x8800, &app)
+            .write(&mut Vec::new())
+            .is_err());
+        assert!(builder
+            .flash_size(Some(0x10000))
+            .write(&mut Vec::new())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bootloader_offsets() {
+        assert_eq!(bootloader_offset("esp32"), 0x1000);
+        assert_eq!(bootloader_offset("esp32s2"), 0x1000);
+        assert_eq!(bootloader_offset("esp32c3"), 0x0);
+        assert_eq!(bootloader_offset("esp32c5"), 0x2000);
+        assert_eq!(bootloader_offset("esp32p4"), 0x2000);
+    }
+}
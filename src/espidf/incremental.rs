@@ -0,0 +1,268 @@
+//! Incremental builds of co-processor programs.
+//!
+//! A build step is skipped if its outputs are newer than all of its inputs, including
+//! the headers listed in the dependency file written by `gcc -MD`, and if its command
+//! line did not change since the outputs were built.
+//!
+//! The dependency files list the system headers too (unlike with `gcc -MMD`), as the
+//! headers of ESP-IDF are usually included with `-isystem`.
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::cargo;
+use crate::cmd::Cmd;
+
+/// Runs build steps and collects their inputs.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    inputs: BTreeSet<PathBuf>,
+    outputs: BTreeSet<PathBuf>,
+}
+
+impl Tracker {
+    /// Run `command` to build `output` from `inputs` and the prerequisites in
+    /// `dep_file`, unless `output` is up to date.
+    pub fn run(
+        &mut self,
+        mut command: Cmd,
+        output: &Path,
+        inputs: &[&Path],
+        dep_file: Option<&Path>,
+    ) -> Result<()> {
+        let stamp = format!("{:?}", command.cmd);
+
+        self.step(&[output], inputs, dep_file, &stamp, || Ok(command.run()?))
+    }
+
+    /// Call `build` to build `outputs` from `inputs` and the prerequisites in
+    /// `dep_file`, unless all `outputs` are up to date and were built with the same
+    /// `stamp`, e.g. the command line of the step.
+    pub fn step(
+        &mut self,
+        outputs: &[&Path],
+        inputs: &[&Path],
+        dep_file: Option<&Path>,
+        stamp: &str,
+        build: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let output = outputs[0];
+
+        let stamp_file = {
+            let mut stamp_file = OsString::from(output.as_os_str());
+            stamp_file.push(".stamp");
+            PathBuf::from(stamp_file)
+        };
+
+        let all_inputs = |dep_file: Option<&Path>| -> Result<Option<Vec<PathBuf>>> {
+            let deps = match dep_file {
+                Some(dep_file) => match read_dep_file(dep_file)? {
+                    Some(deps) => deps,
+                    None => return Ok(None),
+                },
+                None => Vec::new(),
+            };
+
+            Ok(Some(
+                inputs
+                    .iter()
+                    .map(|input| input.to_path_buf())
+                    .chain(deps)
+                    .collect(),
+            ))
+        };
+
+        let up_to_date = fs::read_to_string(&stamp_file).ok().as_deref() == Some(stamp)
+            && match all_inputs(dep_file)? {
+                Some(inputs) => outputs.iter().all(|output| is_up_to_date(output, &inputs)),
+                None => false,
+            };
+
+        if up_to_date {
+            eprintln!("Up to date: {}", output.display());
+        } else {
+            // Remove the stamp first, so that a failed build is never up to date
+            let _ = fs::remove_file(&stamp_file);
+
+            build()?;
+
+            fs::write(&stamp_file, stamp)
+                .map_err(|e| anyhow!("Could not write {}: {e}", stamp_file.display()))?;
+        }
+
+        self.inputs
+            .extend(all_inputs(dep_file)?.into_iter().flatten());
+        self.outputs
+            .extend(outputs.iter().map(|output| output.to_path_buf()));
+
+        Ok(())
+    }
+
+    /// Rerun the build script if any of the inputs of the steps run so far changes.
+    ///
+    /// The outputs of the steps are not tracked, as they change during the build.
+    pub fn track(&self) {
+        for input in self.inputs.difference(&self.outputs) {
+            cargo::track_file(input);
+        }
+    }
+}
+
+/// Whether `output` exists and is not older than any of the `inputs`, which must exist.
+fn is_up_to_date(output: &Path, inputs: &[PathBuf]) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+
+    let output_modified = match modified(output) {
+        Some(modified) => modified,
+        None => return false,
+    };
+
+    inputs.iter().all(|input| {
+        modified(input).map_or(false, |input_modified| input_modified <= output_modified)
+    })
+}
+
+/// Read the prerequisites of the make rules in the dependency file `path`, or [`None`]
+/// if it does not exist.
+fn read_dep_file(path: &Path) -> Result<Option<Vec<PathBuf>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content =
+        fs::read_to_string(path).map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+
+    Ok(Some(parse_dep_file(&content)))
+}
+
+/// Parse the prerequisites of the make rules written by `gcc -MD`.
+fn parse_dep_file(content: &str) -> Vec<PathBuf> {
+    let content = content.replace("\\\r\n", " ").replace("\\\n", " ");
+
+    let mut deps = Vec::new();
+
+    for line in content.lines() {
+        // The separator of the targets, not the colon of a Windows drive
+        let prerequisites = line
+            .char_indices()
+            .find(|&(index, c)| {
+                c == ':'
+                    && line[index + 1..]
+                        .chars()
+                        .next()
+                        .map_or(true, char::is_whitespace)
+            })
+            .map(|(index, _)| &line[index + 1..]);
+
+        let prerequisites = match prerequisites {
+            Some(prerequisites) => prerequisites,
+            None => continue,
+        };
+
+        let mut dep = String::new();
+        let mut chars = prerequisites.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if matches!(chars.peek(), Some(' ' | '#')) => dep.extend(chars.next()),
+                '$' if chars.peek() == Some(&'$') => dep.extend(chars.next()),
+                c if c.is_whitespace() => {
+                    if !dep.is_empty() {
+                        deps.push(PathBuf::from(std::mem::take(&mut dep)));
+                    }
+                }
+                c => dep.push(c),
+            }
+        }
+
+        if !dep.is_empty() {
+            deps.push(PathBuf::from(dep));
+        }
+    }
+
+    deps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+
+        let (input, header, dep_file) = (path("main.S"), path("main.h"), path("main.d"));
+        let (elf, bin) = (path("main.elf"), path("main.bin"));
+
+        let old = filetime::FileTime::from_unix_time(1_000_000, 0);
+        let new = filetime::FileTime::from_unix_time(i32::MAX as i64, 0);
+
+        for file in [&input, &header] {
+            fs::write(file, "").unwrap();
+            filetime::set_file_mtime(file, old).unwrap();
+        }
+        let deps = format!("main.o: {} \\\n {}\n", input.display(), header.display());
+        fs::write(&dep_file, deps).unwrap();
+
+        let mut tracker = Tracker::default();
+        let mut step = |stamp: &str| {
+            let mut built = false;
+
+            tracker
+                .step(&[&elf, &bin], &[&input], Some(&dep_file), stamp, || {
+                    fs::write(&elf, "")?;
+                    fs::write(&bin, "")?;
+                    built = true;
+                    Ok(())
+                })
+                .unwrap();
+
+            built
+        };
+
+        assert!(step("as"));
+        assert!(!step("as"));
+
+        // A header changes
+        filetime::set_file_mtime(&header, new).unwrap();
+        assert!(step("as"));
+        filetime::set_file_mtime(&header, old).unwrap();
+        assert!(!step("as"));
+
+        // The command line changes
+        assert!(step("as -g"));
+        assert!(!step("as -g"));
+
+        // One of the outputs is deleted
+        fs::remove_file(&bin).unwrap();
+        assert!(step("as -g"));
+        assert!(!step("as -g"));
+
+        assert_eq!(
+            tracker
+                .inputs
+                .difference(&tracker.outputs)
+                .collect::<Vec<_>>(),
+            [&input, &header]
+        );
+    }
+
+    #[test]
+    fn dep_file() {
+        let content = "out/main.o: src/main.c include/my\\ header.h \\\n  C:\\idf\\sdkconfig.h\n";
+
+        assert_eq!(
+            parse_dep_file(content),
+            [
+                PathBuf::from("src/main.c"),
+                PathBuf::from("include/my header.h"),
+                PathBuf::from("C:\\idf\\sdkconfig.h"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Build support for the LP core co-processor of the ESP32-C5, ESP32-C6 and ESP32-P4.
+//!
+//! Mirrors the LP core CMake flow of ESP-IDF (5.2 or later): the C and assembly sources
+//! of the LP program and the `lp_core` library of ESP-IDF are compiled with the riscv32
+//! toolchain and linked with the `lp_core_riscv.ld` script of ESP-IDF, and with the
+//! scripts providing the LP ROM functions and the peripherals of the MCU.
+//!
+//! The LP core sees its memory at the same addresses as the main CPU, so the generated
+//! symbols module points directly into the LP memory.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+pub use super::ulp_fsm::{BuildResult, SystemIncludes};
+use super::ulp_riscv::{self, Core};
+use crate::build;
+
+/// The default `-march` of the LP core, for the toolchains of ESP-IDF 5.2 and later.
+pub const DEFAULT_MARCH: &str = "rv32imac_zicsr_zifencei";
+
+pub struct Builder(ulp_riscv::Builder);
+
+impl Builder {
+    pub fn try_from_embuild_env(
+        library: impl AsRef<str>,
+        mcu: impl Into<String>,
+        add_includes: impl Into<Vec<String>>,
+    ) -> Result<Self> {
+        let library = library.as_ref();
+
+        Ok(Self::new(
+            std::env::var(format!("DEP_{library}_EMBUILD_ESP_IDF_PATH"))?,
+            mcu,
+            SystemIncludes::CInclArgs(build::CInclArgs::try_from_env(library)?),
+            add_includes,
+            None,
+            std::env::var_os("DEP_ESP_IDF_EMBUILD_ENV_PATH"),
+        ))
+    }
+
+    pub fn new(
+        esp_idf: impl Into<PathBuf>,
+        mcu: impl Into<String>,
+        sys_includes: SystemIncludes,
+        add_includes: impl Into<Vec<String>>,
+        gcc: Option<String>,
+        env_path: Option<OsString>,
+    ) -> Self {
+        Self(ulp_riscv::Builder::new_for_core(
+            Core::LpCore(mcu.into()),
+            esp_idf,
+            sys_includes,
+            add_includes,
+            gcc,
+            env_path,
+        ))
+    }
+
+    /// Set the `-march` of the compiler and the linker (defaults to [`DEFAULT_MARCH`]).
+    #[must_use]
+    pub fn march(self, march: impl Into<String>) -> Self {
+        Self(self.0.march(march))
+    }
+
+    /// Build the LP program from the C (`.c`) and assembly (`.S`, `.s`) files
+    /// `lp_sources` into `out_dir`.
+    pub fn build<'a, I>(&self, lp_sources: I, out_dir: impl AsRef<Path>) -> Result<BuildResult>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        self.0.build(lp_sources, out_dir)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::espidf::testutils::{fake_toolchain, log, touch};
+
+    #[test]
+    fn lp_core() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        touch(
+            dir,
+            &[
+                "src/main.c",
+                "idf/components/ulp/lp_core/lp_core/start.S",
+                "idf/components/ulp/lp_core/shared/ulp_lp_core_memory_shared.c",
+                "idf/components/esp_rom/esp32c6/ld/esp32c6lp.rom.ld",
+                "idf/components/esp_rom/esp32c6/ld/esp32c6lp.rom.newlib.ld",
+                "idf/components/esp_rom/esp32c6/ld/esp32c6.rom.ld",
+                "idf/components/soc/esp32c6/ld/esp32c6.peripherals.ld",
+            ],
+        );
+
+        let builder = Builder::new(
+            dir.join("idf"),
+            "esp32c6",
+            SystemIncludes::MCU("esp32c6".to_owned()),
+            Vec::new(),
+            None,
+            Some(fake_toolchain(dir).into()),
+        );
+        let sources = [dir.join("src/main.c")];
+
+        // ESP-IDF 5.1 does not have the LP core linker script
+        let error = builder
+            .build(sources.iter().map(PathBuf::as_path), dir.join("out"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cannot find the LP core LD script in ESP-IDF (ESP-IDF 5.2 or later is required)"
+        );
+
+        touch(dir, &["idf/components/ulp/ld/lp_core_riscv.ld"]);
+
+        let result = builder
+            .build(sources.iter().map(PathBuf::as_path), dir.join("out"))
+            .unwrap();
+
+        let log = log(dir);
+        assert!(log[0].starts_with(
+            "riscv32-esp-elf-gcc -march=rv32imac_zicsr_zifencei -g -DIS_ULP_COCPU -MD -MF out/obj/main.o.d "
+        ));
+        assert!(log.contains(&"riscv32-esp-elf-gcc -march=rv32imac_zicsr_zifencei -nostartfiles -Wl,--gc-sections -Wl,-Map=out/ulp.map -Tout/ulp.ld -Tidf/components/esp_rom/esp32c6/ld/esp32c6lp.rom.ld -Tidf/components/esp_rom/esp32c6/ld/esp32c6lp.rom.newlib.ld -Tidf/components/soc/esp32c6/ld/esp32c6.peripherals.ld out/obj/main.o out/lib/lp_core/start.o out/lib/shared/ulp_lp_core_memory_shared.o -o out/ulp".to_owned()));
+
+        // The LP core sees the LP memory at the addresses of the main CPU
+        assert!(fs::read_to_string(result.sym_rs_file)
+            .unwrap()
+            .contains("pub const counts: *mut [i32; 4] = 0x402010 as *mut [i32; 4];"));
+    }
+}
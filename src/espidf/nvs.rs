@@ -0,0 +1,661 @@
+//! ESP-IDF NVS partition images.
+//!
+//! Parses the NVS CSV format and generates the binary image of an NVS partition,
+//! equivalent to `nvs_partition_gen.py generate` (NVS version 2, without encryption).
+//!
+//! The CSV has the columns `key,type,encoding,value`, where `type` is one of
+//! `namespace`, `data` or `file`:
+//!
+//! ```text
+//! key,type,encoding,value
+//! calibration,namespace,,
+//! serial,data,string,SN-0001
+//! offset,data,i32,-42
+//! table,file,binary,cal/table.bin
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+/// The size of an NVS page.
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// The minimum size of an NVS partition.
+pub const MIN_PARTITION_SIZE: u32 = 0x3000;
+
+/// The maximum length of a namespace or key name, excluding the NUL terminator.
+pub const MAX_KEY_LEN: usize = 15;
+
+/// The maximum length of a string value, including the NUL terminator.
+pub const MAX_STRING_LEN: usize = 1984;
+
+const PAGE_STATE_FULL: u32 = 0xffff_fffc;
+const PAGE_VERSION: u8 = 0xfe;
+const PAGE_HEADER_LEN: usize = 32;
+const BITMAP_OFFSET: usize = 32;
+const FIRST_ENTRY_OFFSET: usize = 64;
+const ENTRY_LEN: usize = 32;
+const ENTRIES_PER_PAGE: usize = 126;
+const CHUNK_ANY: u8 = 0xff;
+const MAX_NAMESPACES: usize = 254;
+
+const TYPE_U8: u8 = 0x01;
+const TYPE_I8: u8 = 0x11;
+const TYPE_U16: u8 = 0x02;
+const TYPE_I16: u8 = 0x12;
+const TYPE_U32: u8 = 0x04;
+const TYPE_I32: u8 = 0x14;
+const TYPE_U64: u8 = 0x08;
+const TYPE_I64: u8 = 0x18;
+const TYPE_STRING: u8 = 0x21;
+const TYPE_BLOB_DATA: u8 = 0x42;
+const TYPE_BLOB_INDEX: u8 = 0x48;
+
+/// The value of an NVS entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    /// A string, stored with a NUL terminator.
+    String(String),
+    /// A blob of arbitrary size, split into chunks across pages.
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// Parse `value` with the CSV `encoding`.
+    pub fn parse(encoding: &str, value: &str) -> Result<Self> {
+        Ok(match encoding {
+            "u8" => Self::U8(parse_int(value)?),
+            "i8" => Self::I8(parse_int(value)?),
+            "u16" => Self::U16(parse_int(value)?),
+            "i16" => Self::I16(parse_int(value)?),
+            "u32" => Self::U32(parse_int(value)?),
+            "i32" => Self::I32(parse_int(value)?),
+            "u64" => Self::U64(parse_int(value)?),
+            "i64" => Self::I64(parse_int(value)?),
+            "string" => Self::String(value.to_owned()),
+            "hex2bin" => Self::Blob(parse_hex(value)?),
+            "base64" => Self::Blob(
+                base64::decode(value.trim())
+                    .with_context(|| anyhow!("Invalid base64 value '{value}'"))?,
+            ),
+            "binary" => bail!("Encoding 'binary' is only supported for 'file' entries"),
+            _ => bail!("Unknown encoding '{encoding}'"),
+        })
+    }
+
+    /// Parse the contents of the file at `path` with the CSV `encoding`.
+    pub fn from_file(encoding: &str, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let result = if encoding == "binary" {
+            fs::read(path).map(Self::Blob).map_err(Into::into)
+        } else {
+            fs::read_to_string(path)
+                .map_err(Into::into)
+                .and_then(|value| Self::parse(encoding, &value))
+        };
+
+        result.with_context(|| anyhow!("Could not read {}", path.display()))
+    }
+
+    /// The type and the data field of a primitive value.
+    fn primitive(&self) -> Option<(u8, [u8; 8])> {
+        fn bytes<const N: usize>(le: [u8; N]) -> [u8; 8] {
+            let mut data = [0xff; 8];
+            data[..N].copy_from_slice(&le);
+            data
+        }
+
+        Some(match self {
+            Self::U8(v) => (TYPE_U8, bytes(v.to_le_bytes())),
+            Self::I8(v) => (TYPE_I8, bytes(v.to_le_bytes())),
+            Self::U16(v) => (TYPE_U16, bytes(v.to_le_bytes())),
+            Self::I16(v) => (TYPE_I16, bytes(v.to_le_bytes())),
+            Self::U32(v) => (TYPE_U32, bytes(v.to_le_bytes())),
+            Self::I32(v) => (TYPE_I32, bytes(v.to_le_bytes())),
+            Self::U64(v) => (TYPE_U64, bytes(v.to_le_bytes())),
+            Self::I64(v) => (TYPE_I64, bytes(v.to_le_bytes())),
+            Self::String(_) | Self::Blob(_) => return None,
+        })
+    }
+}
+
+/// An entry of an NVS namespace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+}
+
+/// An NVS namespace and its entries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Namespace {
+    pub name: String,
+    pub entries: Vec<Entry>,
+}
+
+/// The contents of an NVS partition.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NvsPartition {
+    /// The namespaces, in the order in which they are written to the partition.
+    pub namespaces: Vec<Namespace>,
+}
+
+impl NvsPartition {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Parse the NVS CSV file at `path`.
+    ///
+    /// Relative paths of `file` entries are resolved against the directory of the CSV
+    /// file.
+    pub fn from_csv_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let csv = fs::read_to_string(path)
+            .with_context(|| anyhow!("Could not read {}", path.display()))?;
+
+        Self::from_csv(&csv, path.parent().unwrap_or_else(|| Path::new("")))
+            .with_context(|| anyhow!("Could not parse NVS CSV {}", path.display()))
+    }
+
+    /// Parse an NVS CSV, resolving relative paths of `file` entries against `base_dir`.
+    pub fn from_csv(csv: &str, base_dir: impl AsRef<Path>) -> Result<Self> {
+        let base_dir = base_dir.as_ref();
+        let mut nvs = Self::new();
+        let mut namespace = None;
+
+        for (line_no, line) in csv.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            let result = (|| {
+                let columns = split_csv_line(line)?;
+                let column = |index: usize| columns.get(index).map(String::as_str).unwrap_or("");
+
+                let (key, ty, encoding, value) = (column(0), column(1), column(2), column(3));
+
+                match ty {
+                    "type" if key == "key" => {}
+                    "namespace" => {
+                        nvs.namespace(key)?;
+                        namespace = Some(key.to_owned());
+                    }
+                    "data" | "file" => {
+                        let namespace = namespace
+                            .as_deref()
+                            .ok_or_else(|| anyhow!("Entry '{key}' is not in a namespace"))?;
+
+                        let encoding = encoding.to_lowercase();
+                        let value = if ty == "file" {
+                            Value::from_file(&encoding, base_dir.join(value))?
+                        } else {
+                            Value::parse(&encoding, value)?
+                        };
+
+                        nvs.insert(namespace, key, value)?;
+                    }
+                    _ => bail!("Unknown entry type '{ty}'"),
+                }
+
+                Ok(())
+            })();
+
+            result.with_context(|| anyhow!("Error at line {}: '{line}'", line_no + 1))?;
+        }
+
+        Ok(nvs)
+    }
+
+    /// Get the namespace `name`, adding it if it does not exist yet.
+    pub fn namespace(&mut self, name: &str) -> Result<&mut Namespace> {
+        check_key(name)?;
+
+        let index = match self.namespaces.iter().position(|ns| ns.name == name) {
+            Some(index) => index,
+            None => {
+                ensure!(
+                    self.namespaces.len() < MAX_NAMESPACES,
+                    "Too many namespaces, at most {MAX_NAMESPACES} are supported"
+                );
+
+                self.namespaces.push(Namespace {
+                    name: name.to_owned(),
+                    entries: Vec::new(),
+                });
+                self.namespaces.len() - 1
+            }
+        };
+
+        Ok(&mut self.namespaces[index])
+    }
+
+    /// Add the entry `key` with `value` to `namespace`, adding the namespace if it does
+    /// not exist yet.
+    pub fn insert(&mut self, namespace: &str, key: &str, value: Value) -> Result<()> {
+        check_key(key)?;
+
+        if let Value::String(string) = &value {
+            ensure!(
+                !string.contains('\0'),
+                "String value of '{key}' contains a NUL character"
+            );
+            ensure!(
+                string.len() < MAX_STRING_LEN,
+                "String value of '{key}' is longer than {} bytes",
+                MAX_STRING_LEN - 1
+            );
+        }
+
+        let namespace = self.namespace(namespace)?;
+        ensure!(
+            namespace.entries.iter().all(|entry| entry.key != key),
+            "Duplicate key '{key}' in namespace '{}'",
+            namespace.name
+        );
+
+        namespace.entries.push(Entry {
+            key: key.to_owned(),
+            value,
+        });
+
+        Ok(())
+    }
+
+    /// Generate the binary image of an NVS partition of `size` bytes.
+    ///
+    /// `size` must be a multiple of [`PAGE_SIZE`] and at least [`MIN_PARTITION_SIZE`];
+    /// the last page is left erased, as required by the NVS library.
+    pub fn to_binary(&self, size: u32) -> Result<Vec<u8>> {
+        ensure!(
+            size as usize % PAGE_SIZE == 0,
+            "NVS partition size {size:#x} is not a multiple of {PAGE_SIZE:#x}"
+        );
+        ensure!(
+            size >= MIN_PARTITION_SIZE,
+            "NVS partition size {size:#x} is less than the minimum of {MIN_PARTITION_SIZE:#x}"
+        );
+
+        let mut writer = PageWriter::new(size as usize / PAGE_SIZE - 1);
+
+        for (index, namespace) in self.namespaces.iter().enumerate() {
+            let (_, data) = Value::U8(index as u8 + 1).primitive().unwrap();
+            writer.write_primitive(0, TYPE_U8, &namespace.name, data)?;
+
+            for entry in &namespace.entries {
+                writer.write_entry(index as u8 + 1, entry)?;
+            }
+        }
+
+        Ok(writer.finish())
+    }
+
+    /// Generate the binary image of an NVS partition of `size` bytes and write it to
+    /// `output_file`.
+    pub fn write_file(&self, size: u32, output_file: impl AsRef<Path>) -> Result<()> {
+        let output_file = output_file.as_ref();
+
+        fs::write(output_file, self.to_binary(size)?)
+            .with_context(|| anyhow!("Could not write {}", output_file.display()))
+    }
+}
+
+/// Lays out entries into pages the same way as `nvs_partition_gen.py`.
+struct PageWriter {
+    pages: Vec<Vec<u8>>,
+    max_pages: usize,
+    entry_num: usize,
+}
+
+impl PageWriter {
+    fn new(max_pages: usize) -> Self {
+        Self {
+            pages: Vec::new(),
+            max_pages,
+            entry_num: ENTRIES_PER_PAGE,
+        }
+    }
+
+    fn new_page(&mut self) -> Result<()> {
+        ensure!(
+            self.pages.len() < self.max_pages,
+            "NVS partition is too small for its contents"
+        );
+
+        let mut page = vec![0xff; PAGE_SIZE];
+        page[0..4].copy_from_slice(&PAGE_STATE_FULL.to_le_bytes());
+        page[4..8].copy_from_slice(&(self.pages.len() as u32).to_le_bytes());
+        page[8] = PAGE_VERSION;
+
+        let crc = crc32(&page[4..28]);
+        page[28..PAGE_HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+
+        self.pages.push(page);
+        self.entry_num = 0;
+
+        Ok(())
+    }
+
+    fn write_entry(&mut self, ns_index: u8, entry: &Entry) -> Result<()> {
+        let key = &entry.key;
+
+        match &entry.value {
+            Value::String(string) => {
+                let mut data = string.as_bytes().to_vec();
+                data.push(0);
+
+                let data_entries = (data.len() + ENTRY_LEN - 1) / ENTRY_LEN;
+                if self.entry_num + data_entries + 1 >= ENTRIES_PER_PAGE {
+                    self.new_page()?;
+                }
+
+                self.write_data(ns_index, TYPE_STRING, CHUNK_ANY, key, &data)
+            }
+            Value::Blob(blob) => self.write_blob(ns_index, key, blob),
+            value => {
+                let (ty, data) = value.primitive().unwrap();
+                self.write_primitive(ns_index, ty, key, data)
+            }
+        }
+    }
+
+    fn write_primitive(&mut self, ns_index: u8, ty: u8, key: &str, data: [u8; 8]) -> Result<()> {
+        if self.entry_num >= ENTRIES_PER_PAGE {
+            self.new_page()?;
+        }
+
+        let mut header = entry_header(ns_index, ty, 1, CHUNK_ANY, key);
+        header[24..32].copy_from_slice(&data);
+        seal(&mut header);
+
+        self.write_entries(&header, 1);
+
+        Ok(())
+    }
+
+    fn write_blob(&mut self, ns_index: u8, key: &str, blob: &[u8]) -> Result<()> {
+        if self.entry_num >= ENTRIES_PER_PAGE {
+            self.new_page()?;
+        }
+
+        let mut chunk_count = 0u8;
+        let mut remaining = blob;
+
+        loop {
+            let tailroom = (ENTRIES_PER_PAGE - self.entry_num - 1) * ENTRY_LEN;
+            let (chunk, rest) = remaining.split_at(tailroom.min(remaining.len()));
+            remaining = rest;
+
+            self.write_data(ns_index, TYPE_BLOB_DATA, chunk_count, key, chunk)?;
+            chunk_count = chunk_count
+                .checked_add(1)
+                .ok_or_else(|| anyhow!("Blob value of '{key}' is too large"))?;
+
+            if !remaining.is_empty() || tailroom - chunk.len() < ENTRY_LEN {
+                self.new_page()?;
+            }
+
+            if remaining.is_empty() {
+                break;
+            }
+        }
+
+        let mut header = entry_header(ns_index, TYPE_BLOB_INDEX, 1, CHUNK_ANY, key);
+        header[24..28].copy_from_slice(&(blob.len() as u32).to_le_bytes());
+        header[28] = chunk_count;
+        header[29] = 0;
+        seal(&mut header);
+
+        self.write_entries(&header, 1);
+
+        Ok(())
+    }
+
+    fn write_data(
+        &mut self,
+        ns_index: u8,
+        ty: u8,
+        chunk_index: u8,
+        key: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        let data_entries = (data.len() + ENTRY_LEN - 1) / ENTRY_LEN;
+        ensure!(
+            self.entry_num + data_entries < ENTRIES_PER_PAGE,
+            "Value of '{key}' does not fit in an NVS page"
+        );
+
+        let mut header = entry_header(ns_index, ty, data_entries as u8 + 1, chunk_index, key);
+        header[24..26].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        header[28..32].copy_from_slice(&crc32(data).to_le_bytes());
+        seal(&mut header);
+
+        self.write_entries(&header, 1);
+        self.write_entries(data, data_entries);
+
+        Ok(())
+    }
+
+    fn write_entries(&mut self, data: &[u8], count: usize) {
+        let page = self.pages.last_mut().unwrap();
+
+        let offset = FIRST_ENTRY_OFFSET + self.entry_num * ENTRY_LEN;
+        page[offset..offset + data.len()].copy_from_slice(data);
+
+        for _ in 0..count {
+            // Mark the entry as written (`0b10`)
+            let bit = self.entry_num * 2;
+            page[BITMAP_OFFSET + bit / 8] &= !(1 << (bit % 8));
+
+            self.entry_num += 1;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        while self.pages.len() < self.max_pages {
+            self.new_page().unwrap();
+        }
+
+        let mut image = self.pages.concat();
+        // The last page is reserved for the NVS library and stays erased
+        image.resize(image.len() + PAGE_SIZE, 0xff);
+
+        image
+    }
+}
+
+fn entry_header(ns_index: u8, ty: u8, span: u8, chunk_index: u8, key: &str) -> [u8; ENTRY_LEN] {
+    let mut header = [0xff; ENTRY_LEN];
+    header[0] = ns_index;
+    header[1] = ty;
+    header[2] = span;
+    header[3] = chunk_index;
+    header[8..24].fill(0);
+    header[8..8 + key.len()].copy_from_slice(key.as_bytes());
+
+    header
+}
+
+/// Set the CRC of an entry header, which covers all fields except the CRC itself.
+fn seal(header: &mut [u8; ENTRY_LEN]) {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xffff_ffff);
+    hasher.update(&header[0..4]);
+    hasher.update(&header[8..ENTRY_LEN]);
+
+    header[4..8].copy_from_slice(&hasher.finalize().to_le_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xffff_ffff);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn check_key(key: &str) -> Result<()> {
+    ensure!(!key.is_empty(), "Empty key");
+    ensure!(
+        key.len() <= MAX_KEY_LEN,
+        "Key '{key}' is longer than {MAX_KEY_LEN} bytes"
+    );
+
+    Ok(())
+}
+
+fn parse_int<T>(value: &str) -> Result<T>
+where
+    T: TryFrom<i128>,
+{
+    let value = value.trim();
+
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+
+    let parsed = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i128::from_str_radix(hex, 16),
+        None => digits.parse::<i128>(),
+    }
+    .with_context(|| anyhow!("Invalid integer '{value}'"))?;
+
+    T::try_from(if negative { -parsed } else { parsed })
+        .map_err(|_| anyhow!("Integer '{value}' is out of range"))
+}
+
+fn parse_hex(value: &str) -> Result<Vec<u8>> {
+    let value = value.trim();
+    ensure!(
+        value.len() % 2 == 0,
+        "Invalid hex value '{value}', its length must be a multiple of 2"
+    );
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| {
+            value
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hex value '{value}'"))
+        })
+        .collect()
+}
+
+/// Split a CSV line into its columns, handling double-quoted columns.
+fn split_csv_line(line: &str) -> Result<Vec<String>> {
+    let mut columns = Vec::new();
+    let mut column = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                column.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => columns.push(std::mem::take(&mut column)),
+            c => column.push(c),
+        }
+    }
+
+    ensure!(!quoted, "Unterminated quoted column");
+    columns.push(column);
+
+    Ok(columns
+        .into_iter()
+        .map(|column| column.trim().to_owned())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(image: &[u8], page: usize, index: usize) -> &[u8] {
+        let offset = page * PAGE_SIZE + FIRST_ENTRY_OFFSET + index * ENTRY_LEN;
+        &image[offset..offset + ENTRY_LEN]
+    }
+
+    #[test]
+    fn generate() -> Result<()> {
+        let csv = "key,type,encoding,value\n\
+                   cal,namespace,,\n\
+                   count,data,u16,0x1234\n\
+                   serial,data,string,\"SN,1\"\n\
+                   table,data,base64,AQID\n";
+
+        let mut nvs = NvsPartition::from_csv(csv, "")?;
+        nvs.insert("cal", "big", Value::Blob(vec![0x5a; 5000]))?;
+
+        assert_eq!(
+            nvs.namespaces[0].entries[1].value,
+            Value::String("SN,1".into())
+        );
+
+        let image = nvs.to_binary(0x4000)?;
+        assert_eq!(image.len(), 0x4000);
+
+        // Page header
+        assert_eq!(&image[0..9], &[0xfc, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0xfe]);
+        assert_eq!(image[28..32], crc32(&image[4..28]).to_le_bytes());
+        assert_eq!(&image[PAGE_SIZE + 4..PAGE_SIZE + 8], &[1, 0, 0, 0]);
+
+        // Namespace entry
+        let ns = entry(&image, 0, 0);
+        assert_eq!(&ns[0..4], &[0, TYPE_U8, 1, CHUNK_ANY]);
+        assert_eq!(&ns[8..12], b"cal\0");
+        assert_eq!(&ns[24..26], &[1, 0xff]);
+
+        let mut header = [0; ENTRY_LEN];
+        header.copy_from_slice(ns);
+        seal(&mut header);
+        assert_eq!(header, ns);
+
+        assert_eq!(&entry(&image, 0, 1)[24..27], &[0x34, 0x12, 0xff]);
+
+        // String entry and its data
+        let string = entry(&image, 0, 2);
+        assert_eq!(&string[0..4], &[1, TYPE_STRING, 2, CHUNK_ANY]);
+        assert_eq!(&string[24..26], &[5, 0]);
+        assert_eq!(&entry(&image, 0, 3)[0..6], b"SN,1\0\xff");
+
+        // Blobs are split into chunks across pages, followed by their index
+        assert_eq!(&entry(&image, 0, 4)[0..4], &[1, TYPE_BLOB_DATA, 2, 0]);
+        assert_eq!(&entry(&image, 0, 7)[0..4], &[1, TYPE_BLOB_DATA, 119, 0]);
+        assert_eq!(&entry(&image, 1, 0)[0..4], &[1, TYPE_BLOB_DATA, 40, 1]);
+        let index = entry(&image, 1, 40);
+        assert_eq!(&index[0..4], &[1, TYPE_BLOB_INDEX, 1, CHUNK_ANY]);
+        assert_eq!(&index[24..30], &[0x88, 0x13, 0, 0, 2, 0]);
+
+        // Entry state bitmap: 41 written entries on the second page
+        assert_eq!(
+            &image[PAGE_SIZE + BITMAP_OFFSET..][..3],
+            &[0xaa, 0xaa, 0xaa]
+        );
+
+        // Reserved last page
+        assert!(image[3 * PAGE_SIZE..].iter().all(|b| *b == 0xff));
+
+        nvs.insert("cal", "more", Value::Blob(vec![0; 4000]))?;
+        assert!(nvs.to_binary(0x3000).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,638 @@
+//! ESP-IDF partition tables.
+//!
+//! Parses the partition table CSV format and converts it to and from the binary table
+//! flashed to the device, equivalent to `gen_esp32part.py`.
+
+use std::fmt::{self, Display, Write};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use md5::{Digest, Md5};
+
+/// The default offset of the partition table in flash.
+pub const DEFAULT_PARTITION_TABLE_OFFSET: u32 = 0x8000;
+
+/// The flash space reserved for the partition table.
+pub const PARTITION_TABLE_SIZE: u32 = 0x1000;
+
+/// The maximum length of the binary partition table, including the MD5 entry.
+pub const MAX_PARTITION_TABLE_LEN: usize = 0xc00;
+
+const ENTRY_LEN: usize = 32;
+const ENTRY_MAGIC: [u8; 2] = [0xaa, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xeb, 0xeb];
+const MAX_NAME_LEN: usize = 16;
+
+const FLAG_ENCRYPTED: u32 = 1 << 0;
+const FLAG_READONLY: u32 = 1 << 1;
+
+const APP_ALIGN: u32 = 0x10000;
+const DATA_ALIGN: u32 = 0x1000;
+
+const APP_SUBTYPES: &[(&str, u8)] = &[("factory", 0x00), ("test", 0x20)];
+const DATA_SUBTYPES: &[(&str, u8)] = &[
+    ("ota", 0x00),
+    ("phy", 0x01),
+    ("nvs", 0x02),
+    ("coredump", 0x03),
+    ("nvs_keys", 0x04),
+    ("efuse", 0x05),
+    ("undefined", 0x06),
+    ("esphttpd", 0x80),
+    ("fat", 0x81),
+    ("spiffs", 0x82),
+    ("littlefs", 0x83),
+];
+
+/// The type of a partition.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PartitionType {
+    App,
+    Data,
+    /// A custom partition type in the range `0x40..=0xfe`.
+    Custom(u8),
+}
+
+impl PartitionType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x00 => Self::App,
+            0x01 => Self::Data,
+            other => Self::Custom(other),
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Self::App => 0x00,
+            Self::Data => 0x01,
+            Self::Custom(value) => *value,
+        }
+    }
+
+    /// The required alignment of partitions of this type.
+    pub fn alignment(&self) -> u32 {
+        match self {
+            Self::App => APP_ALIGN,
+            _ => DATA_ALIGN,
+        }
+    }
+
+    /// Parse the subtype column of the CSV for partitions of this type.
+    pub fn parse_subtype(&self, subtype: &str) -> Result<u8> {
+        if subtype.is_empty() {
+            return match self {
+                Self::App => bail!("App partitions cannot have an empty subtype"),
+                _ => Ok(0x06),
+            };
+        }
+
+        if let Some((_, value)) = self
+            .subtypes()
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(subtype))
+        {
+            return Ok(*value);
+        }
+
+        if *self == Self::App {
+            if let Some(ota) = subtype.strip_prefix("ota_") {
+                if let Ok(ota) = ota.parse::<u8>() {
+                    ensure!(ota < 16, "OTA app subtype '{subtype}' is out of range");
+
+                    return Ok(0x10 + ota);
+                }
+            }
+        }
+
+        parse_int(subtype)
+            .and_then(|value| {
+                u8::try_from(value).map_err(|_| anyhow!("Subtype {value:#x} is out of range"))
+            })
+            .with_context(|| anyhow!("Invalid subtype '{subtype}' for partition type {self}"))
+    }
+
+    /// Format `subtype` as its name if it has one, or as a hex number.
+    pub fn format_subtype(&self, subtype: u8) -> String {
+        if *self == Self::App && (0x10..0x20).contains(&subtype) {
+            return format!("ota_{}", subtype - 0x10);
+        }
+
+        self.subtypes()
+            .iter()
+            .find(|(_, value)| *value == subtype)
+            .map(|(name, _)| (*name).to_owned())
+            .unwrap_or_else(|| format!("{subtype:#04x}"))
+    }
+
+    fn subtypes(&self) -> &'static [(&'static str, u8)] {
+        match self {
+            Self::App => APP_SUBTYPES,
+            Self::Data => DATA_SUBTYPES,
+            Self::Custom(_) => &[],
+        }
+    }
+}
+
+impl std::str::FromStr for PartitionType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            _ if s.eq_ignore_ascii_case("app") => Ok(Self::App),
+            _ if s.eq_ignore_ascii_case("data") => Ok(Self::Data),
+            _ => {
+                let value = parse_int(s)
+                    .ok()
+                    .and_then(|value| u8::try_from(value).ok())
+                    .ok_or_else(|| anyhow!("Invalid partition type '{s}'"))?;
+
+                match Self::from_u8(value) {
+                    Self::Custom(value) if !(0x40..=0xfe).contains(&value) => bail!(
+                        "Partition type {value:#04x} is out of range, custom types must be in 0x40..=0xfe"
+                    ),
+                    ty => Ok(ty),
+                }
+            }
+        }
+    }
+}
+
+impl Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::App => f.write_str("app"),
+            Self::Data => f.write_str("data"),
+            Self::Custom(value) => write!(f, "{value:#04x}"),
+        }
+    }
+}
+
+/// A partition table entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    pub ty: PartitionType,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+    pub encrypted: bool,
+    pub readonly: bool,
+}
+
+impl Partition {
+    /// The flash offset right after this partition, or an error if it is beyond the
+    /// 32-bit address space.
+    pub fn end(&self) -> Result<u32> {
+        self.offset.checked_add(self.size).ok_or_else(|| {
+            anyhow!(
+                "Partition '{}' at {:#x} of size {:#x} ends beyond the 32-bit address space",
+                self.name,
+                self.offset,
+                self.size
+            )
+        })
+    }
+
+    fn to_binary(&self) -> [u8; ENTRY_LEN] {
+        let mut entry = [0; ENTRY_LEN];
+
+        let flags = if self.encrypted { FLAG_ENCRYPTED } else { 0 }
+            | if self.readonly { FLAG_READONLY } else { 0 };
+
+        entry[0..2].copy_from_slice(&ENTRY_MAGIC);
+        entry[2] = self.ty.as_u8();
+        entry[3] = self.subtype;
+        entry[4..8].copy_from_slice(&self.offset.to_le_bytes());
+        entry[8..12].copy_from_slice(&self.size.to_le_bytes());
+        entry[12..12 + self.name.len()].copy_from_slice(self.name.as_bytes());
+        entry[28..32].copy_from_slice(&flags.to_le_bytes());
+
+        entry
+    }
+
+    fn from_binary(entry: &[u8]) -> Result<Self> {
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                entry[offset],
+                entry[offset + 1],
+                entry[offset + 2],
+                entry[offset + 3],
+            ])
+        };
+
+        let name = &entry[12..28];
+        let name_len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+        let flags = word(28);
+
+        Ok(Self {
+            name: String::from_utf8(name[..name_len].to_vec())
+                .context("Partition name is not valid UTF-8")?,
+            ty: PartitionType::from_u8(entry[2]),
+            subtype: entry[3],
+            offset: word(4),
+            size: word(8),
+            encrypted: flags & FLAG_ENCRYPTED != 0,
+            readonly: flags & FLAG_READONLY != 0,
+        })
+    }
+}
+
+/// An ESP-IDF partition table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PartitionTable {
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Parse a partition table CSV file.
+    ///
+    /// Partitions without an offset are placed after the previous partition, aligned
+    /// as required by their type, starting right after the partition table at
+    /// `table_offset`. Like `gen_esp32part.py`, partitions must not start before the end
+    /// of the partition table.
+    pub fn from_csv_file(path: impl AsRef<Path>, table_offset: u32) -> Result<Self> {
+        let path = path.as_ref();
+
+        let csv = fs::read_to_string(path)
+            .with_context(|| anyhow!("Could not read {}", path.display()))?;
+
+        Self::from_csv(&csv, table_offset)
+            .with_context(|| anyhow!("Could not parse partition table {}", path.display()))
+    }
+
+    /// Parse a partition table CSV.
+    ///
+    /// See [`PartitionTable::from_csv_file`].
+    pub fn from_csv(csv: &str, table_offset: u32) -> Result<Self> {
+        let mut partitions = Vec::<Partition>::new();
+        let table_end = table_offset
+            .checked_add(PARTITION_TABLE_SIZE)
+            .ok_or_else(|| anyhow!("Partition table offset {table_offset:#x} is out of range"))?;
+        let mut next_offset = table_end;
+
+        for (line_no, line) in csv.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let partition = Self::parse_csv_line(line, next_offset)
+                .and_then(|partition| {
+                    ensure!(
+                        partition.offset >= table_end,
+                        "Partition '{}' at {:#x} is below the end of the partition table at {table_end:#x}",
+                        partition.name,
+                        partition.offset
+                    );
+
+                    Ok(partition)
+                })
+                .with_context(|| anyhow!("Error at line {}: '{line}'", line_no + 1))?;
+
+            next_offset = partition.end()?;
+            partitions.push(partition);
+        }
+
+        let table = Self { partitions };
+        table.verify(None)?;
+
+        Ok(table)
+    }
+
+    fn parse_csv_line(line: &str, next_offset: u32) -> Result<Partition> {
+        let mut columns = line.split(',').map(str::trim);
+        let mut column = || columns.next().unwrap_or_default();
+
+        let name = column();
+        let ty = column();
+        let subtype = column();
+        let offset = column();
+        let size = column();
+        let flags = column();
+
+        ensure!(!name.is_empty(), "Missing partition name");
+
+        let ty = ty.parse::<PartitionType>()?;
+        let subtype = ty.parse_subtype(subtype)?;
+
+        let offset = if offset.is_empty() {
+            align_up(next_offset, ty.alignment())
+                .ok_or_else(|| anyhow!("Partition offset is out of range"))?
+        } else {
+            parse_size(offset)?
+        };
+
+        ensure!(!size.is_empty(), "Missing partition size");
+        let size = parse_size(size)?;
+
+        let mut encrypted = false;
+        let mut readonly = false;
+        for flag in flags.split(':').map(str::trim).filter(|f| !f.is_empty()) {
+            match flag {
+                "encrypted" => encrypted = true,
+                "readonly" => readonly = true,
+                _ => bail!("Unknown partition flag '{flag}'"),
+            }
+        }
+
+        Ok(Partition {
+            name: name.to_owned(),
+            ty,
+            subtype,
+            offset,
+            size,
+            encrypted,
+            readonly,
+        })
+    }
+
+    /// Decode a binary partition table, verifying its MD5 entry if present.
+    pub fn from_binary(data: &[u8]) -> Result<Self> {
+        let mut partitions = Vec::new();
+
+        for (index, entry) in data.chunks(ENTRY_LEN).enumerate() {
+            ensure!(entry.len() == ENTRY_LEN, "Partition table is truncated");
+
+            if entry.iter().all(|b| *b == 0xff) {
+                break;
+            }
+
+            if entry[..2] == MD5_MAGIC {
+                let digest = Md5::digest(&data[..index * ENTRY_LEN]);
+                ensure!(
+                    entry[16..] == digest[..],
+                    "Partition table MD5 checksum does not match"
+                );
+
+                continue;
+            }
+
+            ensure!(
+                entry[..2] == ENTRY_MAGIC,
+                "Invalid magic of partition table entry {index}"
+            );
+
+            partitions.push(Partition::from_binary(entry)?);
+        }
+
+        Ok(Self { partitions })
+    }
+
+    /// Decode a binary partition table file, see [`PartitionTable::from_binary`].
+    pub fn from_binary_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let data = fs::read(path).with_context(|| anyhow!("Could not read {}", path.display()))?;
+
+        Self::from_binary(&data)
+            .with_context(|| anyhow!("Could not decode partition table {}", path.display()))
+    }
+
+    /// Encode the binary partition table with its MD5 entry, padded with `0xff` to
+    /// [`MAX_PARTITION_TABLE_LEN`].
+    pub fn to_binary(&self) -> Result<Vec<u8>> {
+        self.verify(None)?;
+
+        let mut data = self
+            .partitions
+            .iter()
+            .flat_map(|partition| partition.to_binary())
+            .collect::<Vec<_>>();
+
+        let digest = Md5::digest(&data);
+        data.extend_from_slice(&MD5_MAGIC);
+        data.extend_from_slice(&[0xff; 14]);
+        data.extend_from_slice(&digest);
+
+        ensure!(
+            data.len() <= MAX_PARTITION_TABLE_LEN,
+            "Partition table with {} entries is too large",
+            self.partitions.len()
+        );
+
+        data.resize(MAX_PARTITION_TABLE_LEN, 0xff);
+
+        Ok(data)
+    }
+
+    /// Format the partition table as CSV.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::new();
+
+        csv.push_str("# ESP-IDF Partition Table\n");
+        csv.push_str("# Name, Type, SubType, Offset, Size, Flags\n");
+
+        for partition in &self.partitions {
+            let flags = [
+                (partition.encrypted, "encrypted"),
+                (partition.readonly, "readonly"),
+            ]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| *flag)
+            .collect::<Vec<_>>()
+            .join(":");
+
+            writeln!(
+                &mut csv,
+                "{},{},{},{:#x},{},{}",
+                partition.name,
+                partition.ty,
+                partition.ty.format_subtype(partition.subtype),
+                partition.offset,
+                format_size(partition.size),
+                flags
+            )
+            .unwrap();
+        }
+
+        csv
+    }
+
+    /// Check partition names, alignment and overlaps, and if `flash_size` is given that
+    /// all partitions fit in flash.
+    pub fn verify(&self, flash_size: Option<u64>) -> Result<()> {
+        let mut sorted = self.partitions.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|partition| partition.offset);
+
+        for partition in &sorted {
+            ensure!(
+                partition.name.len() <= MAX_NAME_LEN,
+                "Partition name '{}' is longer than {MAX_NAME_LEN} bytes",
+                partition.name
+            );
+
+            ensure!(
+                partition.offset % partition.ty.alignment() == 0,
+                "Partition '{}' offset {:#x} is not aligned to {:#x}",
+                partition.name,
+                partition.offset,
+                partition.ty.alignment()
+            );
+
+            ensure!(
+                self.partitions
+                    .iter()
+                    .filter(|p| p.name == partition.name)
+                    .count()
+                    == 1,
+                "Partition name '{}' is not unique",
+                partition.name
+            );
+
+            let end = partition.end()?;
+
+            if let Some(flash_size) = flash_size {
+                ensure!(
+                    end as u64 <= flash_size,
+                    "Partition '{}' ends at {end:#x}, beyond the flash size {flash_size:#x}",
+                    partition.name
+                );
+            }
+        }
+
+        for pair in sorted.windows(2) {
+            let end = pair[0].end()?;
+
+            ensure!(
+                end <= pair[1].offset,
+                "Partition '{}' at {:#x} overlaps partition '{}' ending at {end:#x}",
+                pair[1].name,
+                pair[1].offset,
+                pair[0].name
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Find a partition by name.
+    pub fn find(&self, name: &str) -> Option<&Partition> {
+        self.partitions
+            .iter()
+            .find(|partition| partition.name == name)
+    }
+
+    /// Find the first partition of type `ty` and `subtype`.
+    pub fn find_by_type(&self, ty: PartitionType, subtype: u8) -> Option<&Partition> {
+        self.partitions
+            .iter()
+            .find(|partition| partition.ty == ty && partition.subtype == subtype)
+    }
+}
+
+/// Parse an integer in decimal or `0x` hexadecimal notation.
+fn parse_int(value: &str) -> Result<u64> {
+    let parsed = if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16)
+    } else {
+        value.parse::<u64>()
+    };
+
+    parsed.with_context(|| anyhow!("Invalid number '{value}'"))
+}
+
+/// Parse an offset or size with an optional `K` or `M` suffix.
+fn parse_size(value: &str) -> Result<u32> {
+    let (number, multiplier) = match value.as_bytes().last() {
+        Some(b'k' | b'K') => (&value[..value.len() - 1], 1024),
+        Some(b'm' | b'M') => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    let size = parse_int(number.trim())? * multiplier;
+
+    u32::try_from(size).map_err(|_| anyhow!("Size or offset '{value}' is out of range"))
+}
+
+fn format_size(size: u32) -> String {
+    if size % 0x10_0000 == 0 {
+        format!("{}M", size / 0x10_0000)
+    } else if size % 1024 == 0 {
+        format!("{}K", size / 1024)
+    } else {
+        format!("{size:#x}")
+    }
+}
+
+/// Align `value` up to a multiple of `align`, or [`None`] if that is out of range.
+fn align_up(value: u32, align: u32) -> Option<u32> {
+    Some(value.checked_add(align - 1)? / align * align)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSV: &str = "
+        # Name,   Type, SubType, Offset,  Size, Flags
+        nvs,      data, nvs,     ,        0x6000,
+        phy_init, data, phy,     ,        0x1000,
+        factory,  app,  factory, ,        1M,
+        ota_1,    app,  ota_1,   ,        1M, readonly
+    ";
+
+    #[test]
+    fn csv_binary_roundtrip() {
+        let table = PartitionTable::from_csv(CSV, DEFAULT_PARTITION_TABLE_OFFSET).unwrap();
+
+        let offsets = table
+            .partitions
+            .iter()
+            .map(|p| p.offset)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [0x9000, 0xf000, 0x10000, 0x110000]);
+        assert_eq!(table.partitions[3].subtype, 0x11);
+        assert!(table.partitions[3].readonly);
+
+        let binary = table.to_binary().unwrap();
+        assert_eq!(binary.len(), MAX_PARTITION_TABLE_LEN);
+        assert_eq!(binary[4 * ENTRY_LEN..4 * ENTRY_LEN + 2], MD5_MAGIC);
+
+        let decoded = PartitionTable::from_binary(&binary).unwrap();
+        assert_eq!(decoded, table);
+
+        assert_eq!(
+            decoded.to_csv(),
+            "# ESP-IDF Partition Table\n\
+             # Name, Type, SubType, Offset, Size, Flags\n\
+             nvs,data,nvs,0x9000,24K,\n\
+             phy_init,data,phy,0xf000,4K,\n\
+             factory,app,factory,0x10000,1M,\n\
+             ota_1,app,ota_1,0x110000,1M,readonly\n"
+        );
+    }
+
+    #[test]
+    fn overlap() {
+        let csv = "nvs, data, nvs, 0x9000, 0x6000,\nphy, data, phy, 0xa000, 0x1000,";
+        assert!(PartitionTable::from_csv(csv, DEFAULT_PARTITION_TABLE_OFFSET).is_err());
+    }
+
+    #[test]
+    fn out_of_range() {
+        let below_table = "nvs, data, nvs, 0x8000, 0x6000,";
+        assert!(PartitionTable::from_csv(below_table, DEFAULT_PARTITION_TABLE_OFFSET).is_err());
+
+        let overflow = "nvs, data, nvs, 0xfffff000, 0x2000,";
+        assert!(PartitionTable::from_csv(overflow, DEFAULT_PARTITION_TABLE_OFFSET).is_err());
+
+        let align_overflow = "nvs, data, nvs, 0xffff0000, 0xf001,
+factory, app, factory, , 1M,";
+        assert!(PartitionTable::from_csv(align_overflow, DEFAULT_PARTITION_TABLE_OFFSET).is_err());
+
+        assert_eq!(
+            "0x40".parse::<PartitionType>().unwrap(),
+            PartitionType::Custom(0x40)
+        );
+        assert_eq!("1".parse::<PartitionType>().unwrap(), PartitionType::Data);
+        assert!("0x3f".parse::<PartitionType>().is_err());
+        // The type of empty entries
+        assert!("0xff".parse::<PartitionType>().is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Helpers for the tests of the ULP and LP core builders.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Create the files `paths` in `dir`, and their directories.
+pub fn touch(dir: &Path, paths: &[&str]) {
+    for path in paths {
+        let path = dir.join(path);
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+}
+
+/// A fake `riscv32-esp-elf` toolchain in `dir/bin`, which logs its command lines to
+/// `dir/log` and writes its output, an ELF file when linking.
+pub fn fake_toolchain(dir: &Path) -> PathBuf {
+    let bin = dir.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+
+    let script = format!(
+        r#"#!/bin/sh
+echo "$(basename "$0") $*" >> "{log}"
+for arg; do
+    [ "$prev" = "-o" ] && out="$arg"
+    prev="$arg"
+done
+case " $* " in
+    *" -nostartfiles "*) cp "{elf}" "$out" ;;
+    *) : > "${{out:-$prev}}" ;;
+esac
+"#,
+        log = dir.join("log").display(),
+        elf = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/symgen/testdata/symbols.elf"
+        ),
+    );
+
+    for tool in ["riscv32-esp-elf-gcc", "riscv32-esp-elf-objcopy"] {
+        let tool = bin.join(tool);
+
+        fs::write(&tool, &script).unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    bin
+}
+
+/// The logged command lines, relative to `dir`.
+pub fn log(dir: &Path) -> Vec<String> {
+    fs::read_to_string(dir.join("log"))
+        .unwrap()
+        .replace(&format!("{}/", dir.display()), "")
+        .lines()
+        .map(str::to_owned)
+        .collect()
+}
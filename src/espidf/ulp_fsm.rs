@@ -2,10 +2,13 @@ use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::{env, iter};
 
+use super::incremental::Tracker;
 use crate::build::CInclArgs;
 use crate::utils::OsStrExt;
 use crate::{symgen, *};
 
+pub mod asm;
+
 #[derive(Clone, Debug)]
 pub enum SystemIncludes {
     CInclArgs(CInclArgs),
@@ -25,6 +28,8 @@ pub struct Builder {
     add_includes: Vec<String>,
     gcc: Option<String>,
     env_path: Option<OsString>,
+    builtin_assembler: bool,
+    reserve_mem: Option<u32>,
 }
 
 impl Builder {
@@ -40,6 +45,8 @@ impl Builder {
             add_includes: add_includes.into(),
             gcc: None,
             env_path: env::var_os("DEP_ESP_IDF_EMBUILD_ENV_PATH"),
+            builtin_assembler: false,
+            reserve_mem: None,
         })
     }
 
@@ -56,9 +63,42 @@ impl Builder {
             add_includes: add_includes.into(),
             gcc,
             env_path,
+            builtin_assembler: false,
+            reserve_mem: None,
         }
     }
 
+    /// Whether to assemble and link the ULP program with the [built-in assembler](asm)
+    /// instead of the `esp32ulp-elf` binutils (defaults to `false`).
+    ///
+    /// The sources are still preprocessed with `gcc`, but the binutils need not be
+    /// installed.
+    #[must_use]
+    pub fn builtin_assembler(mut self, builtin_assembler: bool) -> Self {
+        self.builtin_assembler = builtin_assembler;
+        self
+    }
+
+    /// Set the size of the memory reserved for the ULP program, which the built-in
+    /// assembler checks the program against (defaults to the
+    /// `CONFIG_ULP_COPROC_RESERVE_MEM` of the `sdkconfig.h` in the include directories).
+    #[must_use]
+    pub fn reserve_mem(mut self, reserve_mem: u32) -> Self {
+        self.reserve_mem = Some(reserve_mem);
+        self
+    }
+
+    /// Build the ULP program from the assembly files `ulp_sources` into `out_dir`.
+    ///
+    /// Steps whose inputs did not change since the last build are skipped, and the
+    /// build script is rerun if any of the sources, the headers they include or the
+    /// linker script change.
+    ///
+    /// The generated symbols module has a `*mut u32` for each global label of the
+    /// `.text`, `.bss` and `.data` sections: the assembly has no type information and
+    /// the FSM ULP only accesses memory as 32-bit words (of which it uses the lower 16
+    /// bits), and the addresses of the code labels are needed to start the program at
+    /// its entry point.
     pub fn build<'a, I>(
         &self,
         ulp_sources: I,
@@ -71,9 +111,63 @@ impl Builder {
 
         let include_args = self.include_args();
 
+        let mut tracker = Tracker::default();
+
         let ulp_obj_out_dir = path_buf![&out_dir, "obj"];
 
-        self.compile(ulp_sources, &include_args, &ulp_obj_out_dir)?;
+        let ulp_elf = path_buf![&out_dir, "ulp"];
+        let ulp_bin = path_buf![&out_dir, "ulp.bin"];
+
+        if self.builtin_assembler {
+            let sources =
+                self.preprocess(ulp_sources, &include_args, &ulp_obj_out_dir, &mut tracker)?;
+
+            let reserve_mem = match self.reserve_mem {
+                Some(reserve_mem) => Some(reserve_mem),
+                None => sdkconfig_reserve_mem(&include_args)?,
+            };
+
+            self.assemble(&sources, reserve_mem, &ulp_elf, &ulp_bin, &mut tracker)?;
+        } else {
+            self.build_with_binutils(
+                ulp_sources,
+                &include_args,
+                out_dir,
+                &ulp_obj_out_dir,
+                &ulp_elf,
+                &ulp_bin,
+                &mut tracker,
+            )?;
+        }
+
+        let ulp_sym_rs = path_buf![&out_dir, "ulp.rs"];
+
+        self.symbolize(&ulp_elf, &ulp_sym_rs, &mut tracker)?;
+
+        tracker.track();
+
+        Ok(BuildResult {
+            bin_file: ulp_bin,
+            elf_file: ulp_elf,
+            sym_rs_file: ulp_sym_rs,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn build_with_binutils<'a, I>(
+        &self,
+        ulp_sources: I,
+        include_args: &[String],
+        out_dir: &Path,
+        obj_out_dir: &Path,
+        ulp_elf: &Path,
+        ulp_bin: &Path,
+        tracker: &mut Tracker,
+    ) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        let objects = self.compile(ulp_sources, include_args, obj_out_dir, tracker)?;
 
         let ulp_ld_script = ["ulp_fsm.ld", "esp32.ulp.ld"]
             .into_iter()
@@ -83,55 +177,105 @@ impl Builder {
 
         let ulp_ld_out_script = path_buf![&out_dir, "ulp.ld"];
 
-        self.preprocess_one(&ulp_ld_script, &include_args, &ulp_ld_out_script)?;
+        self.preprocess_one(&ulp_ld_script, include_args, &ulp_ld_out_script, tracker)?;
 
-        let ulp_elf = path_buf![&out_dir, "ulp"];
+        self.link(&objects, &ulp_ld_out_script, ulp_elf, tracker)?;
 
-        self.link(&ulp_obj_out_dir, &ulp_ld_out_script, &ulp_elf)?;
+        self.bin(ulp_elf, ulp_bin, tracker)
+    }
 
-        let ulp_bin = path_buf![&out_dir, "ulp.bin"];
+    fn compile<'a, I>(
+        &self,
+        ulp_sources: I,
+        include_args: &[impl AsRef<OsStr>],
+        out_dir: &Path,
+        tracker: &mut Tracker,
+    ) -> anyhow::Result<Vec<PathBuf>>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        let mut objects = Vec::new();
 
-        self.bin(&ulp_elf, &ulp_bin)?;
+        for ulp_preprocessed_source in
+            self.preprocess(ulp_sources, include_args, out_dir, tracker)?
+        {
+            let ulp_object = ulp_preprocessed_source.with_extension("o");
 
-        let ulp_sym_rs = path_buf![&out_dir, "ulp.rs"];
+            self.compile_one(&ulp_preprocessed_source, &ulp_object, tracker)?;
 
-        self.symbolize(&ulp_elf, &ulp_sym_rs)?;
+            objects.push(ulp_object);
+        }
 
-        Ok(BuildResult {
-            bin_file: ulp_bin,
-            elf_file: ulp_elf,
-            sym_rs_file: ulp_sym_rs,
-        })
+        Ok(objects)
     }
 
-    fn compile<'a, I>(
+    fn preprocess<'a, I>(
         &self,
         ulp_sources: I,
         include_args: &[impl AsRef<OsStr>],
         out_dir: &Path,
-    ) -> anyhow::Result<()>
+        tracker: &mut Tracker,
+    ) -> anyhow::Result<Vec<PathBuf>>
     where
         I: IntoIterator<Item = &'a Path>,
     {
+        let mut sources = Vec::new();
+
         for ulp_source in ulp_sources {
             std::fs::create_dir_all(out_dir)?;
 
-            let ulp_preprocessed_source = Self::resuffix(ulp_source, out_dir, "ulp.S")?;
+            let ulp_preprocessed_source = resuffix(ulp_source, out_dir, "ulp.S")?;
 
-            self.preprocess_one(ulp_source, include_args, &ulp_preprocessed_source)?;
+            self.preprocess_one(ulp_source, include_args, &ulp_preprocessed_source, tracker)?;
 
-            let ulp_object = Self::resuffix(ulp_source, out_dir, "o")?;
-
-            self.compile_one(&ulp_preprocessed_source, &ulp_object)?;
+            sources.push(ulp_preprocessed_source);
         }
 
-        Ok(())
+        Ok(sources)
     }
 
-    fn compile_one(&self, ulp_source: &Path, out_file: &Path) -> anyhow::Result<()> {
-        cmd![self.tool("esp32ulp-elf-as")?, "-o", out_file, ulp_source].run()?;
+    fn assemble(
+        &self,
+        sources: &[PathBuf],
+        reserve_mem: Option<u32>,
+        ulp_elf: &Path,
+        ulp_bin: &Path,
+        tracker: &mut Tracker,
+    ) -> anyhow::Result<()> {
+        let inputs = sources.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+        let stamp = format!("{ASM_STAMP} {reserve_mem:?} {sources:?}");
+
+        // The binary is written along with the ELF file, from the same program
+        tracker.step(&[ulp_elf, ulp_bin], &inputs, None, &stamp, || {
+            let mut assembler = asm::Assembler::new();
+
+            if let Some(reserve_mem) = reserve_mem {
+                assembler = assembler.reserved_size(reserve_mem);
+            }
+
+            for source in sources {
+                assembler.add_file(source)?;
+            }
+
+            let program = assembler.link()?;
 
-        Ok(())
+            program.write_binary(ulp_bin)?;
+            program.write_elf(ulp_elf)
+        })
+    }
+
+    fn compile_one(
+        &self,
+        ulp_source: &Path,
+        out_file: &Path,
+        tracker: &mut Tracker,
+    ) -> anyhow::Result<()> {
+        tracker.run(
+            cmd![self.tool("esp32ulp-elf-as")?, "-o", out_file, ulp_source],
+            out_file,
+            &[ulp_source],
+            None,
+        )
     }
 
     fn preprocess_one(
@@ -139,84 +283,111 @@ impl Builder {
         source: &Path,
         include_args: &[impl AsRef<OsStr>],
         out_file: &Path,
+        tracker: &mut Tracker,
     ) -> anyhow::Result<()> {
-        cmd![
-            self.tool(self.gcc.as_deref().unwrap_or("xtensa-esp32-elf-gcc"))?,
-            "-E",
-            "-P",
-            "-xc",
-            "-D__ASSEMBLER__",
-            @include_args,
-            "-o",
+        let dep_file = dep_file(out_file);
+
+        tracker.run(
+            cmd![
+                self.tool(self.gcc.as_deref().unwrap_or("xtensa-esp32-elf-gcc"))?,
+                "-E",
+                "-P",
+                "-xc",
+                "-D__ASSEMBLER__",
+                "-MD",
+                "-MF",
+                &dep_file,
+                @include_args,
+                "-o",
+                out_file,
+                source
+            ],
             out_file,
-            source
-        ]
-        .run()?;
-
-        Ok(())
+            &[source],
+            Some(&dep_file),
+        )
     }
 
     fn link(
         &self,
-        object_files_dir: &Path,
+        objects: &[PathBuf],
         linker_script: &Path,
         out_file: &Path,
+        tracker: &mut Tracker,
     ) -> anyhow::Result<()> {
-        let object_files = std::fs::read_dir(object_files_dir)?
-            .filter_map(|file| {
-                file.ok()
-                    .filter(|file| file.path().extension().map(|e| e == "o").unwrap_or(false))
-            })
-            .map(|de| de.path().as_os_str().to_owned())
+        let inputs = objects
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(iter::once(linker_script))
             .collect::<Vec<_>>();
 
-        cmd![
-            self.tool("esp32ulp-elf-ld")?,
-            "-T",
-            linker_script,
-            @object_files,
-            "-o",
-            out_file
-        ]
-        .run()?;
-
-        Ok(())
+        tracker.run(
+            cmd![
+                self.tool("esp32ulp-elf-ld")?,
+                "-T",
+                linker_script,
+                @objects,
+                "-o",
+                out_file
+            ],
+            out_file,
+            &inputs,
+            None,
+        )
     }
 
-    fn bin(&self, ulp_elf: &Path, out_file: &Path) -> anyhow::Result<()> {
+    fn bin(&self, ulp_elf: &Path, out_file: &Path, tracker: &mut Tracker) -> anyhow::Result<()> {
         // TODO: Switch to our own bingen in embuild
-        cmd![
-            self.tool("esp32ulp-elf-objcopy")?,
-            ulp_elf,
-            "-O",
-            "binary",
-            out_file
-        ]
-        .run()?;
-
-        Ok(())
+        tracker.run(
+            cmd![
+                self.tool("esp32ulp-elf-objcopy")?,
+                ulp_elf,
+                "-O",
+                "binary",
+                out_file
+            ],
+            out_file,
+            &[ulp_elf],
+            None,
+        )
     }
 
-    fn symbolize(&self, ulp_elf: &Path, out_file: &Path) -> anyhow::Result<()> {
-        symgen::Symgen::new_with_pointer_gen(ulp_elf, 0x5000_0000_u64, |symbol| {
-            symbol
-                .sections(&[
-                    symgen::Section::code(".text"),
-                    symgen::Section::data(".bss"),
-                    symgen::Section::data(".data"),
-                ])
-                .map(|mut pointer| {
-                    pointer.r#type = Some("u32".to_owned());
-                    pointer
-                })
+    fn symbolize(
+        &self,
+        ulp_elf: &Path,
+        out_file: &Path,
+        tracker: &mut Tracker,
+    ) -> anyhow::Result<()> {
+        tracker.step(&[out_file], &[ulp_elf], None, SYMGEN_STAMP, || {
+            symgen::Symgen::new_with_pointer_gen(ulp_elf, 0x5000_0000_u64, |symbol| {
+                symbol
+                    .sections(&[
+                        symgen::Section::code(".text"),
+                        symgen::Section::data(".bss"),
+                        symgen::Section::data(".data"),
+                    ])
+                    .map(|mut pointer| {
+                        pointer.r#type = Some("u32".to_owned());
+                        pointer
+                    })
+            })
+            .run_for_file(out_file)
         })
-        .run_for_file(out_file)
     }
 
     fn include_args(&self) -> Vec<String> {
-        match self.sys_includes {
-            SystemIncludes::CInclArgs(ref include_args) => self
-                .add_includes
+        self.sys_includes.args(&self.esp_idf, &self.add_includes)
+    }
+
+    fn tool(&self, tool: &str) -> anyhow::Result<PathBuf> {
+        find_tool(tool, self.env_path.clone())
+    }
+}
+
+impl SystemIncludes {
+    pub(super) fn args(&self, esp_idf: &Path, add_includes: &[String]) -> Vec<String> {
+        match self {
+            SystemIncludes::CInclArgs(ref include_args) => add_includes
                 .iter()
                 .cloned()
                 .chain(
@@ -226,13 +397,12 @@ impl Builder {
                         .filter_map(Self::unescape),
                 )
                 .collect::<Vec<_>>(),
-            SystemIncludes::MCU(ref mcu) => self
-                .add_includes
+            SystemIncludes::MCU(ref mcu) => add_includes
                 .iter()
                 .cloned()
                 .chain(iter::once(format!(
                     "{}",
-                    path_buf![&self.esp_idf, "components", "soc", mcu].display()
+                    path_buf![esp_idf, "components", "soc", mcu].display()
                 )))
                 .flat_map(|s| iter::once("-I".to_owned()).chain(iter::once(s)))
                 .collect::<Vec<_>>(),
@@ -252,25 +422,117 @@ impl Builder {
             None
         }
     }
+}
 
-    fn resuffix(path: &Path, out_dir: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
-        let resuffixed = path_buf![
-            &out_dir,
-            format!(
-                "{}.{}",
-                path.file_stem()
-                    .ok_or_else(|| anyhow::anyhow!("Wrong file name {}", path.display()))?
-                    .try_to_str()?,
-                suffix
-            )
-        ];
+/// The `CONFIG_ULP_COPROC_RESERVE_MEM` of the `sdkconfig.h` in the directories of the
+/// `-I` and `-isystem` options in `include_args`, if any.
+fn sdkconfig_reserve_mem(include_args: &[String]) -> anyhow::Result<Option<u32>> {
+    let mut args = include_args.iter();
+
+    while let Some(arg) = args.next() {
+        let dir = match arg.as_str() {
+            "-I" | "-isystem" => args.next().map(String::as_str),
+            _ => arg
+                .strip_prefix("-isystem")
+                .or_else(|| arg.strip_prefix("-I")),
+        };
 
-        Ok(resuffixed)
+        let sdkconfig_h = match dir {
+            Some(dir) => path_buf![dir, "sdkconfig.h"],
+            None => continue,
+        };
+
+        if !sdkconfig_h.exists() {
+            continue;
+        }
+
+        let content = std::fs::read_to_string(&sdkconfig_h)
+            .map_err(|e| anyhow::anyhow!("Could not read {}: {e}", sdkconfig_h.display()))?;
+
+        let reserve_mem = content.lines().find_map(|line| {
+            let mut words = line.split_whitespace();
+
+            match (words.next(), words.next(), words.next()) {
+                (Some("#define"), Some("CONFIG_ULP_COPROC_RESERVE_MEM"), Some(value)) => {
+                    Some(value)
+                }
+                _ => None,
+            }
+        });
+
+        return reserve_mem
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    anyhow::anyhow!(
+                        "Invalid CONFIG_ULP_COPROC_RESERVE_MEM `{value}` in {}",
+                        sdkconfig_h.display()
+                    )
+                })
+            })
+            .transpose();
     }
 
-    fn tool(&self, tool: &str) -> anyhow::Result<PathBuf> {
-        let path = which::which_in(tool, self.env_path.clone(), env::current_dir()?)?;
+    Ok(None)
+}
+
+/// The stamp of the steps of the built-in assembler, see [`Tracker::step`].
+const ASM_STAMP: &str = concat!("asm ", env!("CARGO_PKG_VERSION"));
+
+/// The stamp of the symgen steps, see [`Tracker::step`].
+pub(super) const SYMGEN_STAMP: &str = concat!("symgen ", env!("CARGO_PKG_VERSION"));
+
+/// The dependency file written by `gcc -MD` for `out_file`.
+pub(super) fn dep_file(out_file: &Path) -> PathBuf {
+    let mut dep_file = OsString::from(out_file.as_os_str());
+    dep_file.push(".d");
+
+    PathBuf::from(dep_file)
+}
+
+pub(super) fn resuffix(path: &Path, out_dir: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
+    let resuffixed = path_buf![
+        &out_dir,
+        format!(
+            "{}.{}",
+            path.file_stem()
+                .ok_or_else(|| anyhow::anyhow!("Wrong file name {}", path.display()))?
+                .try_to_str()?,
+            suffix
+        )
+    ];
+
+    Ok(resuffixed)
+}
+
+pub(super) fn find_tool(tool: &str, env_path: Option<OsString>) -> anyhow::Result<PathBuf> {
+    let path = which::which_in(tool, env_path, env::current_dir()?)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_mem() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().join("config");
+
+        std::fs::create_dir(&config_dir).unwrap();
+        std::fs::write(
+            config_dir.join("sdkconfig.h"),
+            "#pragma once\n#define CONFIG_ULP_COPROC_ENABLED 1\n#define CONFIG_ULP_COPROC_RESERVE_MEM 512\n",
+        )
+        .unwrap();
+
+        let args = [
+            "-I".to_owned(),
+            dir.path().display().to_string(),
+            format!("-isystem{}", config_dir.display()),
+        ];
 
-        Ok(path)
+        assert_eq!(sdkconfig_reserve_mem(&args).unwrap(), Some(512));
+        assert_eq!(sdkconfig_reserve_mem(&args[..2]).unwrap(), None);
     }
 }
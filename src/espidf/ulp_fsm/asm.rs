@@ -0,0 +1,1748 @@
+//! An assembler for the ULP FSM coprocessor of the ESP32.
+//!
+//! Assembles preprocessed sources written in the dialect of the `esp32ulp-elf` binutils
+//! and links them into the same program as `esp32ulp-elf-ld` does with the linker
+//! script of ESP-IDF, so that ULP programs can be built without the binutils.
+//!
+//! Supported are all instructions of the ESP32 ULP FSM coprocessor, including the
+//! `JUMPR` and `JUMPS` conditions which are emulated with two instructions, labels,
+//! the `.text`, `.data` and `.bss` sections, `.global`, `.set` and the data directives
+//! `.long`, `.int`, `.word`, `.short`, `.byte`, `.skip`, `.space` and `.balign`.
+//!
+//! Like in the binutils, labels used as the immediate of an ALU instruction evaluate to
+//! their address in 32-bit words, whereas `JUMP`, `LD` and `ST` as well as the data
+//! directives take byte addresses.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+/// The magic number at the start of ULP binaries (`ulp\0`).
+pub const BINARY_MAGIC: u32 = 0x0070_6c75;
+
+/// The size of the header of ULP binaries, which is also the offset of the text.
+pub const HEADER_SIZE: u16 = 12;
+
+/// The base address of the RTC_CNTL registers, relative to which `REG_RD` and `REG_WR`
+/// address registers.
+const DR_REG_RTCCNTL_BASE: i64 = 0x3ff4_8000;
+
+/// The largest register address `REG_RD` and `REG_WR` can encode.
+const DR_REG_MAX_DIRECT: i64 = 0x3ff;
+
+const OPCODE_WR_REG: u32 = 1;
+const OPCODE_RD_REG: u32 = 2;
+const OPCODE_I2C: u32 = 3;
+const OPCODE_DELAY: u32 = 4;
+const OPCODE_ADC: u32 = 5;
+const OPCODE_ST: u32 = 6;
+const OPCODE_ALU: u32 = 7;
+const OPCODE_BRANCH: u32 = 8;
+const OPCODE_END: u32 = 9;
+const OPCODE_TSENS: u32 = 10;
+const OPCODE_HALT: u32 = 11;
+const OPCODE_LD: u32 = 13;
+
+const SUB_OPCODE_ST: u32 = 4;
+
+const SUB_OPCODE_ALU_REG: u32 = 0;
+const SUB_OPCODE_ALU_IMM: u32 = 1;
+const SUB_OPCODE_ALU_CNT: u32 = 2;
+
+const ALU_SEL_ADD: u32 = 0;
+const ALU_SEL_SUB: u32 = 1;
+const ALU_SEL_AND: u32 = 2;
+const ALU_SEL_OR: u32 = 3;
+const ALU_SEL_MOV: u32 = 4;
+const ALU_SEL_LSH: u32 = 5;
+const ALU_SEL_RSH: u32 = 6;
+
+const ALU_SEL_SINC: u32 = 0;
+const ALU_SEL_SDEC: u32 = 1;
+const ALU_SEL_SRST: u32 = 2;
+
+const SUB_OPCODE_BX: u32 = 0;
+const SUB_OPCODE_B: u32 = 1;
+const SUB_OPCODE_BS: u32 = 2;
+
+const BX_JUMP_TYPE_DIRECT: u32 = 0;
+const BX_JUMP_TYPE_ZERO: u32 = 1;
+const BX_JUMP_TYPE_OVF: u32 = 2;
+
+const B_CMP_LT: u32 = 0;
+const B_CMP_GE: u32 = 1;
+
+const BS_CMP_LT: u32 = 0;
+const BS_CMP_GE: u32 = 1;
+const BS_CMP_LE: u32 = 2;
+
+const SUB_OPCODE_WAKE: u32 = 0;
+const SUB_OPCODE_SLEEP: u32 = 1;
+
+/// A section of a ULP program.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Section {
+    Text,
+    Data,
+    Bss,
+}
+
+impl Section {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Text => ".text",
+            Self::Data => ".data",
+            Self::Bss => ".bss",
+        }
+    }
+
+    /// The section the input section `name` is placed in by the linker script.
+    fn from_input_section(name: &str) -> Option<Self> {
+        let matches = |section: &str| {
+            name == section
+                || name
+                    .strip_prefix(section)
+                    .map_or(false, |suffix| suffix.starts_with('.'))
+        };
+
+        if matches(".text") {
+            Some(Self::Text)
+        } else if matches(".data") || matches(".rodata") {
+            Some(Self::Data)
+        } else if matches(".bss") {
+            Some(Self::Bss)
+        } else {
+            None
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// A symbol of a linked [`Program`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// The section of a label, or [`None`] for symbols defined with `.set`.
+    pub section: Option<Section>,
+    /// The byte address of a label in the ULP memory, or the value of a `.set` symbol.
+    pub value: u32,
+    /// Whether the symbol was declared with `.global`.
+    pub global: bool,
+}
+
+/// A linked ULP program.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub bss_size: u32,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Program {
+    /// The binary loaded by `ulp_load_binary`, i.e. the header followed by the text and
+    /// the data, as output by `esp32ulp-elf-objcopy -O binary`.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::with_capacity(HEADER_SIZE as usize + self.bss_addr() as usize);
+
+        binary.extend_from_slice(&BINARY_MAGIC.to_le_bytes());
+        binary.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+        binary.extend_from_slice(&(self.text.len() as u16).to_le_bytes());
+        binary.extend_from_slice(&(self.data.len() as u16).to_le_bytes());
+        binary.extend_from_slice(&(self.bss_size as u16).to_le_bytes());
+        binary.extend_from_slice(&self.text);
+        binary.extend_from_slice(&self.data);
+
+        binary
+    }
+
+    pub fn write_binary(&self, output_file: impl AsRef<Path>) -> Result<()> {
+        let output_file = output_file.as_ref();
+
+        fs::write(output_file, self.to_binary())
+            .map_err(|e| anyhow!("Could not write {}: {e}", output_file.display()))
+    }
+
+    /// An ELF executable with the sections and the symbol table of the program, e.g.
+    /// for [`symgen`](crate::symgen).
+    pub fn to_elf(&self) -> Vec<u8> {
+        elf::write(self)
+    }
+
+    pub fn write_elf(&self, output_file: impl AsRef<Path>) -> Result<()> {
+        let output_file = output_file.as_ref();
+
+        fs::write(output_file, self.to_elf())
+            .map_err(|e| anyhow!("Could not write {}: {e}", output_file.display()))
+    }
+
+    fn data_addr(&self) -> u32 {
+        self.text.len() as u32
+    }
+
+    fn bss_addr(&self) -> u32 {
+        self.data_addr() + self.data.len() as u32
+    }
+}
+
+/// Assembles ULP sources and links them into a [`Program`].
+#[derive(Debug, Default)]
+pub struct Assembler {
+    objects: Vec<Object>,
+    reserved_size: Option<u32>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Reject programs larger than `size` bytes, i.e. the `CONFIG_ULP_COPROC_RESERVE_MEM`
+    /// of ESP-IDF, like the linker script of ESP-IDF does.
+    ///
+    /// Otherwise only programs whose sizes do not fit into the binary header are
+    /// rejected.
+    #[must_use]
+    pub fn reserved_size(mut self, size: u32) -> Self {
+        self.reserved_size = Some(size);
+        self
+    }
+
+    /// Assemble the preprocessed source file `path`.
+    pub fn add_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+
+        let source = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+
+        self.add_source(path.display().to_string(), &source)
+    }
+
+    /// Assemble the preprocessed `source`, with `name` used in error messages.
+    pub fn add_source(&mut self, name: impl Into<String>, source: &str) -> Result<()> {
+        self.objects.push(Object::parse(name.into(), source)?);
+
+        Ok(())
+    }
+
+    /// Link the sources assembled so far into a program, laid out like by the linker
+    /// script of ESP-IDF: the text of all sources, followed by their data and bss.
+    pub fn link(&self) -> Result<Program> {
+        let mut bases = vec![[0; 3]; self.objects.len()];
+        let mut sizes = [0; 3];
+
+        for section in [Section::Text, Section::Data, Section::Bss] {
+            let index = section.index();
+            let start = sizes.iter().sum::<u32>();
+
+            for (object, bases) in self.objects.iter().zip(&mut bases) {
+                bases[index] = start + sizes[index];
+                sizes[index] += align(object.sizes[index], 4);
+            }
+        }
+
+        let size = sizes.iter().sum::<u32>();
+        let max_size = self
+            .reserved_size
+            .map_or(u16::MAX as u32, |reserved| reserved.min(u16::MAX as u32));
+
+        ensure!(
+            size <= max_size,
+            "The ULP program is too large ({size} bytes, the maximum is {max_size} bytes)"
+        );
+
+        let mut globals = HashMap::new();
+
+        for (index, object) in self.objects.iter().enumerate() {
+            for name in &object.globals {
+                if object.definitions.contains_key(name) {
+                    if let Some(other) = globals.insert(name.as_str(), index) {
+                        bail!(
+                            "Symbol `{name}` is defined in both {} and {}",
+                            self.objects[other].name,
+                            object.name
+                        );
+                    }
+                }
+            }
+        }
+
+        let linker = Linker {
+            objects: &self.objects,
+            bases: &bases,
+            globals: &globals,
+        };
+
+        let mut sections = [
+            vec![0; sizes[Section::Text.index()] as usize],
+            vec![0; sizes[Section::Data.index()] as usize],
+        ];
+
+        for (index, object) in self.objects.iter().enumerate() {
+            for statement in &object.statements {
+                linker
+                    .emit(index, statement, &mut sections)
+                    .map_err(|e| anyhow!("{}:{}: {e}", object.name, statement.line))?;
+            }
+        }
+
+        let mut symbols = Vec::new();
+
+        for (index, object) in self.objects.iter().enumerate() {
+            for name in &object.order {
+                let value = linker.resolve(index, name, 0)?;
+
+                let section = match object.definitions[name] {
+                    Definition::Label { section, .. } => Some(section),
+                    Definition::Set { .. } => None,
+                };
+
+                symbols.push(Symbol {
+                    name: name.clone(),
+                    section,
+                    value: value.value as u32,
+                    global: object.globals.contains(name),
+                });
+            }
+        }
+
+        let [text, data] = sections;
+
+        Ok(Program {
+            text,
+            data,
+            bss_size: sizes[Section::Bss.index()],
+            symbols,
+        })
+    }
+}
+
+/// An assembled source.
+#[derive(Debug)]
+struct Object {
+    name: String,
+    sizes: [u32; 3],
+    statements: Vec<Statement>,
+    definitions: HashMap<String, Definition>,
+    /// The names of the definitions, in the order of the source.
+    order: Vec<String>,
+    globals: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Statement {
+    line: usize,
+    section: Section,
+    offset: u32,
+    kind: StatementKind,
+}
+
+#[derive(Debug)]
+enum StatementKind {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    Data {
+        width: usize,
+        values: Vec<Expr>,
+    },
+    Fill {
+        len: u32,
+        value: u8,
+    },
+}
+
+#[derive(Debug)]
+enum Definition {
+    Label {
+        section: Section,
+        offset: u32,
+    },
+    Set {
+        expr: Expr,
+        section: Section,
+        offset: u32,
+    },
+}
+
+#[derive(Debug)]
+enum Operand {
+    Reg(u32),
+    Expr(Expr),
+}
+
+impl Object {
+    fn parse(name: String, source: &str) -> Result<Self> {
+        let mut object = Self {
+            name,
+            sizes: [0; 3],
+            statements: Vec::new(),
+            definitions: HashMap::new(),
+            order: Vec::new(),
+            globals: Vec::new(),
+        };
+
+        let mut section = Section::Text;
+
+        for (index, line) in source.lines().enumerate() {
+            object
+                .parse_line(index + 1, line, &mut section)
+                .map_err(|e| anyhow!("{}:{}: {e}", object.name, index + 1))?;
+        }
+
+        Ok(object)
+    }
+
+    fn parse_line(&mut self, line: usize, text: &str, section: &mut Section) -> Result<()> {
+        let text = text.split("//").next().unwrap_or_default().trim();
+
+        // Line markers of the preprocessor
+        if text.starts_with('#') {
+            return Ok(());
+        }
+
+        let mut text = text;
+
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+
+            if !is_identifier(label) {
+                break;
+            }
+
+            self.define(
+                label,
+                Definition::Label {
+                    section: *section,
+                    offset: self.sizes[section.index()],
+                },
+            )?;
+
+            text = rest.trim_start();
+        }
+
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let (word, args) = text
+            .split_once(char::is_whitespace)
+            .map_or((text, ""), |(word, args)| (word, args.trim()));
+        let word = word.to_ascii_lowercase();
+
+        if word.starts_with('.') {
+            self.parse_directive(line, &word, args, section)
+        } else {
+            let operands = split_operands(args)
+                .into_iter()
+                .map(Operand::parse)
+                .collect::<Result<Vec<_>>>()?;
+
+            let size = instruction_size(&word, &operands)?;
+
+            self.push(
+                line,
+                *section,
+                size,
+                StatementKind::Instruction {
+                    mnemonic: word,
+                    operands,
+                },
+            )
+        }
+    }
+
+    fn parse_directive(
+        &mut self,
+        line: usize,
+        directive: &str,
+        args: &str,
+        section: &mut Section,
+    ) -> Result<()> {
+        let mut args = split_operands(args);
+
+        match directive {
+            ".text" => *section = Section::Text,
+            ".data" => *section = Section::Data,
+            ".bss" => *section = Section::Bss,
+            ".section" => {
+                let name = args.first().copied().unwrap_or_default();
+
+                *section = Section::from_input_section(name)
+                    .ok_or_else(|| anyhow!("Unsupported section `{name}`"))?;
+            }
+            ".global" | ".globl" => {
+                for name in args {
+                    ensure!(is_identifier(name), "Invalid symbol name `{name}`");
+
+                    self.globals.push(name.to_owned());
+                }
+            }
+            ".set" | ".equ" => {
+                ensure!(args.len() == 2, "Expected a symbol name and a value");
+                ensure!(is_identifier(args[0]), "Invalid symbol name `{}`", args[0]);
+
+                self.define(
+                    args[0],
+                    Definition::Set {
+                        expr: Expr::parse(args[1])?,
+                        section: *section,
+                        offset: self.sizes[section.index()],
+                    },
+                )?;
+            }
+            ".long" | ".int" | ".word" | ".short" | ".hword" | ".byte" => {
+                let width = match directive {
+                    ".short" | ".hword" => 2,
+                    ".byte" => 1,
+                    _ => 4,
+                };
+
+                let values = args
+                    .into_iter()
+                    .map(Expr::parse)
+                    .collect::<Result<Vec<_>>>()?;
+
+                let len = (width * values.len()) as u32;
+
+                if *section == Section::Bss {
+                    ensure!(
+                        values
+                            .iter()
+                            .all(|value| self.constant_expr(value) == Some(0)),
+                        "Only zeros are allowed in the .bss section"
+                    );
+
+                    self.push(line, *section, len, StatementKind::Fill { len, value: 0 })?;
+                } else {
+                    self.push(line, *section, len, StatementKind::Data { width, values })?;
+                }
+            }
+            ".skip" | ".space" | ".balign" | ".align" | ".p2align" => {
+                ensure!(
+                    !args.is_empty() && args.len() <= 2,
+                    "Expected a size and an optional fill value"
+                );
+
+                let value = args
+                    .drain(1..)
+                    .next()
+                    .map_or(Ok(0), |value| self.constant(value))?;
+                let size = self.constant(args[0])?;
+
+                let offset = self.sizes[section.index()];
+
+                let len = match directive {
+                    ".skip" | ".space" => size,
+                    ".p2align" => {
+                        let alignment = 1_u32
+                            .checked_shl(size)
+                            .ok_or_else(|| anyhow!("Invalid alignment 2^{size}"))?;
+
+                        align(offset, alignment) - offset
+                    }
+                    _ => {
+                        ensure!(
+                            size.is_power_of_two(),
+                            "Alignment {size} is not a power of two"
+                        );
+
+                        align(offset, size) - offset
+                    }
+                };
+
+                self.push(
+                    line,
+                    *section,
+                    len,
+                    StatementKind::Fill {
+                        len,
+                        value: value as u8,
+                    },
+                )?;
+            }
+            ".type" | ".size" | ".file" | ".ident" | ".local" | ".end" => (),
+            _ => bail!("Unsupported directive `{directive}`"),
+        }
+
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, definition: Definition) -> Result<()> {
+        ensure!(
+            !self.definitions.contains_key(name),
+            "Symbol `{name}` is already defined"
+        );
+
+        self.definitions.insert(name.to_owned(), definition);
+        self.order.push(name.to_owned());
+
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        line: usize,
+        section: Section,
+        size: u32,
+        kind: StatementKind,
+    ) -> Result<()> {
+        let offset = self.sizes[section.index()];
+
+        if section == Section::Bss {
+            ensure!(
+                matches!(kind, StatementKind::Fill { value: 0, .. }),
+                "Only zeros are allowed in the .bss section"
+            );
+        } else {
+            self.statements.push(Statement {
+                line,
+                section,
+                offset,
+                kind,
+            });
+        }
+
+        self.sizes[section.index()] = offset
+            .checked_add(size)
+            .ok_or_else(|| anyhow!("Section {} is too large", section.name()))?;
+
+        Ok(())
+    }
+
+    /// Evaluate the constant `expr`, which may only refer to symbols defined with `.set`
+    /// before.
+    fn constant(&self, expr: &str) -> Result<u32> {
+        let value = self
+            .constant_expr(&Expr::parse(expr)?)
+            .ok_or_else(|| anyhow!("`{expr}` is not a constant"))?;
+
+        u32::try_from(value).map_err(|_| anyhow!("Invalid size {value}"))
+    }
+
+    fn constant_expr(&self, expr: &Expr) -> Option<i64> {
+        expr.eval(&mut |name| match self.definitions.get(name) {
+            Some(Definition::Set { expr, .. }) => Ok(Value::absolute(
+                self.constant_expr(expr)
+                    .ok_or_else(|| anyhow!("Symbol `{name}` is not a constant"))?,
+            )),
+            _ => bail!("Symbol `{name}` is not a constant"),
+        })
+        .and_then(|value| value.constant())
+        .ok()
+    }
+}
+
+impl Operand {
+    fn parse(operand: &str) -> Result<Self> {
+        let reg = operand
+            .strip_prefix(|c| c == 'r' || c == 'R')
+            .and_then(|index| match index {
+                "0" => Some(0),
+                "1" => Some(1),
+                "2" => Some(2),
+                "3" => Some(3),
+                _ => None,
+            });
+
+        match reg {
+            Some(reg) => Ok(Self::Reg(reg)),
+            None => Ok(Self::Expr(Expr::parse(operand)?)),
+        }
+    }
+}
+
+/// The size of an instruction in bytes, after checking its operands.
+fn instruction_size(mnemonic: &str, operands: &[Operand]) -> Result<u32> {
+    let counts: &[usize] = match mnemonic {
+        "nop" | "halt" | "wake" | "stage_rst" => &[0],
+        "wait" | "sleep" | "stage_inc" | "stage_dec" => &[1],
+        "move" | "tsens" => &[2],
+        "jump" => &[1, 2],
+        "add" | "sub" | "and" | "or" | "lsh" | "rsh" | "st" | "ld" | "jumpr" | "jumps" | "adc"
+        | "reg_rd" => &[3],
+        "reg_wr" | "i2c_rd" => &[4],
+        "i2c_wr" => &[5],
+        _ => bail!("Unknown instruction `{mnemonic}`"),
+    };
+
+    ensure!(
+        counts.contains(&operands.len()),
+        "Wrong number of operands for `{mnemonic}`"
+    );
+
+    let emulated = match mnemonic {
+        "jumpr" => condition(&operands[2])? == "EQ",
+        "jumps" => matches!(condition(&operands[2])?.as_str(), "EQ" | "GT"),
+        _ => false,
+    };
+
+    Ok(if emulated { 8 } else { 4 })
+}
+
+fn condition(operand: &Operand) -> Result<String> {
+    match operand {
+        Operand::Expr(Expr::Symbol(name)) => Ok(name.to_ascii_uppercase()),
+        _ => bail!("Expected a condition"),
+    }
+}
+
+/// Resolves the symbols of the objects, once their sections are laid out.
+struct Linker<'a> {
+    objects: &'a [Object],
+    /// The base addresses of the sections of each object.
+    bases: &'a [[u32; 3]],
+    globals: &'a HashMap<&'a str, usize>,
+}
+
+impl<'a> Linker<'a> {
+    fn resolve(&self, object: usize, name: &str, depth: usize) -> Result<Value> {
+        ensure!(depth < 64, "Symbol `{name}` is defined recursively");
+
+        match self.objects[object].definitions.get(name) {
+            Some(Definition::Label { section, offset }) => {
+                Ok(self.address(object, *section, *offset))
+            }
+            Some(Definition::Set {
+                expr,
+                section,
+                offset,
+            }) => self.eval(
+                object,
+                expr,
+                self.address(object, *section, *offset),
+                depth + 1,
+            ),
+            None => match self.globals.get(name) {
+                Some(&other) => self.resolve(other, name, depth + 1),
+                None => bail!("Undefined symbol `{name}`"),
+            },
+        }
+    }
+
+    fn eval(&self, object: usize, expr: &Expr, location: Value, depth: usize) -> Result<Value> {
+        expr.eval(&mut |name| {
+            if name == "." {
+                Ok(location)
+            } else {
+                self.resolve(object, name, depth)
+            }
+        })
+    }
+
+    fn address(&self, object: usize, section: Section, offset: u32) -> Value {
+        Value::address((self.bases[object][section.index()] + offset) as i64)
+    }
+
+    fn emit(
+        &self,
+        object: usize,
+        statement: &Statement,
+        sections: &mut [Vec<u8>; 2],
+    ) -> Result<()> {
+        let location = self.address(object, statement.section, statement.offset);
+        let eval = |expr: &Expr| self.eval(object, expr, location, 0);
+
+        let section = &mut sections[statement.section.index()];
+        let start = (self.bases[object][statement.section.index()]
+            - self.bases[0][statement.section.index()]
+            + statement.offset) as usize;
+
+        match &statement.kind {
+            StatementKind::Instruction { mnemonic, operands } => {
+                ensure!(
+                    location.value % 4 == 0,
+                    "Instruction at {:#x} is not aligned",
+                    location.value
+                );
+
+                let words = encode(mnemonic, operands, location.value, &eval)?;
+
+                for (index, word) in words.into_iter().enumerate() {
+                    section[start + index * 4..][..4].copy_from_slice(&word.to_le_bytes());
+                }
+            }
+            StatementKind::Data { width, values } => {
+                for (index, value) in values.iter().enumerate() {
+                    let value = eval(value)?.value;
+
+                    ensure!(
+                        *width == 4 || value >> (width * 8) == 0 || value >> (width * 8 - 1) == -1,
+                        "Value {value} does not fit into {width} bytes"
+                    );
+
+                    section[start + index * width..][..*width]
+                        .copy_from_slice(&value.to_le_bytes()[..*width]);
+                }
+            }
+            StatementKind::Fill { len, value } => {
+                section[start..][..*len as usize].fill(*value);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Encode an instruction at the byte address `pc` into one or two words.
+fn encode(
+    mnemonic: &str,
+    operands: &[Operand],
+    pc: i64,
+    eval: &dyn Fn(&Expr) -> Result<Value>,
+) -> Result<Vec<u32>> {
+    let reg = |index: usize| match operands[index] {
+        Operand::Reg(reg) => Ok(reg),
+        _ => bail!("Expected a register as operand {}", index + 1),
+    };
+
+    let value = |index: usize| match &operands[index] {
+        Operand::Expr(expr) => eval(expr),
+        _ => bail!("Expected a value as operand {}", index + 1),
+    };
+
+    // Labels evaluate to their address in words
+    let imm = |index: usize| -> Result<i64> {
+        let value = value(index)?;
+
+        if value.relocatable {
+            words(value.value)
+        } else {
+            Ok(value.value)
+        }
+    };
+
+    // Labels evaluate to their byte address and constants to a step in bytes
+    let target = |index: usize| -> Result<i64> {
+        let value = value(index)?;
+
+        Ok(if value.relocatable {
+            value.value
+        } else {
+            pc + value.value
+        })
+    };
+
+    let insn = |opcode: u32, sub_opcode: u32| opcode << 28 | sub_opcode << 25;
+
+    let alu = |sel: u32| -> Result<u32> {
+        let (rd, rs) = (reg(0)?, reg(1)?);
+
+        Ok(match operands[2] {
+            Operand::Reg(rt) => {
+                insn(OPCODE_ALU, SUB_OPCODE_ALU_REG) | sel << 21 | rt << 4 | rs << 2 | rd
+            }
+            Operand::Expr(_) => {
+                insn(OPCODE_ALU, SUB_OPCODE_ALU_IMM)
+                    | sel << 21
+                    | any_signed(imm(2)?, 16)? << 4
+                    | rs << 2
+                    | rd
+            }
+        })
+    };
+
+    let stage = |sel: u32, imm: u32| insn(OPCODE_ALU, SUB_OPCODE_ALU_CNT) | sel << 21 | imm << 4;
+
+    let jumpr = |target: i64, pc: i64, threshold: i64, cmp: u32| -> Result<u32> {
+        Ok(insn(OPCODE_BRANCH, SUB_OPCODE_B)
+            | branch_offset(target, pc)?
+            | cmp << 16
+            | any_signed(threshold, 16)?)
+    };
+
+    let jumps = |target: i64, pc: i64, threshold: i64, cmp: u32| -> Result<u32> {
+        Ok(insn(OPCODE_BRANCH, SUB_OPCODE_BS)
+            | branch_offset(target, pc)?
+            | cmp << 15
+            | unsigned(threshold, 8)?)
+    };
+
+    let word = match mnemonic {
+        "nop" => insn(OPCODE_DELAY, 0),
+        "wait" => insn(OPCODE_DELAY, 0) | unsigned(imm(0)?, 16)?,
+        "halt" => insn(OPCODE_HALT, 0),
+        "wake" => insn(OPCODE_END, SUB_OPCODE_WAKE) | 1,
+        "sleep" => insn(OPCODE_END, SUB_OPCODE_SLEEP) | unsigned(imm(0)?, 4)?,
+        "add" => alu(ALU_SEL_ADD)?,
+        "sub" => alu(ALU_SEL_SUB)?,
+        "and" => alu(ALU_SEL_AND)?,
+        "or" => alu(ALU_SEL_OR)?,
+        "lsh" => alu(ALU_SEL_LSH)?,
+        "rsh" => alu(ALU_SEL_RSH)?,
+        "move" => match operands[1] {
+            Operand::Reg(rs) => {
+                insn(OPCODE_ALU, SUB_OPCODE_ALU_REG) | ALU_SEL_MOV << 21 | rs << 2 | reg(0)?
+            }
+            Operand::Expr(_) => {
+                insn(OPCODE_ALU, SUB_OPCODE_ALU_IMM)
+                    | ALU_SEL_MOV << 21
+                    | any_signed(imm(1)?, 16)? << 4
+                    | reg(0)?
+            }
+        },
+        "stage_inc" => stage(ALU_SEL_SINC, unsigned(imm(0)?, 8)?),
+        "stage_dec" => stage(ALU_SEL_SDEC, unsigned(imm(0)?, 8)?),
+        "stage_rst" => stage(ALU_SEL_SRST, 0),
+        // The value register is encoded in the lower bits, unlike the destination of `LD`
+        "st" => {
+            insn(OPCODE_ST, SUB_OPCODE_ST)
+                | signed(words(value(2)?.value)?, 11)? << 10
+                | reg(1)? << 2
+                | reg(0)?
+        }
+        "ld" => {
+            insn(OPCODE_LD, 0) | signed(words(value(2)?.value)?, 11)? << 10 | reg(1)? << 2 | reg(0)?
+        }
+        "jump" => {
+            let jump_type = if operands.len() == 2 {
+                match condition(&operands[1])?.as_str() {
+                    "EQ" => BX_JUMP_TYPE_ZERO,
+                    "OV" => BX_JUMP_TYPE_OVF,
+                    cond => bail!("Unknown condition `{cond}`"),
+                }
+            } else {
+                BX_JUMP_TYPE_DIRECT
+            };
+
+            let word = insn(OPCODE_BRANCH, SUB_OPCODE_BX) | jump_type << 22;
+
+            match operands[0] {
+                Operand::Reg(rd) => word | 1 << 21 | rd,
+                Operand::Expr(_) => word | unsigned(words(value(0)?.value)?, 11)? << 2,
+            }
+        }
+        "jumpr" => {
+            let (target, threshold) = (target(0)?, imm(1)?);
+
+            match condition(&operands[2])?.as_str() {
+                "LT" => jumpr(target, pc, threshold, B_CMP_LT)?,
+                "GE" => jumpr(target, pc, threshold, B_CMP_GE)?,
+                "LE" => jumpr(target, pc, threshold + 1, B_CMP_LT)?,
+                "GT" => jumpr(target, pc, threshold + 1, B_CMP_GE)?,
+                "EQ" => {
+                    return Ok(vec![
+                        jumpr(pc + 8, pc, threshold + 1, B_CMP_GE)?,
+                        jumpr(target, pc + 4, threshold, B_CMP_GE)?,
+                    ])
+                }
+                cond => bail!("Unknown condition `{cond}`"),
+            }
+        }
+        "jumps" => {
+            let (target, threshold) = (target(0)?, imm(1)?);
+
+            match condition(&operands[2])?.as_str() {
+                "LT" => jumps(target, pc, threshold, BS_CMP_LT)?,
+                "GE" => jumps(target, pc, threshold, BS_CMP_GE)?,
+                "LE" => jumps(target, pc, threshold, BS_CMP_LE)?,
+                "EQ" => {
+                    return Ok(vec![
+                        jumps(pc + 8, pc, threshold, BS_CMP_LT)?,
+                        jumps(target, pc + 4, threshold, BS_CMP_LE)?,
+                    ])
+                }
+                "GT" => {
+                    return Ok(vec![
+                        jumps(pc + 8, pc, threshold, BS_CMP_LE)?,
+                        jumps(target, pc + 4, threshold, BS_CMP_GE)?,
+                    ])
+                }
+                cond => bail!("Unknown condition `{cond}`"),
+            }
+        }
+        "tsens" => insn(OPCODE_TSENS, 0) | unsigned(imm(1)?, 14)? << 2 | reg(0)?,
+        "adc" => {
+            insn(OPCODE_ADC, 0) | unsigned(imm(1)?, 1)? << 6 | unsigned(imm(2)?, 4)? << 2 | reg(0)?
+        }
+        "i2c_rd" | "i2c_wr" => {
+            let (data, rest) = if mnemonic == "i2c_wr" {
+                (unsigned(imm(1)?, 8)?, 2)
+            } else {
+                (0, 1)
+            };
+
+            insn(OPCODE_I2C, 0)
+                | ((mnemonic == "i2c_wr") as u32) << 27
+                | unsigned(imm(rest + 2)?, 4)? << 22
+                | unsigned(imm(rest)?, 3)? << 19
+                | unsigned(imm(rest + 1)?, 3)? << 16
+                | data << 8
+                | unsigned(imm(0)?, 8)?
+        }
+        "reg_rd" | "reg_wr" => {
+            let mut addr = value(0)?.value;
+
+            if addr > DR_REG_MAX_DIRECT {
+                ensure!(
+                    addr >= DR_REG_RTCCNTL_BASE && (addr - DR_REG_RTCCNTL_BASE) % 4 == 0,
+                    "Invalid register address {addr:#x}"
+                );
+
+                addr = (addr - DR_REG_RTCCNTL_BASE) / 4;
+            }
+
+            let (opcode, data) = if mnemonic == "reg_wr" {
+                (OPCODE_WR_REG, unsigned(imm(3)?, 8)?)
+            } else {
+                (OPCODE_RD_REG, 0)
+            };
+
+            insn(opcode, 0)
+                | unsigned(imm(1)?, 5)? << 23
+                | unsigned(imm(2)?, 5)? << 18
+                | data << 10
+                | unsigned(addr, 10)?
+        }
+        _ => bail!("Unknown instruction `{mnemonic}`"),
+    };
+
+    Ok(vec![word])
+}
+
+/// The relative offset and sign fields of the conditional branch at `pc` to `target`.
+fn branch_offset(target: i64, pc: i64) -> Result<u32> {
+    let offset = words(target - pc)?;
+
+    ensure!(
+        offset.abs() < 1 << 7,
+        "Branch target {target:#x} is out of range"
+    );
+
+    Ok(((offset < 0) as u32) << 24 | (offset.unsigned_abs() as u32) << 17)
+}
+
+/// Convert a byte address or offset to 32-bit words.
+fn words(bytes: i64) -> Result<i64> {
+    ensure!(
+        bytes % 4 == 0,
+        "Address {bytes:#x} is not aligned to 4 bytes"
+    );
+
+    Ok(bytes / 4)
+}
+
+fn unsigned(value: i64, bits: u32) -> Result<u32> {
+    ensure!(
+        (0..1 << bits).contains(&value),
+        "Value {value} does not fit into {bits} bits"
+    );
+
+    Ok(value as u32)
+}
+
+fn signed(value: i64, bits: u32) -> Result<u32> {
+    ensure!(
+        (-(1 << (bits - 1))..1 << (bits - 1)).contains(&value),
+        "Value {value} does not fit into {bits} signed bits"
+    );
+
+    Ok(value as u32 & ((1 << bits) - 1))
+}
+
+/// Like [`unsigned`], but also accepts negative values in two's complement.
+fn any_signed(value: i64, bits: u32) -> Result<u32> {
+    ensure!(
+        (-(1 << (bits - 1))..1 << bits).contains(&value),
+        "Value {value} does not fit into {bits} bits"
+    );
+
+    Ok(value as u32 & ((1 << bits) - 1))
+}
+
+fn align(offset: u32, alignment: u32) -> u32 {
+    (offset + alignment - 1) / alignment * alignment
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || "_.$".contains(c))
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.$".contains(c))
+}
+
+/// Split `args` at the commas outside of parentheses.
+fn split_operands(args: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    let mut depth = 0_usize;
+    let mut start = 0;
+
+    for (index, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                operands.push(args[start..index].trim());
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+
+    if !args[start..].trim().is_empty() || !operands.is_empty() {
+        operands.push(args[start..].trim());
+    }
+
+    operands
+}
+
+/// The value of an expression, which is either a constant or a byte address.
+#[derive(Copy, Clone, Debug)]
+struct Value {
+    value: i64,
+    relocatable: bool,
+}
+
+impl Value {
+    fn absolute(value: i64) -> Self {
+        Self {
+            value,
+            relocatable: false,
+        }
+    }
+
+    fn address(value: i64) -> Self {
+        Self {
+            value,
+            relocatable: true,
+        }
+    }
+
+    fn constant(self) -> Result<i64> {
+        ensure!(!self.relocatable, "Expected a constant, not an address");
+
+        Ok(self.value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// The binary operators and their precedence, as in C.
+const OPERATORS: &[(&str, u8)] = &[
+    ("|", 1),
+    ("^", 2),
+    ("&", 3),
+    ("<<", 4),
+    (">>", 4),
+    ("+", 5),
+    ("-", 5),
+    ("*", 6),
+    ("/", 6),
+    ("%", 6),
+];
+
+impl Expr {
+    fn parse(expr: &str) -> Result<Self> {
+        let mut parser = ExprParser {
+            rest: expr.trim_start(),
+        };
+
+        let parsed = parser.parse(0)?;
+
+        ensure!(parser.rest.is_empty(), "Invalid expression `{expr}`");
+
+        Ok(parsed)
+    }
+
+    /// Evaluate the expression, resolving symbols (including `.`, the location counter)
+    /// with `resolve`.
+    fn eval(&self, resolve: &mut dyn FnMut(&str) -> Result<Value>) -> Result<Value> {
+        match self {
+            Self::Number(value) => Ok(Value::absolute(*value)),
+            Self::Symbol(name) => resolve(name),
+            Self::Unary(op, operand) => {
+                let operand = operand.eval(resolve)?;
+
+                match op {
+                    '+' => Ok(operand),
+                    '-' => Ok(Value::absolute(-operand.constant()?)),
+                    _ => Ok(Value::absolute(!operand.constant()?)),
+                }
+            }
+            Self::Binary(op, left, right) => {
+                let (left, right) = (left.eval(resolve)?, right.eval(resolve)?);
+
+                let value = match *op {
+                    // The difference of two addresses is a constant
+                    "+" if !(left.relocatable && right.relocatable) => Value {
+                        value: left.value + right.value,
+                        relocatable: left.relocatable || right.relocatable,
+                    },
+                    "-" if !right.relocatable || left.relocatable => Value {
+                        value: left.value - right.value,
+                        relocatable: left.relocatable && !right.relocatable,
+                    },
+                    "+" | "-" => bail!("Invalid operation `{op}` on addresses"),
+                    op => {
+                        let (left, right) = (left.constant()?, right.constant()?);
+
+                        Value::absolute(match op {
+                            "|" => left | right,
+                            "^" => left ^ right,
+                            "&" => left & right,
+                            "<<" => left.checked_shl(right as u32).unwrap_or_default(),
+                            ">>" => left.checked_shr(right as u32).unwrap_or_default(),
+                            "*" => left.wrapping_mul(right),
+                            _ if right == 0 => bail!("Division by zero"),
+                            "/" => left / right,
+                            _ => left % right,
+                        })
+                    }
+                };
+
+                Ok(value)
+            }
+        }
+    }
+}
+
+struct ExprParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> ExprParser<'a> {
+    fn parse(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut left = self.parse_primary()?;
+
+        while let Some(&(op, precedence)) = OPERATORS
+            .iter()
+            .find(|(op, precedence)| *precedence >= min_precedence && self.rest.starts_with(op))
+        {
+            self.advance(op.len());
+
+            let right = self.parse(precedence + 1)?;
+
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let c = self
+            .rest
+            .chars()
+            .next()
+            .ok_or_else(|| anyhow!("Expected a value"))?;
+
+        match c {
+            '(' => {
+                self.advance(1);
+
+                let expr = self.parse(0)?;
+
+                ensure!(self.rest.starts_with(')'), "Expected `)`");
+                self.advance(1);
+
+                Ok(expr)
+            }
+            '+' | '-' | '~' => {
+                self.advance(1);
+
+                Ok(Expr::Unary(c, Box::new(self.parse_primary()?)))
+            }
+            '0'..='9' => {
+                let len = self
+                    .rest
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(self.rest.len());
+                let number = &self.rest[..len];
+
+                let parsed = if let Some(hex) = number
+                    .strip_prefix("0x")
+                    .or_else(|| number.strip_prefix("0X"))
+                {
+                    i64::from_str_radix(hex, 16)
+                } else if let Some(bin) = number
+                    .strip_prefix("0b")
+                    .or_else(|| number.strip_prefix("0B"))
+                {
+                    i64::from_str_radix(bin, 2)
+                } else if number.len() > 1 && number.starts_with('0') {
+                    i64::from_str_radix(&number[1..], 8)
+                } else {
+                    number.parse()
+                };
+
+                let parsed = parsed.map_err(|_| anyhow!("Invalid number `{number}`"))?;
+
+                self.advance(len);
+
+                Ok(Expr::Number(parsed))
+            }
+            c if c.is_ascii_alphabetic() || "_.$".contains(c) => {
+                let len = self
+                    .rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || "_.$".contains(c)))
+                    .unwrap_or(self.rest.len());
+                let name = self.rest[..len].to_owned();
+
+                self.advance(len);
+
+                Ok(Expr::Symbol(name))
+            }
+            c => bail!("Unexpected `{c}`"),
+        }
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.rest = self.rest[len..].trim_start();
+    }
+}
+
+/// A minimal writer of ELF executables.
+mod elf {
+    use super::{Program, Section};
+
+    const SHT_PROGBITS: u32 = 1;
+    const SHT_SYMTAB: u32 = 2;
+    const SHT_STRTAB: u32 = 3;
+    const SHT_NOBITS: u32 = 8;
+
+    const SHF_WRITE: u32 = 1;
+    const SHF_ALLOC: u32 = 2;
+    const SHF_EXECINSTR: u32 = 4;
+
+    const SHN_ABS: u16 = 0xfff1;
+
+    const EHDR_SIZE: usize = 52;
+    const SHDR_SIZE: usize = 40;
+    const SYM_SIZE: usize = 16;
+
+    struct SectionHeader {
+        name: u32,
+        typ: u32,
+        flags: u32,
+        addr: u32,
+        offset: u32,
+        size: u32,
+        link: u32,
+        info: u32,
+        align: u32,
+        entsize: u32,
+    }
+
+    pub fn write(program: &Program) -> Vec<u8> {
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYM_SIZE];
+
+        // Local symbols must precede the global ones
+        let mut symbols = program.symbols.iter().collect::<Vec<_>>();
+        symbols.sort_by_key(|symbol| symbol.global);
+
+        let first_global = symbols
+            .iter()
+            .position(|symbol| symbol.global)
+            .unwrap_or(symbols.len())
+            + 1;
+
+        for symbol in symbols {
+            push_u32(&mut symtab, strtab.len() as u32);
+            strtab.extend_from_slice(symbol.name.as_bytes());
+            strtab.push(0);
+
+            push_u32(&mut symtab, symbol.value);
+            push_u32(&mut symtab, 0);
+            symtab.push((symbol.global as u8) << 4);
+            symtab.push(0);
+            push_u16(
+                &mut symtab,
+                match symbol.section {
+                    Some(Section::Text) => 1,
+                    Some(Section::Data) => 2,
+                    Some(Section::Bss) => 3,
+                    None => SHN_ABS,
+                },
+            );
+        }
+
+        let mut shstrtab = vec![0];
+        let mut name = |name: &str| {
+            let offset = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+
+            offset
+        };
+
+        let mut headers = vec![SectionHeader {
+            name: 0,
+            typ: 0,
+            flags: 0,
+            addr: 0,
+            offset: 0,
+            size: 0,
+            link: 0,
+            info: 0,
+            align: 0,
+            entsize: 0,
+        }];
+
+        let mut contents = Vec::new();
+        let mut offset = EHDR_SIZE;
+
+        let mut section = |name: u32, typ, flags, addr, data: &[u8], entsize, link, info| {
+            let header = SectionHeader {
+                name,
+                typ,
+                flags,
+                addr,
+                offset: offset as u32,
+                size: data.len() as u32,
+                link,
+                info,
+                align: if entsize > 0 { 4 } else { 1 },
+                entsize,
+            };
+
+            contents.extend_from_slice(data);
+            offset += data.len();
+
+            // Keep the following sections aligned
+            let padding = (4 - offset % 4) % 4;
+            contents.resize(contents.len() + padding, 0);
+            offset += padding;
+
+            header
+        };
+
+        let text = SHF_ALLOC | SHF_EXECINSTR;
+        let data = SHF_ALLOC | SHF_WRITE;
+
+        headers.push(section(
+            name(".text"),
+            SHT_PROGBITS,
+            text,
+            0,
+            &program.text,
+            0,
+            0,
+            0,
+        ));
+        headers.push(section(
+            name(".data"),
+            SHT_PROGBITS,
+            data,
+            program.data_addr(),
+            &program.data,
+            0,
+            0,
+            0,
+        ));
+
+        let mut bss = section(
+            name(".bss"),
+            SHT_NOBITS,
+            data,
+            program.bss_addr(),
+            &[],
+            0,
+            0,
+            0,
+        );
+        bss.size = program.bss_size;
+        headers.push(bss);
+
+        headers.push(section(
+            name(".symtab"),
+            SHT_SYMTAB,
+            0,
+            0,
+            &symtab,
+            SYM_SIZE as u32,
+            5,
+            first_global as u32,
+        ));
+        headers.push(section(name(".strtab"), SHT_STRTAB, 0, 0, &strtab, 0, 0, 0));
+
+        let shstrtab_name = name(".shstrtab");
+        headers.push(section(shstrtab_name, SHT_STRTAB, 0, 0, &shstrtab, 0, 0, 0));
+
+        for header in &mut headers[1..4] {
+            header.align = 4;
+        }
+
+        let mut elf = Vec::with_capacity(offset + headers.len() * SHDR_SIZE);
+
+        elf.extend_from_slice(b"\x7fELF");
+        // 32 bit, little endian, version 1
+        elf.extend_from_slice(&[1, 1, 1]);
+        elf.resize(16, 0);
+        // Executable, no machine
+        push_u16(&mut elf, 2);
+        push_u16(&mut elf, 0);
+        push_u32(&mut elf, 1);
+        // Entry point and program headers
+        push_u32(&mut elf, 0);
+        push_u32(&mut elf, 0);
+        push_u32(&mut elf, offset as u32);
+        push_u32(&mut elf, 0);
+        push_u16(&mut elf, EHDR_SIZE as u16);
+        push_u16(&mut elf, 0);
+        push_u16(&mut elf, 0);
+        push_u16(&mut elf, SHDR_SIZE as u16);
+        push_u16(&mut elf, headers.len() as u16);
+        push_u16(&mut elf, headers.len() as u16 - 1);
+
+        elf.extend_from_slice(&contents);
+
+        for header in headers {
+            for field in [
+                header.name,
+                header.typ,
+                header.flags,
+                header.addr,
+                header.offset,
+                header.size,
+                header.link,
+                header.info,
+                header.align,
+                header.entsize,
+            ] {
+                push_u32(&mut elf, field);
+            }
+        }
+
+        elf
+    }
+
+    fn push_u16(buf: &mut Vec<u8>, value: u16) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, value: u32) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assemble() {
+        let source = r#"
+            .set TIMER, 2
+            .set SCALED, TIMER * 4 + 1
+
+            .bss
+            .global counter
+        counter: .long 0
+            .skip 4
+
+            .data
+            .global limit
+        limit: .long 1000, counter
+            .short -1
+            .balign 4
+
+            .text
+            .global entry
+        entry:
+            move r3, counter        // Address in words
+            ld r0, r3, 0
+            add r0, r0, 1
+            st r0, r3, 0
+            sub r1, r0, r2
+            stage_inc SCALED
+        loop: jumpr done, 5, EQ
+            jumps loop, 3, GT
+            jump loop, ov
+            jump r1
+            reg_wr 0x3ff48018, 31, 0, 0xff
+            reg_rd 12, 7, 4
+            i2c_wr 0x10, 0x55, 7, 0, 1
+            sleep TIMER
+        done:
+            wake
+            halt
+        "#;
+
+        let mut assembler = Assembler::new();
+        assembler.add_source("test.S", source).unwrap();
+
+        let program = assembler.link().unwrap();
+
+        let words = program
+            .text
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            words,
+            [
+                0x7280_0153, // move r3, 21
+                0xd000_000c, // ld r0, r3, 0
+                0x7200_0010, // add r0, r0, 1
+                0x6800_000c, // st r0, r3, 0
+                0x7020_0021, // sub r1, r0, r2
+                0x7400_0090, // stage_inc 9
+                0x8205_0006, // jumpr +2, 6, GE
+                0x8213_0005, // jumpr done (+9), 5, GE
+                0x8405_0003, // jumps +2, 3, LE
+                0x8506_8003, // jumps loop (-3), 3, GE
+                0x8080_0018, // jump 6, ov
+                0x8020_0001, // jump r1
+                0x1f83_fc06, // reg_wr 6, 31, 0, 0xff
+                0x2390_000c, // reg_rd 12, 7, 4
+                0x3878_5510, // i2c_wr 0x10, 0x55, 7, 0, 1
+                0x9200_0002, // sleep 2
+                0x9000_0001, // wake
+                0xb000_0000, // halt
+            ]
+        );
+
+        assert_eq!(
+            program.data,
+            [0xe8, 0x03, 0, 0, 84, 0, 0, 0, 0xff, 0xff, 0, 0]
+        );
+        assert_eq!(program.bss_size, 8);
+
+        let binary = program.to_binary();
+        assert_eq!(
+            binary[..12],
+            [0x75, 0x6c, 0x70, 0, 12, 0, 72, 0, 12, 0, 8, 0]
+        );
+        assert_eq!(binary.len(), 12 + 72 + 12);
+
+        let symbol = |name: &str| program.symbols.iter().find(|symbol| symbol.name == name);
+
+        assert_eq!(
+            symbol("counter"),
+            Some(&Symbol {
+                name: "counter".into(),
+                section: Some(Section::Bss),
+                value: 84,
+                global: true,
+            })
+        );
+        assert_eq!(symbol("limit").unwrap().value, 72);
+        assert_eq!(symbol("done").unwrap().value, 64);
+        assert!(!symbol("done").unwrap().global);
+        assert_eq!(symbol("SCALED").unwrap().value, 9);
+        assert_eq!(symbol("SCALED").unwrap().section, None);
+    }
+
+    /// One instruction of each class, with the words laid out like by the `I_*` macros
+    /// of the `ulp.h` of ESP-IDF.
+    #[test]
+    fn instruction_classes() {
+        let source = r#"
+            nop
+            wait 10
+            halt
+            wake
+            sleep 1
+            add r1, r2, r3
+            or r0, r1, 0x10
+            rsh r3, r3, 2
+            move r2, r1
+            move r0, 0x1234
+            stage_dec 3
+            stage_rst
+            st r1, r2, 8
+            ld r1, r2, -4
+            jump r2
+            jump 0x10
+            jump 0x10, eq
+            jumpr . + 8, 100, lt
+            jumps . - 4, 0x20, le
+            tsens r1, 1000
+            adc r2, 1, 5
+            i2c_rd 0x20, 7, 0, 1
+            reg_rd 0x3ff48400, 15, 0
+            reg_wr 0x3ff48400, 7, 0, 0x5a
+        "#;
+
+        let mut assembler = Assembler::new();
+        assembler.add_source("test.S", source).unwrap();
+
+        let words = assembler
+            .link()
+            .unwrap()
+            .text
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            words,
+            [
+                0x4000_0000, // I_DELAY(0)
+                0x4000_000a, // I_DELAY(10)
+                0xb000_0000, // I_HALT()
+                0x9000_0001, // I_WAKE()
+                0x9200_0001, // I_SLEEP_CYCLE_SEL(1)
+                0x7000_0039, // I_ADDR(R1, R2, R3)
+                0x7260_0104, // I_ORI(R0, R1, 0x10)
+                0x72c0_002f, // I_RSHI(R3, R3, 2)
+                0x7080_0006, // I_MOVR(R2, R1)
+                0x7281_2340, // I_MOVI(R0, 0x1234)
+                0x7420_0030, // I_STAGEDECI(3)
+                0x7440_0000, // I_STAGERSTI()
+                0x6800_0809, // I_ST(R1, R2, 2)
+                0xd01f_fc09, // I_LD(R1, R2, -1)
+                0x8020_0002, // I_BXR(R2)
+                0x8000_0010, // I_BXI(4)
+                0x8040_0010, // I_BXZI(4)
+                0x8204_0064, // I_BL(2, 100)
+                0x8503_0020, // I_JUMPS(-1, 0x20, JUMPS_LE)
+                0xa000_0fa1, // I_TSENS(R1, 1000)
+                0x5000_0056, // I_ADC(R2, 1, 5)
+                0x3078_0020, // I_I2C_READ(1, 0x20)
+                0x2780_0100, // I_RD_REG(0x3ff48400, 0, 15)
+                0x1381_6900, // I_WR_REG(0x3ff48400, 0, 7, 0x5a)
+            ]
+        );
+    }
+
+    #[test]
+    fn reserved_size() {
+        let link = |reserved_size: u32| {
+            let mut assembler = Assembler::new().reserved_size(reserved_size);
+            assembler
+                .add_source("test.S", "halt\n.data\n.long 1\n.bss\n.skip 8")
+                .unwrap();
+            assembler.link()
+        };
+
+        assert!(link(16).is_ok());
+        assert_eq!(
+            link(12).unwrap_err().to_string(),
+            "The ULP program is too large (16 bytes, the maximum is 12 bytes)"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let assemble = |source: &str| {
+            let mut assembler = Assembler::new();
+            assembler.add_source("test.S", source)?;
+            assembler.link()
+        };
+
+        assert!(assemble("move r4, 1").is_err());
+        assert!(assemble(".skip 0x10000").is_err());
+        assert!(assemble("jump nowhere").is_err());
+        assert!(assemble("jumpr far, 0, LT\n.skip 1024\nfar: halt").is_err());
+        assert_eq!(
+            assemble("halt\nfoo 1").unwrap_err().to_string(),
+            "test.S:2: Unknown instruction `foo`"
+        );
+    }
+}
@@ -0,0 +1,535 @@
+//! Build support for the RISC-V ULP coprocessor of the ESP32-S2 and ESP32-S3.
+//!
+//! Mirrors the ULP RISC-V CMake flow of ESP-IDF (5.0 or later): the C and assembly
+//! sources of the ULP program and the ULP core library of ESP-IDF are compiled with the
+//! riscv32 toolchain and linked with the `ulp_riscv.ld` script of ESP-IDF.
+//!
+//! The same flow builds the programs of the LP core, see [`super::lp_core`].
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::{fs, iter};
+
+use anyhow::{anyhow, bail, Result};
+
+use super::incremental::Tracker;
+use super::ulp_fsm::{dep_file, find_tool, resuffix, SYMGEN_STAMP};
+pub use super::ulp_fsm::{BuildResult, SystemIncludes};
+use crate::{build, cmd, path_buf, symgen};
+
+/// The default `-march` of the RISC-V ULP, for the toolchains of ESP-IDF 5.1 and later.
+///
+/// Older toolchains require `rv32imc`, see [`Builder::march`].
+pub const DEFAULT_MARCH: &str = "rv32imc_zicsr_zifencei";
+
+/// The address of the RTC slow memory, where the ULP program is loaded, as seen from
+/// the main CPU.
+pub const RTC_SLOW_MEM_ADDR: u64 = 0x5000_0000;
+
+/// A RISC-V co-processor supported by [`Builder`].
+#[derive(Clone, Debug)]
+pub(super) enum Core {
+    UlpRiscv,
+    /// The LP core of the given MCU.
+    LpCore(String),
+}
+
+impl Core {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::UlpRiscv => "ULP RISC-V",
+            Self::LpCore(_) => "LP core",
+        }
+    }
+
+    /// The directory of the co-processor's component in `components/ulp` of ESP-IDF.
+    fn component_dir(&self, esp_idf: &Path) -> PathBuf {
+        let dir = match self {
+            Self::UlpRiscv => "ulp_riscv",
+            Self::LpCore(_) => "lp_core",
+        };
+
+        path_buf![esp_idf, "components", "ulp", dir]
+    }
+
+    /// The directories of the co-processor library in the component directory.
+    fn library_dirs(&self) -> [&'static str; 2] {
+        match self {
+            Self::UlpRiscv => ["ulp_core", "shared"],
+            Self::LpCore(_) => ["lp_core", "shared"],
+        }
+    }
+
+    /// The linker script in `components/ulp/ld` of ESP-IDF and the first ESP-IDF
+    /// version providing it.
+    fn ld_script(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::UlpRiscv => ("ulp_riscv.ld", "5.0"),
+            Self::LpCore(_) => ("lp_core_riscv.ld", "5.2"),
+        }
+    }
+
+    /// The linker scripts with the addresses of the ROM functions and the peripherals
+    /// of the co-processor.
+    fn extra_ld_scripts(&self, esp_idf: &Path) -> Result<Vec<PathBuf>> {
+        let mcu = match self {
+            Self::UlpRiscv => return Ok(Vec::new()),
+            Self::LpCore(mcu) => mcu,
+        };
+
+        let mut scripts = Vec::new();
+
+        let rom_dir = path_buf![esp_idf, "components", "esp_rom", mcu, "ld"];
+        if rom_dir.exists() {
+            let prefix = format!("{mcu}lp.rom");
+
+            scripts.extend(
+                fs::read_dir(&rom_dir)
+                    .map_err(|e| anyhow!("Could not read {}: {e}", rom_dir.display()))?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| {
+                        path.file_name()
+                            .and_then(OsStr::to_str)
+                            .map_or(false, |name| {
+                                name.starts_with(&prefix) && name.ends_with(".ld")
+                            })
+                    }),
+            );
+        }
+
+        let peripherals = path_buf![
+            esp_idf,
+            "components",
+            "soc",
+            mcu,
+            "ld",
+            format!("{mcu}.peripherals.ld")
+        ];
+        if peripherals.exists() {
+            scripts.push(peripherals);
+        }
+
+        scripts.sort();
+
+        Ok(scripts)
+    }
+
+    fn default_march(&self) -> &'static str {
+        match self {
+            Self::UlpRiscv => DEFAULT_MARCH,
+            Self::LpCore(_) => super::lp_core::DEFAULT_MARCH,
+        }
+    }
+
+    /// The address of the co-processor's memory as seen from the main CPU, relative to
+    /// the addresses of the co-processor's ELF file.
+    fn symbols_addr(&self) -> u64 {
+        match self {
+            Self::UlpRiscv => RTC_SLOW_MEM_ADDR,
+            // The LP core sees the LP memory at the same addresses as the main CPU
+            Self::LpCore(_) => 0,
+        }
+    }
+}
+
+pub struct Builder {
+    core: Core,
+    esp_idf: PathBuf,
+    sys_includes: SystemIncludes,
+    add_includes: Vec<String>,
+    gcc: Option<String>,
+    env_path: Option<OsString>,
+    march: String,
+}
+
+impl Builder {
+    pub fn try_from_embuild_env(
+        library: impl AsRef<str>,
+        add_includes: impl Into<Vec<String>>,
+    ) -> Result<Self> {
+        let library = library.as_ref();
+
+        Ok(Self::new(
+            std::env::var(format!("DEP_{library}_EMBUILD_ESP_IDF_PATH"))?,
+            SystemIncludes::CInclArgs(build::CInclArgs::try_from_env(library)?),
+            add_includes,
+            None,
+            std::env::var_os("DEP_ESP_IDF_EMBUILD_ENV_PATH"),
+        ))
+    }
+
+    pub fn new(
+        esp_idf: impl Into<PathBuf>,
+        sys_includes: SystemIncludes,
+        add_includes: impl Into<Vec<String>>,
+        gcc: Option<String>,
+        env_path: Option<OsString>,
+    ) -> Self {
+        Self::new_for_core(
+            Core::UlpRiscv,
+            esp_idf,
+            sys_includes,
+            add_includes,
+            gcc,
+            env_path,
+        )
+    }
+
+    pub(super) fn new_for_core(
+        core: Core,
+        esp_idf: impl Into<PathBuf>,
+        sys_includes: SystemIncludes,
+        add_includes: impl Into<Vec<String>>,
+        gcc: Option<String>,
+        env_path: Option<OsString>,
+    ) -> Self {
+        Self {
+            march: core.default_march().to_owned(),
+            core,
+            esp_idf: esp_idf.into(),
+            sys_includes,
+            add_includes: add_includes.into(),
+            gcc,
+            env_path,
+        }
+    }
+
+    /// Set the `-march` of the compiler and the linker (defaults to [`DEFAULT_MARCH`]).
+    #[must_use]
+    pub fn march(mut self, march: impl Into<String>) -> Self {
+        self.march = march.into();
+        self
+    }
+
+    /// Build the ULP program from the C (`.c`) and assembly (`.S`, `.s`) files
+    /// `ulp_sources` into `out_dir`.
+    ///
+    /// Steps whose inputs did not change since the last build are skipped, and the
+    /// build script is rerun if any of the sources, the headers they include or the
+    /// linker scripts change.
+    ///
+    /// The generated symbols module has a pointer for each global variable of the
+    /// `.bss`, `.data` and `.rodata` sections, to its C type as read from the DWARF info.
+    /// Unlike with the FSM ULP (see [`super::ulp_fsm::Builder::build`]), functions are not
+    /// exported, as the program is always started at its reset vector.
+    pub fn build<'a, I>(&self, ulp_sources: I, out_dir: impl AsRef<Path>) -> Result<BuildResult>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        let out_dir = out_dir.as_ref();
+
+        let include_args = self.include_args();
+
+        let mut tracker = Tracker::default();
+
+        let mut objects = self.compile(
+            ulp_sources,
+            &include_args,
+            &path_buf![out_dir, "obj"],
+            &mut tracker,
+        )?;
+
+        for dir in self.core.library_dirs() {
+            let sources = self.library_sources(dir)?;
+
+            objects.extend(self.compile(
+                sources.iter().map(PathBuf::as_path),
+                &include_args,
+                &path_buf![out_dir, "lib", dir],
+                &mut tracker,
+            )?);
+        }
+
+        let (ld_script, idf_version) = self.core.ld_script();
+        let ulp_ld_script = path_buf![&self.esp_idf, "components", "ulp", "ld", ld_script];
+        if !ulp_ld_script.exists() {
+            bail!(
+                "Cannot find the {} LD script in ESP-IDF (ESP-IDF {idf_version} or later is required)",
+                self.core.name()
+            );
+        }
+
+        let ulp_ld_out_script = path_buf![out_dir, "ulp.ld"];
+
+        self.preprocess_ld_script(
+            &ulp_ld_script,
+            &include_args,
+            &ulp_ld_out_script,
+            &mut tracker,
+        )?;
+
+        let ulp_elf = path_buf![out_dir, "ulp"];
+
+        self.link(
+            &objects,
+            iter::once(ulp_ld_out_script).chain(self.core.extra_ld_scripts(&self.esp_idf)?),
+            &path_buf![out_dir, "ulp.map"],
+            &ulp_elf,
+            &mut tracker,
+        )?;
+
+        let ulp_bin = path_buf![out_dir, "ulp.bin"];
+
+        self.bin(&ulp_elf, &ulp_bin, &mut tracker)?;
+
+        let ulp_sym_rs = path_buf![out_dir, "ulp.rs"];
+
+        self.symbolize(&ulp_elf, &ulp_sym_rs, &mut tracker)?;
+
+        tracker.track();
+
+        Ok(BuildResult {
+            bin_file: ulp_bin,
+            elf_file: ulp_elf,
+            sym_rs_file: ulp_sym_rs,
+        })
+    }
+
+    fn compile<'a, I>(
+        &self,
+        sources: I,
+        include_args: &[impl AsRef<OsStr>],
+        out_dir: &Path,
+        tracker: &mut Tracker,
+    ) -> Result<Vec<PathBuf>>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        fs::create_dir_all(out_dir)?;
+
+        let mut objects = Vec::new();
+
+        for source in sources {
+            let object = resuffix(source, out_dir, "o")?;
+            let dep_file = dep_file(&object);
+
+            let lang_args: &[&str] = match source.extension().and_then(OsStr::to_str) {
+                Some("c") => &["-Os", "-mdiv", "-fdata-sections", "-ffunction-sections"],
+                Some("S" | "s") => &["-x", "assembler-with-cpp"],
+                _ => bail!("Unsupported ULP source file {}", source.display()),
+            };
+
+            tracker.run(
+                cmd![
+                    self.tool(self.gcc())?,
+                    format!("-march={}", self.march),
+                    "-g",
+                    "-DIS_ULP_COCPU",
+                    "-MD",
+                    "-MF",
+                    &dep_file,
+                    @lang_args,
+                    @include_args,
+                    "-c",
+                    "-o",
+                    &object,
+                    source
+                ],
+                &object,
+                &[source],
+                Some(&dep_file),
+            )?;
+
+            objects.push(object);
+        }
+
+        Ok(objects)
+    }
+
+    fn preprocess_ld_script(
+        &self,
+        source: &Path,
+        include_args: &[impl AsRef<OsStr>],
+        out_file: &Path,
+        tracker: &mut Tracker,
+    ) -> Result<()> {
+        let dep_file = dep_file(out_file);
+
+        tracker.run(
+            cmd![
+                self.tool(self.gcc())?,
+                "-E",
+                "-P",
+                "-xc",
+                "-D__ASSEMBLER__",
+                "-MD",
+                "-MF",
+                &dep_file,
+                @include_args,
+                "-o",
+                out_file,
+                source
+            ],
+            out_file,
+            &[source],
+            Some(&dep_file),
+        )
+    }
+
+    fn link(
+        &self,
+        objects: &[PathBuf],
+        linker_scripts: impl IntoIterator<Item = PathBuf>,
+        map_file: &Path,
+        out_file: &Path,
+        tracker: &mut Tracker,
+    ) -> Result<()> {
+        let linker_scripts = linker_scripts.into_iter().collect::<Vec<_>>();
+        let linker_script_args = linker_scripts
+            .iter()
+            .map(|script| format!("-T{}", script.display()))
+            .collect::<Vec<_>>();
+
+        let inputs = objects
+            .iter()
+            .chain(&linker_scripts)
+            .map(PathBuf::as_path)
+            .collect::<Vec<_>>();
+
+        let command = cmd![
+            self.tool(self.gcc())?,
+            format!("-march={}", self.march),
+            "-nostartfiles",
+            "-Wl,--gc-sections",
+            format!("-Wl,-Map={}", map_file.display()),
+            @linker_script_args,
+            @objects,
+            "-o",
+            out_file
+        ];
+
+        tracker.run(command, out_file, &inputs, None)
+    }
+
+    fn bin(&self, ulp_elf: &Path, out_file: &Path, tracker: &mut Tracker) -> Result<()> {
+        let gcc = self.gcc();
+        let objcopy = format!("{}objcopy", gcc.strip_suffix("gcc").unwrap_or(gcc));
+
+        tracker.run(
+            cmd![self.tool(&objcopy)?, ulp_elf, "-O", "binary", out_file],
+            out_file,
+            &[ulp_elf],
+            None,
+        )
+    }
+
+    fn symbolize(&self, ulp_elf: &Path, out_file: &Path, tracker: &mut Tracker) -> Result<()> {
+        tracker.step(&[out_file], &[ulp_elf], None, SYMGEN_STAMP, || {
+            symgen::Symgen::new_with_pointer_gen(ulp_elf, self.core.symbols_addr(), |symbol| {
+                symbol.sections(&[
+                    symgen::Section::data(".bss"),
+                    symgen::Section::data(".data"),
+                    symgen::Section::code(".rodata"),
+                ])
+            })
+            .dwarf_types(true)
+            .run_for_file(out_file)
+        })
+    }
+
+    fn include_args(&self) -> Vec<String> {
+        let mut args = self.sys_includes.args(&self.esp_idf, &self.add_includes);
+
+        let component_dir = self.core.component_dir(&self.esp_idf);
+
+        for dir in self.core.library_dirs() {
+            let dir = path_buf![&component_dir, dir, "include"];
+
+            if dir.exists() {
+                args.push(format!("-I{}", dir.display()));
+            }
+        }
+
+        args
+    }
+
+    /// The C and assembly sources of the co-processor library of ESP-IDF in `dir`,
+    /// sorted by name.
+    fn library_sources(&self, dir: &str) -> Result<Vec<PathBuf>> {
+        let dir = path_buf![self.core.component_dir(&self.esp_idf), dir];
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut sources = fs::read_dir(&dir)
+            .map_err(|e| anyhow!("Could not read {}: {e}", dir.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file()
+                    && matches!(
+                        path.extension().and_then(OsStr::to_str),
+                        Some("c" | "S" | "s")
+                    )
+            })
+            .collect::<Vec<_>>();
+
+        sources.sort();
+
+        Ok(sources)
+    }
+
+    fn gcc(&self) -> &str {
+        self.gcc.as_deref().unwrap_or("riscv32-esp-elf-gcc")
+    }
+
+    fn tool(&self, tool: &str) -> Result<PathBuf> {
+        find_tool(tool, self.env_path.clone())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::espidf::testutils::{fake_toolchain, log, touch};
+
+    #[test]
+    fn ulp_riscv() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        touch(
+            dir,
+            &[
+                "src/main.c",
+                "idf/components/ulp/ld/ulp_riscv.ld",
+                "idf/components/ulp/ulp_riscv/ulp_core/start.S",
+                "idf/components/ulp/ulp_riscv/ulp_core/ulp_riscv_utils.c",
+                "idf/components/ulp/ulp_riscv/ulp_core/include/ulp_riscv.h",
+            ],
+        );
+
+        let builder = Builder::new(
+            dir.join("idf"),
+            SystemIncludes::MCU("esp32s3".to_owned()),
+            Vec::new(),
+            None,
+            Some(fake_toolchain(dir).into()),
+        );
+        let result = builder
+            .build([dir.join("src/main.c").as_path()], dir.join("out"))
+            .unwrap();
+
+        let includes =
+            "-I idf/components/soc/esp32s3 -Iidf/components/ulp/ulp_riscv/ulp_core/include";
+        let compile = "riscv32-esp-elf-gcc -march=rv32imc_zicsr_zifencei -g -DIS_ULP_COCPU -MD";
+        let c = "-Os -mdiv -fdata-sections -ffunction-sections";
+
+        assert_eq!(
+            log(dir),
+            [
+                format!("{compile} -MF out/obj/main.o.d {c} {includes} -c -o out/obj/main.o src/main.c"),
+                format!("{compile} -MF out/lib/ulp_core/start.o.d -x assembler-with-cpp {includes} -c -o out/lib/ulp_core/start.o idf/components/ulp/ulp_riscv/ulp_core/start.S"),
+                format!("{compile} -MF out/lib/ulp_core/ulp_riscv_utils.o.d {c} {includes} -c -o out/lib/ulp_core/ulp_riscv_utils.o idf/components/ulp/ulp_riscv/ulp_core/ulp_riscv_utils.c"),
+                format!("riscv32-esp-elf-gcc -E -P -xc -D__ASSEMBLER__ -MD -MF out/ulp.ld.d {includes} -o out/ulp.ld idf/components/ulp/ld/ulp_riscv.ld"),
+                "riscv32-esp-elf-gcc -march=rv32imc_zicsr_zifencei -nostartfiles -Wl,--gc-sections -Wl,-Map=out/ulp.map -Tout/ulp.ld out/obj/main.o out/lib/ulp_core/start.o out/lib/ulp_core/ulp_riscv_utils.o -o out/ulp".to_owned(),
+                "riscv32-esp-elf-objcopy out/ulp -O binary out/ulp.bin".to_owned(),
+            ]
+        );
+
+        assert_eq!(result.bin_file, dir.join("out/ulp.bin"));
+        // The symbols are relative to the RTC slow memory
+        assert!(fs::read_to_string(result.sym_rs_file)
+            .unwrap()
+            .contains("pub const counts: *mut [i32; 4] = 0x50402010 as *mut [i32; 4];"));
+    }
+}
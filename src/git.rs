@@ -363,7 +363,7 @@ impl Ref {
     ///
     /// The ref string can have the following format:
     /// - `commit:<hash>`: Uses the commit `<hash>` of the repository. Note that
-    ///                    this will clone the whole repository not just one commit.
+    ///   this will clone the whole repository not just one commit.
     /// - `tag:<tag>`: Uses the tag `<tag>` of the repository.
     /// - `branch:<branch>`: Uses the branch `<branch>` of the repository.
     /// - `v<major>.<minor>` or `<major>.<minor>`: Uses the tag `v<major>.<minor>` of the repository.
@@ -419,6 +419,7 @@ pub struct CloneOptions {
     /// - `git reset HEAD <reset mode>` (where `reset mode` is the value of
     ///   [`branch_update_action`](Self::branch_update_action))
     /// - `git pull --ff-only`
+    ///
     /// If these operations fail an error is returned from [`Repository::clone_ext`].
     pub force_ref: Option<Ref>,
     /// The mode that is passed to `git reset` when the branch is updated.
@@ -451,6 +452,7 @@ impl CloneOptions {
     /// - `git reset HEAD <reset mode>` (where `reset mode` is the value of
     ///   [`branch_update_action`](Self::branch_update_action))
     /// - `git pull --ff-only`
+    ///
     /// If these operations fail an error is returned from [`Repository::clone_ext`].
     pub fn force_ref(mut self, force_ref: Ref) -> Self {
         self.force_ref = Some(force_ref);
@@ -499,9 +501,9 @@ pub mod sdk {
     ///
     /// Two variations exist:
     /// - Managed
-    ///     The SDK source is installed automatically.
+    ///   The SDK source is installed automatically.
     /// - Custom
-    ///     A user-provided local clone the SDK repository.
+    ///   A user-provided local clone the SDK repository.
     ///
     /// In both cases the [`Installer`] will install all required tools.
     ///
@@ -1,12 +1,27 @@
 //! A quick and dirty parser for the .config files generated by kconfig systems (e.g. used
-//! in the esp-idf).
+//! in the esp-idf), as well as a parser for the Kconfig files themselves (see [`Kconfig`])
+//! to validate configurations against and a [`Config`] type to layer them and to write
+//! them as `.config` files, C headers and CMake include files.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::{self, BufRead, Read};
+use std::io::{self, BufRead, Read, Write};
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+
+mod confgen;
+pub mod config;
+pub mod expr;
+pub mod tree;
+mod validate;
+
+pub use config::Config;
+pub use tree::Kconfig;
+pub use validate::{Diagnostic, Rename};
+
+/// The prefix of the names of the options in .config files.
+pub const CONFIG_PREFIX: &str = "CONFIG_";
 
 /// A tristate kconfig configuration item.
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
@@ -25,12 +40,16 @@ pub enum Tristate {
 }
 
 /// Value of a kconfig configuration item.
-#[derive(Clone, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Value {
     /// A [`Tristate`] value.
     Tristate(Tristate),
     /// A [`String`] value.
     String(String),
+    /// An `int` value.
+    Int(i64),
+    /// A `hex` value.
+    Hex(u64),
 }
 
 impl Value {
@@ -38,15 +57,19 @@ impl Value {
     ///
     /// Only the following cfgs will be generated:
     /// - For a [`Tristate::True`], `<prefix>_<key>`;
-    /// - for a [`String`], `<prefix>_<key>="<value>"`.
+    /// - for a [`String`], `<prefix>_<key>="<value>"`;
+    /// - for an [`Int`](Value::Int) or a [`Hex`](Value::Hex), `<prefix>_<key>="<value>"`,
+    ///   with the value in decimal or in hexadecimal with a `0x` prefix.
     ///
     /// All other values return [`None`].
     ///
     /// Both `prefix` and `key` are lowercased.
     pub fn to_rustc_cfg(&self, prefix: impl AsRef<str>, key: impl AsRef<str>) -> Option<String> {
         match self {
-            Value::Tristate(Tristate::True) => Some(""),
-            Value::String(s) => Some(s.as_str()),
+            Value::Tristate(Tristate::True) => Some(String::new()),
+            Value::String(s) => Some(s.clone()),
+            Value::Int(value) => Some(value.to_string()),
+            Value::Hex(value) => Some(format!("{value:#x}")),
             _ => None,
         }
         .map(|value| {
@@ -66,14 +89,62 @@ impl Value {
             }
         })
     }
+
+    /// The rustc check-cfg declaration of the cfgs [`Value::to_rustc_cfg`] generates for
+    /// an item named `key` with a value of this type.
+    ///
+    /// This is `cfg(<prefix>_<key>)` for a [`Tristate`] and
+    /// `cfg(<prefix>_<key>, values(any()))` for all other values, with both `prefix` and
+    /// `key` lowercased.
+    pub fn to_rustc_check_cfg(&self, prefix: impl AsRef<str>, key: impl AsRef<str>) -> String {
+        rustc_check_cfg(
+            prefix.as_ref(),
+            key.as_ref(),
+            !matches!(self, Value::Tristate(_)),
+        )
+    }
+}
+
+impl Kconfig {
+    /// The rustc check-cfg declarations of the cfgs [`Value::to_rustc_cfg`] generates
+    /// for all symbols of the Kconfig tree, see [`Value::to_rustc_check_cfg`].
+    ///
+    /// Unlike the ones of a configuration, these also cover the symbols which are unset.
+    /// They can be declared with [`CheckCfgArgs`](crate::build::CheckCfgArgs).
+    pub fn to_rustc_check_cfgs(&self, prefix: impl AsRef<str>) -> Vec<String> {
+        self.symbols()
+            .filter_map(|symbol| {
+                let with_values = !matches!(symbol.typ?, tree::Type::Bool | tree::Type::Tristate);
+
+                Some(rustc_check_cfg(prefix.as_ref(), &symbol.name, with_values))
+            })
+            .collect()
+    }
+}
+
+fn rustc_check_cfg(prefix: &str, key: &str, with_values: bool) -> String {
+    let name = format!("{}_{}", prefix.to_lowercase(), key.to_lowercase());
+
+    if with_values {
+        format!("cfg({name}, values(any()))")
+    } else {
+        format!("cfg({name})")
+    }
 }
 
 /// Try to load the configurations from a generated kconfig json file.
+///
+/// See [`try_from_json`] for how numbers are loaded.
 pub fn try_from_json_file(path: impl AsRef<Path>) -> Result<impl Iterator<Item = (String, Value)>> {
     try_from_json(fs::File::open(path)?)
 }
 
 /// Try to load the configurations from a generated kconfig json stream.
+///
+/// The json has no separate representation of `hex` values, so all numbers are loaded
+/// as [`Value::Int`] (or as [`Value::Hex`] if they do not fit in an `i64`), and `hex`
+/// options end up as decimal cfgs and constants. Load the `.config` file with
+/// [`try_from_config_file`] instead to keep the `hex` values.
 pub fn try_from_json<R>(reader: R) -> Result<impl Iterator<Item = (String, Value)>>
 where
     R: Read,
@@ -84,6 +155,11 @@ where
         serde_json::Value::Bool(true) => Some((k, Value::Tristate(Tristate::True))),
         serde_json::Value::Bool(false) => Some((k, Value::Tristate(Tristate::False))),
         serde_json::Value::String(value) => Some((k, Value::String(value))),
+        serde_json::Value::Number(value) => value
+            .as_i64()
+            .map(Value::Int)
+            .or_else(|| value.as_u64().map(Value::Hex))
+            .map(|value| (k, value)),
         _ => None,
     });
 
@@ -123,6 +199,33 @@ where
     Ok(iter)
 }
 
+/// Read the raw `(name, value)` entries of a .config stream, including the
+/// `# CONFIG_X is not set` entries with the value `n`.
+fn read_config_entries<R>(reader: R) -> Result<Vec<(String, String)>>
+where
+    R: Read,
+{
+    let mut entries = Vec::new();
+
+    for line in io::BufReader::new(reader).lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if let Some(name) = line
+            .strip_prefix("# ")
+            .and_then(|line| line.strip_suffix(" is not set"))
+        {
+            entries.push((name.to_owned(), "n".to_owned()));
+        } else if !line.starts_with('#') {
+            if let Some((name, value)) = line.split_once('=') {
+                entries.push((name.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
 fn parse_config_value(str: impl AsRef<str>) -> Option<Value> {
     let str = str.as_ref();
 
@@ -134,7 +237,159 @@ fn parse_config_value(str: impl AsRef<str>) -> Option<Value> {
         Value::Tristate(Tristate::False)
     } else if str == "m" {
         Value::Tristate(Tristate::Module)
+    } else if let Some(hex) = str.strip_prefix("0x").or_else(|| str.strip_prefix("0X")) {
+        Value::Hex(u64::from_str_radix(hex, 16).ok()?)
+    } else if let Ok(value) = str.parse() {
+        Value::Int(value)
     } else {
         return None;
     })
 }
+
+/// Write a Rust module with a `pub const CONFIG_<KEY>: T` item for each configuration
+/// item, to be included in firmware code with `include!`.
+///
+/// The type `T` is `bool` for tristates, `&str` for strings, `i64` for ints and `u64` for
+/// hex values. Tristates which are [`Tristate::Module`] are skipped. The keys may have the
+/// `CONFIG_` prefix (as in .config files) or not (as in json files).
+///
+/// As inner attributes cannot be included, unused items are allowed by the module the
+/// file is included in, e.g.
+///
+/// ```ignore
+/// #[allow(dead_code)]
+/// mod config {
+///     include!(concat!(env!("OUT_DIR"), "/config.rs"));
+/// }
+/// ```
+pub fn write_rust_consts<I>(config: I, output: &mut impl Write) -> Result<()>
+where
+    I: IntoIterator<Item = (String, Value)>,
+{
+    let config = config
+        .into_iter()
+        .map(|(key, value)| match key.strip_prefix(CONFIG_PREFIX) {
+            Some(key) => (key.to_owned(), value),
+            None => (key, value),
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    for (key, value) in config {
+        let (typ, value) = match value {
+            Value::Tristate(Tristate::True) => ("bool", "true".to_owned()),
+            Value::Tristate(Tristate::False | Tristate::NotSet) => ("bool", "false".to_owned()),
+            Value::Tristate(Tristate::Module) => continue,
+            Value::String(value) => ("&str", format!("{value:?}")),
+            Value::Int(value) => ("i64", value.to_string()),
+            Value::Hex(value) => ("u64", format!("{value:#x}")),
+        };
+
+        writeln!(output, "pub const {CONFIG_PREFIX}{key}: {typ} = {value};")?;
+    }
+
+    Ok(())
+}
+
+/// Write a Rust module with the configuration items to `output_file`, see
+/// [`write_rust_consts`].
+pub fn write_rust_consts_file<I>(config: I, output_file: impl AsRef<Path>) -> Result<()>
+where
+    I: IntoIterator<Item = (String, Value)>,
+{
+    let output_file = output_file.as_ref();
+
+    let mut output = io::BufWriter::new(
+        fs::File::create(output_file)
+            .map_err(|e| anyhow!("Could not write {}: {e}", output_file.display()))?,
+    );
+
+    write_rust_consts(config, &mut output)?;
+
+    output.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rust_consts() {
+        let config = "CONFIG_FOO=y\n# CONFIG_BAR is not set\nCONFIG_NAME=\"esp32\"\nCONFIG_SIZE=-42\nCONFIG_ADDR=0x3ff0\n";
+
+        let config = read_config_entries(config.as_bytes())
+            .unwrap()
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, parse_config_value(value)?)));
+
+        let mut output = Vec::new();
+        write_rust_consts(config, &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "pub const CONFIG_ADDR: u64 = 0x3ff0;\n\
+             pub const CONFIG_BAR: bool = false;\n\
+             pub const CONFIG_FOO: bool = true;\n\
+             pub const CONFIG_NAME: &str = \"esp32\";\n\
+             pub const CONFIG_SIZE: i64 = -42;\n"
+        );
+    }
+
+    #[test]
+    fn rustc_check_cfg() {
+        let kconfig = Kconfig::from_source(
+            "config FOO\n    bool \"Foo\"\nconfig BAR_SIZE\n    int \"Size\"\n",
+            "",
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            kconfig.to_rustc_check_cfgs("ESP_IDF"),
+            ["cfg(esp_idf_bar_size, values(any()))", "cfg(esp_idf_foo)"]
+        );
+        assert_eq!(
+            Value::Tristate(Tristate::False).to_rustc_check_cfg("esp_idf", "FOO"),
+            "cfg(esp_idf_foo)"
+        );
+        assert_eq!(
+            Value::Hex(0x10).to_rustc_cfg("esp_idf", "ADDR").as_deref(),
+            Some("esp_idf_addr=\"0x10\"")
+        );
+    }
+
+    #[test]
+    fn rustc_check_cfgs_cover_cfgs() {
+        let kconfig = Kconfig::from_source(
+            "config FOO\n    bool \"Foo\"\nconfig MOD\n    tristate \"Mod\"\n\
+             config SIZE\n    int \"Size\"\nconfig ADDR\n    hex \"Addr\"\n\
+             config NAME\n    string \"Name\"\n",
+            "",
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        let check_cfgs = kconfig.to_rustc_check_cfgs("ESP_IDF");
+        let mut used = std::collections::HashSet::new();
+
+        for symbol in kconfig.symbols() {
+            for raw in ["y", "m", "n", "-42", "0x3ff0", "\"esp32\""] {
+                let cfg = validate::parse_value(symbol.typ.unwrap(), raw)
+                    .and_then(|value| value.to_rustc_cfg("ESP_IDF", &symbol.name));
+
+                if let Some(cfg) = cfg {
+                    let check_cfg = match cfg.split_once('=') {
+                        Some((name, _)) => format!("cfg({name}, values(any()))"),
+                        None => format!("cfg({cfg})"),
+                    };
+
+                    assert!(check_cfgs.contains(&check_cfg), "{cfg} is not declared");
+                    used.insert(check_cfg);
+                }
+            }
+        }
+
+        assert_eq!(used.len(), check_cfgs.len());
+    }
+}
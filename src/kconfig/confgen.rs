@@ -0,0 +1,258 @@
+//! Writers of the `sdkconfig.h` and `sdkconfig.cmake` files generated by the `confgen.py`
+//! of ESP-IDF.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::config::{write_file, Config};
+use super::{parse_config_value, Rename, Tristate, Value, CONFIG_PREFIX};
+
+impl Config {
+    /// Write the C header with a `#define` for each entry, as `confgen.py` writes the
+    /// `sdkconfig.h` from a full `sdkconfig`.
+    ///
+    /// Symbols which are `y` are defined as `1` (and `m` ones as `CONFIG_X_MODULE 1`),
+    /// strings and numbers as their values, while symbols which are `n` are not defined.
+    /// `idf_version` is only used in the comment at the top, e.g. `v5.1.2`.
+    ///
+    /// The deprecated symbols of `renames` are defined as aliases of their new symbols,
+    /// e.g. `#define CONFIG_OLD CONFIG_NEW`, if those are defined.
+    pub fn write_c_header(
+        &self,
+        idf_version: &str,
+        renames: &[Rename],
+        output: &mut impl Write,
+    ) -> Result<()> {
+        write!(
+            output,
+            "/*\n * Automatically generated file. DO NOT EDIT.\n \
+             * Espressif IoT Development Framework (ESP-IDF) {idf_version} Configuration Header\n \
+             */\n#pragma once\n"
+        )?;
+
+        for entry in self.entries() {
+            let name = &entry.name;
+
+            match parse_config_value(&entry.value) {
+                Some(Value::Tristate(Tristate::True)) => {
+                    writeln!(output, "#define {CONFIG_PREFIX}{name} 1")?
+                }
+                Some(Value::Tristate(Tristate::Module)) => {
+                    writeln!(output, "#define {CONFIG_PREFIX}{name}_MODULE 1")?
+                }
+                Some(Value::Tristate(_)) | None => (),
+                // Strings are escaped the same way in `.config` files and in C
+                Some(Value::String(_) | Value::Int(_) | Value::Hex(_)) => {
+                    writeln!(output, "#define {CONFIG_PREFIX}{name} {}", entry.value)?
+                }
+            }
+        }
+
+        if !renames.is_empty() {
+            write!(output, "\n/* List of deprecated options */\n")?;
+
+            let mut renames = renames.iter().collect::<Vec<_>>();
+            renames.sort_by(|a, b| a.old.cmp(&b.old));
+
+            for rename in renames {
+                let value = self
+                    .get(&rename.new)
+                    .and_then(|entry| parse_config_value(&entry.value));
+
+                match value {
+                    Some(Value::Tristate(Tristate::False)) if rename.inverted => {
+                        writeln!(output, "#define {CONFIG_PREFIX}{} 1", rename.old)?
+                    }
+                    Some(Value::Tristate(Tristate::False | Tristate::NotSet)) | None => (),
+                    Some(Value::String(value)) if value.is_empty() => (),
+                    Some(_) if !rename.inverted => writeln!(
+                        output,
+                        "#define {CONFIG_PREFIX}{} {CONFIG_PREFIX}{}",
+                        rename.old, rename.new
+                    )?,
+                    Some(_) => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the C header to the file `path`, see [`Config::write_c_header`].
+    pub fn write_c_header_file(
+        &self,
+        idf_version: &str,
+        renames: &[Rename],
+        path: impl AsRef<Path>,
+    ) -> Result<()> {
+        write_file(path.as_ref(), |output| {
+            self.write_c_header(idf_version, renames, output)
+        })
+    }
+
+    /// Write the CMake include file with a `set(CONFIG_X "<value>")` for each entry and
+    /// the `CONFIGS_LIST` of all of them, as `confgen.py` writes the `sdkconfig.cmake`
+    /// from a full `sdkconfig`.
+    ///
+    /// Symbols which are `n` are set to an empty string and hex values are normalized to
+    /// lowercase without leading zeros.
+    ///
+    /// The deprecated symbols of `renames` are set to the values of their new symbols
+    /// after the `CONFIGS_LIST`, which lists each of them after its new symbol.
+    pub fn write_cmake(&self, renames: &[Rename], output: &mut impl Write) -> Result<()> {
+        write!(
+            output,
+            "#\n# Automatically generated file. DO NOT EDIT.\n\
+             # Espressif IoT Development Framework (ESP-IDF) Configuration cmake include file\n#\n"
+        )?;
+
+        let mut configs = Vec::new();
+        let mut deprecated = Vec::new();
+
+        for entry in self.entries() {
+            let value = match parse_config_value(&entry.value) {
+                Some(Value::Tristate(Tristate::False)) => String::new(),
+                Some(Value::String(_)) => entry.value[1..entry.value.len() - 1].to_owned(),
+                Some(Value::Hex(value)) => format!("{value:#x}"),
+                _ => entry.value.clone(),
+            };
+
+            writeln!(output, "set({CONFIG_PREFIX}{} \"{value}\")", entry.name)?;
+            configs.push(format!("{CONFIG_PREFIX}{}", entry.name));
+
+            for rename in renames.iter().filter(|rename| rename.new == entry.name) {
+                let value = match (rename.inverted, value.as_str()) {
+                    (false, _) => value.clone(),
+                    (true, "") => "y".to_owned(),
+                    (true, _) => String::new(),
+                };
+
+                deprecated.push(format!("set({CONFIG_PREFIX}{} \"{value}\")", rename.old));
+                configs.push(format!("{CONFIG_PREFIX}{}", rename.old));
+            }
+        }
+
+        // No newline at the end, like `confgen.py`
+        write!(output, "set(CONFIGS_LIST {})", configs.join(";"))?;
+
+        if !deprecated.is_empty() {
+            write!(
+                output,
+                "\n# List of deprecated options for backward compatibility\n"
+            )?;
+
+            for line in deprecated {
+                writeln!(output, "{line}")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the CMake include file to the file `path`, see [`Config::write_cmake`].
+    pub fn write_cmake_file(&self, renames: &[Rename], path: impl AsRef<Path>) -> Result<()> {
+        write_file(path.as_ref(), |output| self.write_cmake(renames, output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDKCONFIG: &str = r#"
+CONFIG_IDF_TARGET="esp32"
+CONFIG_APP_NAME="say \"hi\""
+CONFIG_FEATURE=y
+# CONFIG_DEBUG is not set
+CONFIG_DRIVER=m
+CONFIG_LEVEL=-3
+CONFIG_BASE=0x003F0
+"#;
+
+    #[test]
+    fn confgen() {
+        let config = Config::from_reader(SDKCONFIG.as_bytes()).unwrap();
+
+        let mut header = Vec::new();
+        config.write_c_header("v5.1", &[], &mut header).unwrap();
+
+        assert_eq!(
+            String::from_utf8(header).unwrap(),
+            r#"/*
+ * Automatically generated file. DO NOT EDIT.
+ * Espressif IoT Development Framework (ESP-IDF) v5.1 Configuration Header
+ */
+#pragma once
+#define CONFIG_IDF_TARGET "esp32"
+#define CONFIG_APP_NAME "say \"hi\""
+#define CONFIG_FEATURE 1
+#define CONFIG_DRIVER_MODULE 1
+#define CONFIG_LEVEL -3
+#define CONFIG_BASE 0x003F0
+"#
+        );
+
+        let mut cmake = Vec::new();
+        config.write_cmake(&[], &mut cmake).unwrap();
+
+        assert_eq!(
+            String::from_utf8(cmake).unwrap(),
+            r#"#
+# Automatically generated file. DO NOT EDIT.
+# Espressif IoT Development Framework (ESP-IDF) Configuration cmake include file
+#
+set(CONFIG_IDF_TARGET "esp32")
+set(CONFIG_APP_NAME "say \"hi\"")
+set(CONFIG_FEATURE "y")
+set(CONFIG_DEBUG "")
+set(CONFIG_DRIVER "m")
+set(CONFIG_LEVEL "-3")
+set(CONFIG_BASE "0x3f0")
+set(CONFIGS_LIST CONFIG_IDF_TARGET;CONFIG_APP_NAME;CONFIG_FEATURE;CONFIG_DEBUG;CONFIG_DRIVER;CONFIG_LEVEL;CONFIG_BASE)"#
+        );
+    }
+
+    #[test]
+    fn deprecated_options() {
+        let config = Config::from_reader(SDKCONFIG.as_bytes()).unwrap();
+        let renames = Rename::from_reader(
+            "CONFIG_OLD_FEATURE CONFIG_FEATURE\n\
+             CONFIG_NO_DEBUG !CONFIG_DEBUG\n\
+             CONFIG_OLD_DEBUG CONFIG_DEBUG\n\
+             CONFIG_OLD_BASE CONFIG_BASE\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let mut header = Vec::new();
+        config
+            .write_c_header("v5.1", &renames, &mut header)
+            .unwrap();
+
+        assert!(String::from_utf8(header).unwrap().ends_with(
+            "#define CONFIG_BASE 0x003F0
+
+/* List of deprecated options */
+#define CONFIG_NO_DEBUG 1
+#define CONFIG_OLD_BASE CONFIG_BASE
+#define CONFIG_OLD_FEATURE CONFIG_FEATURE
+"
+        ));
+
+        let mut cmake = Vec::new();
+        config.write_cmake(&renames, &mut cmake).unwrap();
+
+        assert!(String::from_utf8(cmake).unwrap().ends_with(
+            r#"set(CONFIG_BASE "0x3f0")
+set(CONFIGS_LIST CONFIG_IDF_TARGET;CONFIG_APP_NAME;CONFIG_FEATURE;CONFIG_OLD_FEATURE;CONFIG_DEBUG;CONFIG_NO_DEBUG;CONFIG_OLD_DEBUG;CONFIG_DRIVER;CONFIG_LEVEL;CONFIG_BASE;CONFIG_OLD_BASE)
+# List of deprecated options for backward compatibility
+set(CONFIG_OLD_FEATURE "y")
+set(CONFIG_NO_DEBUG "y")
+set(CONFIG_OLD_DEBUG "")
+set(CONFIG_OLD_BASE "0x3f0")
+"#
+        ));
+    }
+}
@@ -0,0 +1,434 @@
+//! `.config` files, e.g. an `sdkconfig` or `sdkconfig.defaults` fragments, and how
+//! ESP-IDF layers them.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use super::tree::{Choice, Kconfig, Symbol, Type};
+use super::validate::parse_value;
+use super::{read_config_entries, Tristate, Value, CONFIG_PREFIX};
+
+/// An entry of a [`Config`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The name of the symbol, without the `CONFIG_` prefix.
+    pub name: String,
+    /// The value in the syntax of `.config` files, e.g. `y`, `n`, `"string"` or `0x2a`.
+    pub value: String,
+    /// The file the value was read from, if any.
+    pub source: Option<PathBuf>,
+}
+
+/// The entries of a `.config` file, in the order they were first set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    entries: Vec<Entry>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the `.config` file `path`.
+    ///
+    /// `# CONFIG_X is not set` lines are read as entries with the value `n`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let read = || -> Result<Self> {
+            let mut config = Self::from_reader(fs::File::open(path)?)?;
+
+            for entry in &mut config.entries {
+                entry.source = Some(path.to_owned());
+            }
+
+            Ok(config)
+        };
+
+        read().map_err(|e| anyhow!("Could not read {}: {e}", path.display()))
+    }
+
+    /// Read a `.config` stream, see [`Config::from_file`].
+    pub fn from_reader<R>(reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut config = Self::new();
+
+        for (name, value) in read_config_entries(reader)? {
+            config.set(name, value);
+        }
+
+        Ok(config)
+    }
+
+    /// Layer the `sdkconfig.defaults` fragments `files` the way ESP-IDF does.
+    ///
+    /// The files are read in order and later values win. If `target` is set, a
+    /// `<file>.<target>` file next to each of the files (e.g. `sdkconfig.defaults.esp32s3`)
+    /// is read right after it, if it exists. The [`Entry::source`] of each entry is the
+    /// file which set its value last.
+    pub fn from_defaults<I, P>(files: I, target: Option<&str>) -> Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut config = Self::new();
+
+        for file in files {
+            let file = file.as_ref();
+
+            config.merge(Self::from_file(file)?);
+
+            if let Some(target) = target {
+                let target_file = {
+                    let mut target_file = OsString::from(file.as_os_str());
+                    target_file.push(".");
+                    target_file.push(target);
+                    PathBuf::from(target_file)
+                };
+
+                if target_file.exists() {
+                    config.merge(Self::from_file(&target_file)?);
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Get the entry of the symbol `name`, which may have the `CONFIG_` prefix.
+    pub fn get(&self, name: impl AsRef<str>) -> Option<&Entry> {
+        let name = strip_prefix(name.as_ref());
+
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Set the symbol `name` to the raw `value`, e.g. `y` or `"string"`.
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+
+        self.insert(Entry {
+            name: strip_prefix(&name).to_owned(),
+            value: value.into(),
+            source: None,
+        });
+    }
+
+    /// Remove the entry of the symbol `name`.
+    pub fn remove(&mut self, name: impl AsRef<str>) -> Option<Entry> {
+        let name = strip_prefix(name.as_ref());
+
+        let index = self.entries.iter().position(|entry| entry.name == name)?;
+
+        Some(self.entries.remove(index))
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Set all entries of `other`, overriding the values of the entries which are
+    /// already set but keeping their position.
+    pub fn merge(&mut self, other: Config) {
+        for entry in other.entries {
+            self.insert(entry);
+        }
+    }
+
+    /// The entries which are not in `base` or have a different value there.
+    #[must_use]
+    pub fn diff(&self, base: &Config) -> Config {
+        Config {
+            entries: self
+                .entries
+                .iter()
+                .filter(|entry| {
+                    base.get(&entry.name)
+                        .map_or(true, |base| base.value != entry.value)
+                })
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Write the entries in the `.config` syntax, with the entries which are `n` as
+    /// `# CONFIG_X is not set`.
+    pub fn write(&self, output: &mut impl Write) -> Result<()> {
+        for entry in &self.entries {
+            if entry.value == "n" {
+                writeln!(output, "# {CONFIG_PREFIX}{} is not set", entry.name)?;
+            } else {
+                writeln!(output, "{CONFIG_PREFIX}{}={}", entry.name, entry.value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the entries to the `.config` file `path`, see [`Config::write`].
+    pub fn write_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_file(path.as_ref(), |output| self.write(output))
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|other| other.name == entry.name)
+        {
+            Some(other) => *other = entry,
+            None => self.entries.push(entry),
+        }
+    }
+}
+
+impl Kconfig {
+    /// Compute the minimal configuration ("defconfig") which results in the full
+    /// configuration `config`, e.g. an `sdkconfig`.
+    ///
+    /// Only the entries of symbols which can be set (i.e. have a prompt and their
+    /// dependencies met) and whose values differ from their defaults are kept. Of a
+    /// choice, only the selected symbol is kept and only if it is not the default one.
+    /// Entries which are not symbols of the Kconfig tree or have an invalid value are
+    /// kept as they are.
+    pub fn defconfig(&self, config: &Config) -> Config {
+        let values = config
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let typ = self.symbol(&entry.name)?.typ?;
+
+                Some((entry.name.as_str(), parse_value(typ, &entry.value)?))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let value = |name: &str| self.value(&values, name);
+
+        let mut defconfig = Config::new();
+
+        for entry in &config.entries {
+            let (symbol, typ, current) = match self
+                .symbol(&entry.name)
+                .and_then(|symbol| Some((symbol, symbol.typ?, values.get(symbol.name.as_str())?)))
+            {
+                Some(symbol) => symbol,
+                None => {
+                    defconfig.insert(entry.clone());
+                    continue;
+                }
+            };
+
+            if symbol.prompt.is_none() || symbol.depends_on.eval(&value) == Tristate::False {
+                continue;
+            }
+
+            let differs = match symbol.choice {
+                Some(choice) => {
+                    *current == Value::Tristate(Tristate::True)
+                        && choice_default(&self.choices()[choice], &value).as_deref()
+                            != Some(symbol.name.as_str())
+                }
+                None => Some(current) != self.default_value(symbol, typ, &value).as_ref(),
+            };
+
+            if differs {
+                defconfig.insert(entry.clone());
+            }
+        }
+
+        defconfig
+    }
+
+    /// The value of `symbol` if it is not set, or [`None`] if it has no default.
+    fn default_value(
+        &self,
+        symbol: &Symbol,
+        typ: Type,
+        value: &dyn Fn(&str) -> Option<Value>,
+    ) -> Option<Value> {
+        let default = symbol
+            .defaults
+            .iter()
+            .find(|default| default.condition.eval(value) != Tristate::False);
+
+        match typ {
+            Type::Bool | Type::Tristate => {
+                let is_set = |name: &str| {
+                    matches!(
+                        value(name),
+                        Some(Value::Tristate(Tristate::True | Tristate::Module))
+                    )
+                };
+
+                let forced = self.symbols().any(|other| {
+                    is_set(&other.name)
+                        && other.selects.iter().chain(&other.implies).any(|select| {
+                            select.value == symbol.name
+                                && select.condition.eval(value) != Tristate::False
+                        })
+                });
+
+                let tristate = match default.map(|default| default.value.eval(value)) {
+                    _ if forced => Tristate::True,
+                    Some(Tristate::Module) if typ == Type::Bool => Tristate::True,
+                    Some(Tristate::True) => Tristate::True,
+                    Some(Tristate::Module) => Tristate::Module,
+                    _ => Tristate::False,
+                };
+
+                Some(Value::Tristate(tristate))
+            }
+            Type::String => {
+                Some(Value::String(default.map_or_else(String::new, |default| {
+                    default.value.string(value)
+                })))
+            }
+            Type::Int | Type::Hex => parse_value(typ, &default?.value.string(value)),
+        }
+    }
+}
+
+/// The symbol of `choice` which is selected if none is set.
+fn choice_default(choice: &Choice, value: &dyn Fn(&str) -> Option<Value>) -> Option<String> {
+    choice
+        .defaults
+        .iter()
+        .find(|default| default.condition.eval(value) != Tristate::False)
+        .map(|default| default.value.clone())
+        .or_else(|| choice.symbols.first().cloned())
+}
+
+/// Create the file `path` and write it with `write`.
+pub(super) fn write_file(
+    path: &Path,
+    write: impl FnOnce(&mut io::BufWriter<fs::File>) -> Result<()>,
+) -> Result<()> {
+    let mut output = io::BufWriter::new(
+        fs::File::create(path).map_err(|e| anyhow!("Could not write {}: {e}", path.display()))?,
+    );
+
+    write(&mut output)?;
+    output.flush()?;
+
+    Ok(())
+}
+
+fn strip_prefix(name: &str) -> &str {
+    name.strip_prefix(CONFIG_PREFIX).unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KCONFIG: &str = r#"
+config FEATURE
+    bool "Feature"
+    default y
+
+config FEATURE_LEVEL
+    int "Level"
+    depends on FEATURE
+    default 5
+
+config NAME
+    string "Name"
+    default "esp"
+
+config LOG
+    bool
+    default y
+
+config DEBUG
+    bool "Debug"
+    select LOG
+
+choice MODE
+    prompt "Mode"
+    default MODE_FAST
+
+    config MODE_FAST
+        bool "Fast"
+    config MODE_SLOW
+        bool "Slow"
+endchoice
+"#;
+
+    #[test]
+    fn defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let files = [
+            dir.join("sdkconfig.defaults"),
+            dir.join("sdkconfig.release"),
+        ];
+
+        fs::write(&files[0], "CONFIG_FEATURE=y\nCONFIG_NAME=\"base\"\n").unwrap();
+        fs::write(
+            dir.join("sdkconfig.defaults.esp32s3"),
+            "CONFIG_FEATURE_LEVEL=7\n",
+        )
+        .unwrap();
+        fs::write(&files[1], "# CONFIG_FEATURE is not set\n").unwrap();
+
+        let config = Config::from_defaults(&files, Some("esp32s3")).unwrap();
+        let esp32 = Config::from_defaults(&files, Some("esp32")).unwrap();
+
+        let mut output = Vec::new();
+        config.write(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "# CONFIG_FEATURE is not set\nCONFIG_NAME=\"base\"\nCONFIG_FEATURE_LEVEL=7\n"
+        );
+        assert_eq!(
+            config.get("CONFIG_FEATURE").unwrap().source.as_ref(),
+            Some(&files[1])
+        );
+        assert_eq!(esp32.get("FEATURE_LEVEL"), None);
+        assert_eq!(config.diff(&esp32).entries(), &config.entries()[2..],);
+    }
+
+    #[test]
+    fn defconfig() {
+        let kconfig = Kconfig::from_source(KCONFIG, "", &HashMap::new()).unwrap();
+
+        let config = Config::from_reader(
+            "CONFIG_FEATURE=y\nCONFIG_FEATURE_LEVEL=6\nCONFIG_NAME=\"esp\"\nCONFIG_LOG=y\n\
+             CONFIG_DEBUG=y\n# CONFIG_MODE_FAST is not set\nCONFIG_MODE_SLOW=y\nCONFIG_OTHER=1\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let names = |config: &Config| {
+            config
+                .entries()
+                .iter()
+                .map(|entry| entry.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(&kconfig.defconfig(&config)),
+            ["FEATURE_LEVEL", "DEBUG", "MODE_SLOW", "OTHER"]
+        );
+
+        let mut config = config;
+        config.set("FEATURE", "n");
+        config.set("MODE_FAST", "y");
+        config.set("MODE_SLOW", "n");
+
+        assert_eq!(
+            names(&kconfig.defconfig(&config)),
+            ["FEATURE", "DEBUG", "OTHER"]
+        );
+    }
+}
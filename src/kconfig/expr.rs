@@ -0,0 +1,437 @@
+//! Kconfig expressions, as used in `depends on`, `if` and the conditions of properties.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use super::{Tristate, Value};
+
+/// A Kconfig expression.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    /// A symbol or an unquoted constant, e.g. `FOO`, `y` or `42`.
+    ///
+    /// Names which are not defined as symbols are constants.
+    Symbol(String),
+    /// A quoted string constant.
+    String(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+}
+
+/// A comparison operator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl CompareOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Equal => "=",
+            Self::NotEqual => "!=",
+            Self::Less => "<",
+            Self::LessEqual => "<=",
+            Self::Greater => ">",
+            Self::GreaterEqual => ">=",
+        }
+    }
+
+    fn from_str(op: &str) -> Option<Self> {
+        [
+            Self::Equal,
+            Self::NotEqual,
+            Self::Less,
+            Self::LessEqual,
+            Self::Greater,
+            Self::GreaterEqual,
+        ]
+        .into_iter()
+        .find(|candidate| candidate.as_str() == op)
+    }
+
+    fn matches(&self, ordering: Ordering) -> bool {
+        match self {
+            Self::Equal => ordering == Ordering::Equal,
+            Self::NotEqual => ordering != Ordering::Equal,
+            Self::Less => ordering == Ordering::Less,
+            Self::LessEqual => ordering != Ordering::Greater,
+            Self::Greater => ordering == Ordering::Greater,
+            Self::GreaterEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+impl Expr {
+    /// The constant `y`, i.e. an expression which is always met.
+    pub fn yes() -> Self {
+        Self::Symbol("y".to_owned())
+    }
+
+    /// Whether this is the constant `y`.
+    pub fn is_yes(&self) -> bool {
+        matches!(self, Self::Symbol(name) if name == "y")
+    }
+
+    /// `self && other`, omitting operands which are `y`.
+    #[must_use]
+    pub fn and(self, other: Self) -> Self {
+        if self.is_yes() {
+            other
+        } else if other.is_yes() {
+            self
+        } else {
+            Self::And(Box::new(self), Box::new(other))
+        }
+    }
+
+    /// `self || other`, or `y` if either operand is `y`.
+    #[must_use]
+    pub fn or(self, other: Self) -> Self {
+        if self.is_yes() || other.is_yes() {
+            Self::yes()
+        } else {
+            Self::Or(Box::new(self), Box::new(other))
+        }
+    }
+
+    /// Evaluate the expression, where `value` returns the values of the symbols, or
+    /// [`None`] for names which are not symbols and thus constants.
+    ///
+    /// Symbols other than `bool` and `tristate` ones are `n` outside of comparisons,
+    /// which compare numerically if both operands are numbers and as strings otherwise.
+    pub fn eval(&self, value: &dyn Fn(&str) -> Option<Value>) -> Tristate {
+        match self.level(value) {
+            2 => Tristate::True,
+            1 => Tristate::Module,
+            _ => Tristate::False,
+        }
+    }
+
+    /// The value of the expression with `n`, `m` and `y` as 0, 1 and 2.
+    fn level(&self, value: &dyn Fn(&str) -> Option<Value>) -> u8 {
+        match self {
+            Self::Symbol(name) => constant_level(name).unwrap_or_else(|| match value(name) {
+                Some(Value::Tristate(tristate)) => level(tristate),
+                _ => 0,
+            }),
+            // Quoted `"y"`, `"m"` and `"n"` are the same constants as unquoted ones
+            Self::String(string) => constant_level(string).unwrap_or(0),
+            Self::Not(expr) => 2 - expr.level(value),
+            Self::And(left, right) => left.level(value).min(right.level(value)),
+            Self::Or(left, right) => left.level(value).max(right.level(value)),
+            Self::Compare(op, left, right) => {
+                let (left, right) = (left.string(value), right.string(value));
+
+                let ordering = match (parse_number(&left), parse_number(&right)) {
+                    (Some(left), Some(right)) => left.cmp(&right),
+                    _ => left.cmp(&right),
+                };
+
+                if op.matches(ordering) {
+                    2
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    /// The value of the expression as the operand of a comparison or a `range`.
+    pub fn string(&self, value: &dyn Fn(&str) -> Option<Value>) -> String {
+        match self {
+            Self::Symbol(name) => match value(name) {
+                Some(Value::Tristate(tristate)) => tristate_str(level(tristate)).to_owned(),
+                Some(Value::String(string)) => string,
+                Some(Value::Int(value)) => value.to_string(),
+                Some(Value::Hex(value)) => format!("{value:#x}"),
+                None => name.clone(),
+            },
+            Self::String(string) => string.clone(),
+            expr => tristate_str(expr.level(value)).to_owned(),
+        }
+    }
+
+    /// Parse an expression from the tokens of a line.
+    pub(super) fn parse(tokens: &[Token]) -> Result<Self> {
+        let mut parser = ExprParser { tokens, pos: 0 };
+
+        let expr = parser.or()?;
+
+        if let Some(token) = tokens.get(parser.pos) {
+            bail!("Unexpected {token} in expression");
+        }
+
+        Ok(expr)
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Or(..) => 1,
+            Self::And(..) => 2,
+            Self::Not(_) => 3,
+            Self::Compare(..) => 4,
+            Self::Symbol(_) | Self::String(_) => 5,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, precedence: u8) -> fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "({self})")
+        } else {
+            write!(f, "{self}")
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precedence = self.precedence();
+
+        match self {
+            Self::Symbol(name) => f.write_str(name),
+            Self::String(string) => write!(f, "\"{}\"", string.replace('"', "\\\"")),
+            Self::Not(expr) => {
+                f.write_str("!")?;
+                expr.fmt_operand(f, precedence)
+            }
+            Self::And(left, right) | Self::Or(left, right) => {
+                left.fmt_operand(f, precedence)?;
+                f.write_str(if precedence == 1 { " || " } else { " && " })?;
+                right.fmt_operand(f, precedence)
+            }
+            Self::Compare(op, left, right) => {
+                left.fmt_operand(f, precedence + 1)?;
+                write!(f, " {} ", op.as_str())?;
+                right.fmt_operand(f, precedence + 1)
+            }
+        }
+    }
+}
+
+/// Parse a decimal or hexadecimal number as used in `int` and `hex` symbols.
+pub(super) fn parse_number(number: &str) -> Option<i64> {
+    let (negative, digits) = match number.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, number),
+    };
+
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) => {
+            digits.parse().ok()?
+        }
+        None => return None,
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn level(tristate: Tristate) -> u8 {
+    match tristate {
+        Tristate::True => 2,
+        Tristate::Module => 1,
+        Tristate::False | Tristate::NotSet => 0,
+    }
+}
+
+/// The level of the tristate constant `name`, if it is one.
+fn constant_level(name: &str) -> Option<u8> {
+    match name {
+        "y" => Some(2),
+        "m" => Some(1),
+        "n" => Some(0),
+        _ => None,
+    }
+}
+
+fn tristate_str(level: u8) -> &'static str {
+    match level {
+        2 => "y",
+        1 => "m",
+        _ => "n",
+    }
+}
+
+/// A token of a line of a Kconfig file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) enum Token {
+    Word(String),
+    String(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Word(word) => write!(f, "`{word}`"),
+            Self::String(string) => write!(f, "\"{string}\""),
+            Self::Op(op) => write!(f, "`{op}`"),
+        }
+    }
+}
+
+const OPERATORS: &[&str] = &["&&", "||", "!=", "<=", ">=", "=", "<", ">", "!", "(", ")"];
+
+/// Split a line into tokens, up to a `#` comment.
+pub(super) fn tokenize(line: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c == '#' {
+            break;
+        } else if c == '"' || c == '\'' {
+            let mut string = String::new();
+            let mut chars = rest[1..].char_indices();
+
+            let end = loop {
+                match chars.next() {
+                    Some((index, quote)) if quote == c => break index + 2,
+                    Some((_, '\\')) => string.extend(chars.next().map(|(_, c)| c)),
+                    Some((_, c)) => string.push(c),
+                    None => bail!("Unterminated string"),
+                }
+            };
+
+            tokens.push(Token::String(string));
+            rest = &rest[end..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || "=!<>()&|\"'#".contains(c))
+                .unwrap_or(rest.len());
+
+            ensure!(end > 0, "Unexpected `{}`", &rest[..1]);
+
+            tokens.push(Token::Word(rest[..end].to_owned()));
+            rest = &rest[end..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.not()?)))
+        } else {
+            self.compare()
+        }
+    }
+
+    fn compare(&mut self) -> Result<Expr> {
+        let left = self.primary()?;
+
+        let op = match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => CompareOp::from_str(op),
+            _ => None,
+        };
+
+        match op {
+            Some(op) => {
+                self.pos += 1;
+
+                Ok(Expr::Compare(op, Box::new(left), Box::new(self.primary()?)))
+            }
+            None => Ok(left),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| anyhow!("Unexpected end of expression"))?;
+
+        self.pos += 1;
+
+        match token {
+            Token::Word(name) => Ok(Expr::Symbol(name.clone())),
+            Token::String(string) => Ok(Expr::String(string.clone())),
+            Token::Op("(") => {
+                let expr = self.or()?;
+
+                ensure!(self.eat(")"), "Expected `)`");
+
+                Ok(expr)
+            }
+            token => bail!("Unexpected {token} in expression"),
+        }
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let matches = matches!(self.tokens.get(self.pos), Some(Token::Op(token)) if *token == op);
+
+        if matches {
+            self.pos += 1;
+        }
+
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_constants() {
+        let eval = |expr: &str| {
+            Expr::parse(&tokenize(expr).unwrap())
+                .unwrap()
+                .eval(&|name| match name {
+                    "FOO" => Some(Value::Tristate(Tristate::True)),
+                    _ => None,
+                })
+        };
+
+        assert_eq!(eval("FOO && \"y\""), Tristate::True);
+        assert_eq!(eval("\"m\""), Tristate::Module);
+        assert_eq!(eval("FOO && \"n\""), Tristate::False);
+        assert_eq!(eval("!\"n\""), Tristate::True);
+        assert_eq!(eval("FOO = \"y\""), Tristate::True);
+        assert_eq!(eval("\"yes\""), Tristate::False);
+    }
+}
@@ -0,0 +1,744 @@
+//! A parser for Kconfig files, e.g. the Kconfig tree of ESP-IDF.
+//!
+//! Supports the `config`, `menuconfig`, `choice`, `menu`, `if`, `comment`, `mainmenu`
+//! and `source` (as well as `rsource`, `osource` and `orsource`) entries and the `depends
+//! on`, `select`, `imply`, `default`, `def_<type>`, `range`, `prompt` and `help`
+//! properties. Environment variables referenced as `$VAR`, `${VAR}` or `$(VAR)` in
+//! strings are expanded.
+
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use super::expr::{tokenize, Expr, Token};
+
+/// The type of a kconfig symbol.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Type {
+    Bool,
+    Tristate,
+    String,
+    Int,
+    Hex,
+}
+
+impl Type {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Bool => "bool",
+            Self::Tristate => "tristate",
+            Self::String => "string",
+            Self::Int => "int",
+            Self::Hex => "hex",
+        }
+    }
+
+    fn from_keyword(keyword: &str) -> Option<Self> {
+        Some(match keyword {
+            "bool" | "boolean" => Self::Bool,
+            "tristate" => Self::Tristate,
+            "string" => Self::String,
+            "int" => Self::Int,
+            "hex" => Self::Hex,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A property which only applies if its `condition` is met.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conditional<T> {
+    pub value: T,
+    /// The `if` condition of the property, including the dependencies of the symbol.
+    pub condition: Expr,
+}
+
+/// A symbol defined with `config` or `menuconfig`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub typ: Option<Type>,
+    pub prompt: Option<String>,
+    pub help: Option<String>,
+    /// The dependencies of the symbol, including the ones of the enclosing menus, `if`
+    /// blocks and choice, combined with `||` if the symbol is defined multiple times.
+    pub depends_on: Expr,
+    pub defaults: Vec<Conditional<Expr>>,
+    pub selects: Vec<Conditional<String>>,
+    pub implies: Vec<Conditional<String>>,
+    /// The ranges of an `int` or `hex` symbol, of which the first one whose condition
+    /// is met applies.
+    pub ranges: Vec<Conditional<(Expr, Expr)>>,
+    /// The index of the choice the symbol belongs to in [`Kconfig::choices`].
+    pub choice: Option<usize>,
+}
+
+/// A `choice` of `bool` symbols, of which only one can be set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Choice {
+    pub name: Option<String>,
+    pub prompt: Option<String>,
+    pub help: Option<String>,
+    pub depends_on: Expr,
+    pub defaults: Vec<Conditional<String>>,
+    /// Whether none of the symbols may be set.
+    pub optional: bool,
+    pub symbols: Vec<String>,
+}
+
+impl fmt::Display for Choice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.name, &self.prompt) {
+            (Some(name), _) => f.write_str(name),
+            (None, Some(prompt)) => write!(f, "\"{prompt}\""),
+            (None, None) => f.write_str("<unnamed>"),
+        }
+    }
+}
+
+/// The symbols and choices of a Kconfig tree.
+#[derive(Clone, Debug, Default)]
+pub struct Kconfig {
+    symbols: BTreeMap<String, Symbol>,
+    choices: Vec<Choice>,
+    mainmenu: Option<String>,
+}
+
+impl Kconfig {
+    /// Parse the Kconfig file `path` and all files it sources.
+    ///
+    /// Environment variables are looked up in `env` first and then in the environment
+    /// of the process, e.g. ESP-IDF requires `IDF_PATH`, `IDF_TARGET`,
+    /// `COMPONENT_KCONFIGS_SOURCE_FILE` and `COMPONENT_KCONFIGS_PROJBUILD_SOURCE_FILE`.
+    /// Relative `source` paths are resolved against `$srctree`, or the directory of
+    /// `path` if it is not set.
+    pub fn from_file(path: impl AsRef<Path>, env: &HashMap<String, String>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let mut parser = Parser::new(path.parent().unwrap_or_else(|| Path::new("")), env);
+
+        parser.parse_file(path)?;
+        parser.finish()
+    }
+
+    /// Parse the Kconfig `source`, see [`Kconfig::from_file`].
+    pub fn from_source(
+        source: &str,
+        dir: impl AsRef<Path>,
+        env: &HashMap<String, String>,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+
+        let mut parser = Parser::new(dir, env);
+
+        parser.parse_source(source, &dir.join("Kconfig"))?;
+        parser.finish()
+    }
+
+    pub fn symbol(&self, name: impl AsRef<str>) -> Option<&Symbol> {
+        self.symbols.get(name.as_ref())
+    }
+
+    /// All symbols, ordered by name.
+    pub fn symbols(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.values()
+    }
+
+    pub fn choices(&self) -> &[Choice] {
+        &self.choices
+    }
+
+    pub fn mainmenu(&self) -> Option<&str> {
+        self.mainmenu.as_deref()
+    }
+}
+
+struct Parser<'a> {
+    kconfig: Kconfig,
+    env: &'a HashMap<String, String>,
+    srctree: PathBuf,
+    blocks: Vec<Block>,
+    entry: Entry,
+}
+
+/// A `menu`, `if` or `choice` block, whose dependencies apply to all of its entries.
+struct Block {
+    kind: BlockKind,
+    depends_on: Expr,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum BlockKind {
+    If,
+    Menu,
+    Choice(usize),
+}
+
+/// The entry the properties being parsed belong to.
+enum Entry {
+    None,
+    Config(Box<Definition>),
+    Choice(usize),
+    Menu,
+    Comment,
+}
+
+/// A single definition of a symbol, whose properties depend on its `depends on`.
+struct Definition {
+    name: String,
+    parent: Expr,
+    depends_on: Expr,
+    typ: Option<Type>,
+    prompt: Option<String>,
+    help: Option<String>,
+    defaults: Vec<Conditional<Expr>>,
+    selects: Vec<Conditional<String>>,
+    implies: Vec<Conditional<String>>,
+    ranges: Vec<Conditional<(Expr, Expr)>>,
+    choice: Option<usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(dir: &Path, env: &'a HashMap<String, String>) -> Self {
+        let mut parser = Self {
+            kconfig: Kconfig::default(),
+            env,
+            srctree: dir.to_owned(),
+            blocks: Vec::new(),
+            entry: Entry::None,
+        };
+
+        if let Some(srctree) = parser.var("srctree") {
+            parser.srctree = PathBuf::from(srctree);
+        }
+
+        parser
+    }
+
+    fn finish(mut self) -> Result<Kconfig> {
+        self.finish_entry();
+
+        Ok(self.kconfig)
+    }
+
+    fn parse_file(&mut self, path: &Path) -> Result<()> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+
+        self.parse_source(&source, path)
+    }
+
+    fn parse_source(&mut self, source: &str, file: &Path) -> Result<()> {
+        let lines = source.lines().collect::<Vec<_>>();
+        let depth = self.blocks.len();
+
+        let mut index = 0;
+
+        while index < lines.len() {
+            let number = index + 1;
+            let mut line = lines[index].to_owned();
+            index += 1;
+
+            while line.ends_with('\\') && index < lines.len() {
+                line.pop();
+                line.push_str(lines[index]);
+                index += 1;
+            }
+
+            let result = tokenize(&line).and_then(|tokens| match tokens.first() {
+                Some(Token::Word(keyword)) if keyword == "help" || keyword == "---help---" => {
+                    let (help, end) = help_text(&lines, index);
+                    index = end;
+
+                    self.set_help(help)
+                }
+                Some(_) => self.statement(&tokens, file),
+                None => Ok(()),
+            });
+
+            result.map_err(|e| anyhow!("{}:{number}: {e}", file.display()))?;
+        }
+
+        ensure!(
+            self.blocks.len() == depth,
+            "{}: Missing `endmenu`, `endif` or `endchoice`",
+            file.display()
+        );
+
+        Ok(())
+    }
+
+    fn statement(&mut self, tokens: &[Token], file: &Path) -> Result<()> {
+        let (keyword, args) = match tokens.split_first() {
+            Some((Token::Word(keyword), args)) => (keyword.as_str(), args),
+            _ => bail!("Expected a keyword"),
+        };
+
+        match keyword {
+            "config" | "menuconfig" => {
+                self.finish_entry();
+
+                let name = match args {
+                    [Token::Word(name)] => name.clone(),
+                    _ => bail!("Expected a symbol name"),
+                };
+
+                let choice = self.blocks.iter().rev().find_map(|block| match block.kind {
+                    BlockKind::Choice(choice) => Some(choice),
+                    _ => None,
+                });
+
+                self.entry = Entry::Config(Box::new(Definition {
+                    name,
+                    parent: self.depends_on(),
+                    depends_on: Expr::yes(),
+                    typ: None,
+                    prompt: None,
+                    help: None,
+                    defaults: Vec::new(),
+                    selects: Vec::new(),
+                    implies: Vec::new(),
+                    ranges: Vec::new(),
+                    choice,
+                }));
+            }
+            "choice" => {
+                self.finish_entry();
+
+                let name = match args {
+                    [] => None,
+                    [Token::Word(name)] => Some(name.clone()),
+                    _ => bail!("Expected an optional choice name"),
+                };
+
+                let index = self.kconfig.choices.len();
+
+                self.kconfig.choices.push(Choice {
+                    name,
+                    prompt: None,
+                    help: None,
+                    depends_on: self.depends_on(),
+                    defaults: Vec::new(),
+                    optional: false,
+                    symbols: Vec::new(),
+                });
+
+                self.blocks.push(Block {
+                    kind: BlockKind::Choice(index),
+                    depends_on: Expr::yes(),
+                });
+                self.entry = Entry::Choice(index);
+            }
+            "menu" => {
+                self.finish_entry();
+                self.string(args)?;
+
+                self.blocks.push(Block {
+                    kind: BlockKind::Menu,
+                    depends_on: Expr::yes(),
+                });
+                self.entry = Entry::Menu;
+            }
+            "if" => {
+                self.finish_entry();
+
+                self.blocks.push(Block {
+                    kind: BlockKind::If,
+                    depends_on: Expr::parse(args)?,
+                });
+            }
+            "endchoice" | "endmenu" | "endif" => {
+                self.finish_entry();
+
+                let block = self.blocks.pop();
+
+                let matches = matches!(
+                    (keyword, block.map(|block| block.kind)),
+                    ("endchoice", Some(BlockKind::Choice(_)))
+                        | ("endmenu", Some(BlockKind::Menu))
+                        | ("endif", Some(BlockKind::If))
+                );
+
+                ensure!(matches, "Unexpected `{keyword}`");
+            }
+            "comment" => {
+                self.finish_entry();
+                self.string(args)?;
+
+                self.entry = Entry::Comment;
+            }
+            "mainmenu" => {
+                self.finish_entry();
+
+                self.kconfig.mainmenu = Some(self.string(args)?);
+            }
+            "source" | "rsource" | "osource" | "orsource" => {
+                self.finish_entry();
+
+                let path = PathBuf::from(self.string(args)?);
+
+                let path = if path.is_absolute() {
+                    path
+                } else if keyword.starts_with('r') || keyword.starts_with("or") {
+                    file.parent().unwrap_or_else(|| Path::new("")).join(path)
+                } else {
+                    self.srctree.join(path)
+                };
+
+                if keyword.starts_with('o') && !path.exists() {
+                    return Ok(());
+                }
+
+                self.parse_file(&path)?;
+            }
+            _ => self.property(keyword, args)?,
+        }
+
+        Ok(())
+    }
+
+    fn property(&mut self, keyword: &str, args: &[Token]) -> Result<()> {
+        if keyword == "depends" {
+            ensure!(
+                matches!(args.first(), Some(Token::Word(on)) if on == "on"),
+                "Expected `depends on`"
+            );
+
+            let depends_on = Expr::parse(&args[1..])?;
+
+            match &mut self.entry {
+                Entry::Config(definition) => and(&mut definition.depends_on, depends_on),
+                Entry::Choice(index) => {
+                    and(
+                        &mut self.kconfig.choices[*index].depends_on,
+                        depends_on.clone(),
+                    );
+
+                    if let Some(block) = self.blocks.last_mut() {
+                        and(&mut block.depends_on, depends_on);
+                    }
+                }
+                Entry::Menu => {
+                    if let Some(block) = self.blocks.last_mut() {
+                        and(&mut block.depends_on, depends_on);
+                    }
+                }
+                Entry::Comment => (),
+                Entry::None => bail!("Unexpected `depends on`"),
+            }
+
+            return Ok(());
+        }
+
+        if let Entry::Choice(index) = self.entry {
+            let choice = &mut self.kconfig.choices[index];
+
+            match keyword {
+                "bool" | "boolean" | "tristate" | "prompt" => {
+                    if let ([Token::String(prompt)], _) = split_condition(args)? {
+                        choice.prompt = Some(prompt.clone());
+                    }
+                }
+                "default" => {
+                    let (value, condition) = split_condition(args)?;
+
+                    match value {
+                        [Token::Word(name)] => choice.defaults.push(Conditional {
+                            value: name.clone(),
+                            condition,
+                        }),
+                        _ => bail!("Expected a symbol name"),
+                    }
+                }
+                "optional" => choice.optional = true,
+                "option" | "visible" => (),
+                _ => bail!("Unexpected `{keyword}` in a choice"),
+            }
+
+            return Ok(());
+        }
+
+        let expanded = args
+            .iter()
+            .map(|token| match token {
+                Token::String(string) => Token::String(self.expand(string)),
+                token => token.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        let definition = match &mut self.entry {
+            Entry::Config(definition) => definition,
+            Entry::Menu | Entry::Comment if matches!(keyword, "visible" | "option") => {
+                return Ok(())
+            }
+            _ => bail!("Unexpected `{keyword}`"),
+        };
+
+        let (value, condition) = split_condition(&expanded)?;
+
+        if let Some(typ) = Type::from_keyword(keyword) {
+            definition.typ = Some(typ);
+
+            match value {
+                [] => (),
+                [Token::String(prompt)] => definition.prompt = Some(prompt.clone()),
+                _ => bail!("Expected an optional prompt"),
+            }
+
+            return Ok(());
+        }
+
+        match keyword {
+            "prompt" => match value {
+                [Token::String(prompt)] => definition.prompt = Some(prompt.clone()),
+                _ => bail!("Expected a prompt"),
+            },
+            "default" | "def_bool" | "def_tristate" | "def_string" | "def_int" | "def_hex" => {
+                if let Some(typ) = keyword.strip_prefix("def_").and_then(Type::from_keyword) {
+                    definition.typ = Some(typ);
+                }
+
+                definition.defaults.push(Conditional {
+                    value: Expr::parse(value)?,
+                    condition,
+                });
+            }
+            "select" | "imply" => {
+                let name = match value {
+                    [Token::Word(name)] => name.clone(),
+                    _ => bail!("Expected a symbol name"),
+                };
+
+                let properties = if keyword == "select" {
+                    &mut definition.selects
+                } else {
+                    &mut definition.implies
+                };
+
+                properties.push(Conditional {
+                    value: name,
+                    condition,
+                });
+            }
+            "range" => {
+                let bound = |token: &Token| match token {
+                    Token::Word(name) => Ok(Expr::Symbol(name.clone())),
+                    Token::String(string) => Ok(Expr::String(string.clone())),
+                    token => Err(anyhow!("Unexpected {token} in range")),
+                };
+
+                match value {
+                    [low, high] => definition.ranges.push(Conditional {
+                        value: (bound(low)?, bound(high)?),
+                        condition,
+                    }),
+                    _ => bail!("Expected the bounds of the range"),
+                }
+            }
+            "option" | "visible" | "modules" | "transitional" => (),
+            _ => bail!("Unknown keyword `{keyword}`"),
+        }
+
+        Ok(())
+    }
+
+    fn set_help(&mut self, help: String) -> Result<()> {
+        match &mut self.entry {
+            Entry::Config(definition) => definition.help = Some(help),
+            Entry::Choice(index) => self.kconfig.choices[*index].help = Some(help),
+            Entry::Menu | Entry::Comment => (),
+            Entry::None => bail!("Unexpected `help`"),
+        }
+
+        Ok(())
+    }
+
+    /// Merge the definition being parsed into its symbol.
+    fn finish_entry(&mut self) {
+        let definition = match std::mem::replace(&mut self.entry, Entry::None) {
+            Entry::Config(definition) => definition,
+            _ => return,
+        };
+
+        let depends_on = definition.parent.and(definition.depends_on);
+
+        let defaults = with_condition(definition.defaults, &depends_on);
+        let selects = with_condition(definition.selects, &depends_on);
+        let implies = with_condition(definition.implies, &depends_on);
+        let ranges = with_condition(definition.ranges, &depends_on);
+
+        let symbol = match self.kconfig.symbols.entry(definition.name.clone()) {
+            btree_map::Entry::Vacant(entry) => entry.insert(Symbol {
+                name: definition.name.clone(),
+                typ: None,
+                prompt: None,
+                help: None,
+                depends_on,
+                defaults: Vec::new(),
+                selects: Vec::new(),
+                implies: Vec::new(),
+                ranges: Vec::new(),
+                choice: None,
+            }),
+            btree_map::Entry::Occupied(entry) => {
+                let symbol = entry.into_mut();
+                or(&mut symbol.depends_on, depends_on);
+
+                symbol
+            }
+        };
+
+        symbol.typ = symbol.typ.or(definition.typ);
+        symbol.prompt = symbol.prompt.take().or(definition.prompt);
+        symbol.help = symbol.help.take().or(definition.help);
+        symbol.defaults.extend(defaults);
+        symbol.selects.extend(selects);
+        symbol.implies.extend(implies);
+        symbol.ranges.extend(ranges);
+
+        if let (None, Some(choice)) = (symbol.choice, definition.choice) {
+            symbol.choice = Some(choice);
+            self.kconfig.choices[choice].symbols.push(definition.name);
+        }
+    }
+
+    /// The dependencies of the enclosing blocks.
+    fn depends_on(&self) -> Expr {
+        self.blocks.iter().fold(Expr::yes(), |depends_on, block| {
+            depends_on.and(block.depends_on.clone())
+        })
+    }
+
+    /// The string argument of an entry, with environment variables expanded.
+    fn string(&self, args: &[Token]) -> Result<String> {
+        match args {
+            [Token::String(string)] => Ok(self.expand(string)),
+            _ => bail!("Expected a string"),
+        }
+    }
+
+    fn expand(&self, string: &str) -> String {
+        let mut expanded = String::with_capacity(string.len());
+        let mut rest = string;
+
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+
+            let (name, len) = match rest.chars().next() {
+                Some(open @ ('(' | '{')) => {
+                    let close = if open == '(' { ')' } else { '}' };
+
+                    match rest.find(close) {
+                        Some(end) => (&rest[1..end], end + 1),
+                        None => ("", 0),
+                    }
+                }
+                _ => {
+                    let end = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+
+                    (&rest[..end], end)
+                }
+            };
+
+            if name.is_empty() {
+                expanded.push('$');
+            } else {
+                expanded.push_str(&self.var(name).unwrap_or_default());
+                rest = &rest[len..];
+            }
+        }
+
+        expanded.push_str(rest);
+        expanded
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.env.get(name).cloned().or_else(|| env::var(name).ok())
+    }
+}
+
+fn and(expr: &mut Expr, other: Expr) {
+    *expr = std::mem::replace(expr, Expr::yes()).and(other);
+}
+
+fn or(expr: &mut Expr, other: Expr) {
+    *expr = std::mem::replace(expr, Expr::yes()).or(other);
+}
+
+/// Add the dependencies of a definition to the conditions of its properties.
+fn with_condition<T>(properties: Vec<Conditional<T>>, depends_on: &Expr) -> Vec<Conditional<T>> {
+    properties
+        .into_iter()
+        .map(|property| Conditional {
+            value: property.value,
+            condition: property.condition.and(depends_on.clone()),
+        })
+        .collect()
+}
+
+/// Split the trailing `if <condition>` off a property.
+fn split_condition(args: &[Token]) -> Result<(&[Token], Expr)> {
+    match args
+        .iter()
+        .position(|token| matches!(token, Token::Word(word) if word == "if"))
+    {
+        Some(index) => Ok((&args[..index], Expr::parse(&args[index + 1..])?)),
+        None => Ok((args, Expr::yes())),
+    }
+}
+
+/// Read the help text starting at `lines[start]`, i.e. all lines indented at least as
+/// far as the first one, and return it along with the index of the line after it.
+fn help_text(lines: &[&str], start: usize) -> (String, usize) {
+    let indentation = |line: &str| {
+        line.chars()
+            .take_while(|c| c.is_whitespace())
+            .fold(0, |column, c| {
+                if c == '\t' {
+                    (column / 8 + 1) * 8
+                } else {
+                    column + 1
+                }
+            })
+    };
+
+    let mut text = Vec::new();
+    let mut min_indentation = None;
+    let mut index = start;
+
+    while index < lines.len() {
+        let line = lines[index];
+
+        if !line.trim().is_empty() {
+            let indentation = indentation(line);
+
+            match min_indentation {
+                None if indentation == 0 => break,
+                None => min_indentation = Some(indentation),
+                Some(min) if indentation < min => break,
+                Some(_) => (),
+            }
+        }
+
+        text.push(line.trim());
+        index += 1;
+    }
+
+    while text.last() == Some(&"") {
+        text.pop();
+    }
+
+    (text.join("\n"), index)
+}
@@ -0,0 +1,570 @@
+//! Validation of configurations against a Kconfig tree.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use super::expr::parse_number;
+use super::tree::{Kconfig, Type};
+use super::{read_config_entries, Tristate, Value, CONFIG_PREFIX};
+
+/// A problem of a configuration found by [`Kconfig::validate`].
+///
+/// Symbol names are without the `CONFIG_` prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// The symbol is not defined in the Kconfig tree.
+    UnknownSymbol { name: String },
+    /// The value is not valid for the type of the symbol.
+    InvalidValue {
+        name: String,
+        typ: Type,
+        value: String,
+    },
+    /// The symbol is set, although its dependencies are not met and it is not selected.
+    UnmetDependencies { name: String, depends_on: String },
+    /// The symbol is not set, although it is selected by another symbol which is set.
+    NotSelected { name: String, selected_by: String },
+    /// The value of an `int` or `hex` symbol is outside of its range.
+    OutOfRange {
+        name: String,
+        value: String,
+        low: String,
+        high: String,
+    },
+    /// More than one symbol of the choice is set.
+    ChoiceConflict { choice: String, names: Vec<String> },
+    /// None of the symbols of the choice is set, although it is not optional.
+    ChoiceNotSet { choice: String },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownSymbol { name } => write!(f, "Unknown symbol {CONFIG_PREFIX}{name}"),
+            Self::InvalidValue { name, typ, value } => {
+                write!(
+                    f,
+                    "Invalid value {value} of {typ} symbol {CONFIG_PREFIX}{name}"
+                )
+            }
+            Self::UnmetDependencies { name, depends_on } => write!(
+                f,
+                "{CONFIG_PREFIX}{name} is set, but its dependencies are not met: {depends_on}"
+            ),
+            Self::NotSelected { name, selected_by } => write!(
+                f,
+                "{CONFIG_PREFIX}{name} is not set, but selected by {CONFIG_PREFIX}{selected_by}"
+            ),
+            Self::OutOfRange {
+                name,
+                value,
+                low,
+                high,
+            } => write!(
+                f,
+                "{CONFIG_PREFIX}{name}={value} is out of the range [{low}, {high}]"
+            ),
+            Self::ChoiceConflict { choice, names } => write!(
+                f,
+                "More than one symbol of choice {choice} is set: {}",
+                names
+                    .iter()
+                    .map(|name| format!("{CONFIG_PREFIX}{name}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::ChoiceNotSet { choice } => write!(f, "No symbol of choice {choice} is set"),
+        }
+    }
+}
+
+/// The new name of a deprecated symbol, as listed in the `sdkconfig.rename` files of
+/// ESP-IDF and its components.
+///
+/// Symbol names are without the `CONFIG_` prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rename {
+    pub old: String,
+    pub new: String,
+    /// Whether the new `bool` symbol is the inverse of the old one.
+    pub inverted: bool,
+}
+
+impl Rename {
+    /// Read the renames of the `sdkconfig.rename` file `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let path = path.as_ref();
+
+        let file =
+            fs::File::open(path).map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+
+        Self::from_reader(file).map_err(|e| anyhow!("Could not read {}: {e}", path.display()))
+    }
+
+    /// Read the renames of an `sdkconfig.rename` file, with lines like
+    /// `CONFIG_OLD CONFIG_NEW` or, for inverted `bool` symbols, `CONFIG_OLD !CONFIG_NEW`.
+    pub fn from_reader(reader: impl Read) -> Result<Vec<Self>> {
+        let mut renames = Vec::new();
+
+        for line in io::BufReader::new(reader).lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (old, new) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [old, new] => (old, new),
+                _ => return Err(anyhow!("Invalid rename `{line}`")),
+            };
+
+            let (new, inverted) = match new.strip_prefix('!') {
+                Some(new) => (new, true),
+                None => (new, false),
+            };
+
+            let strip = |name: &'_ str| name.strip_prefix(CONFIG_PREFIX).unwrap_or(name).to_owned();
+
+            renames.push(Self {
+                old: strip(old),
+                new: strip(new),
+                inverted,
+            });
+        }
+
+        Ok(renames)
+    }
+}
+
+impl Kconfig {
+    /// Check the configuration `config` against the Kconfig tree, e.g. an `sdkconfig`
+    /// before it is passed to the ESP-IDF build.
+    ///
+    /// The values are in the syntax of `.config` files, e.g. `y`, `n`, `"string"`,
+    /// `42` or `0x2a`, and the names may have the `CONFIG_` prefix. Symbols which are
+    /// not in `config` are unset.
+    ///
+    /// Deprecated symbols are reported as unknown, see [`Kconfig::validate_with_renames`].
+    pub fn validate<I, K, V>(&self, config: I) -> Vec<Diagnostic>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.validate_with_renames(config, &[])
+    }
+
+    /// Check the configuration `config` like [`Kconfig::validate`], where the deprecated
+    /// symbols of `renames` are checked as their new symbols, unless those are set too.
+    pub fn validate_with_renames<I, K, V>(&self, config: I, renames: &[Rename]) -> Vec<Diagnostic>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let mut config = config
+            .into_iter()
+            .map(|(name, value)| {
+                let name = name.as_ref();
+
+                (
+                    name.strip_prefix(CONFIG_PREFIX).unwrap_or(name).to_owned(),
+                    value.as_ref().trim().to_owned(),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        for rename in renames {
+            if let Some(value) = config.remove(&rename.old) {
+                let value = match value.as_str() {
+                    "y" if rename.inverted => "n".to_owned(),
+                    "n" if rename.inverted => "y".to_owned(),
+                    _ => value,
+                };
+
+                config.entry(rename.new.clone()).or_insert(value);
+            }
+        }
+
+        let mut diagnostics = Vec::new();
+        let mut values = HashMap::new();
+
+        for (name, raw) in &config {
+            match self.symbol(name).and_then(|symbol| symbol.typ) {
+                Some(typ) => match parse_value(typ, raw) {
+                    Some(value) => {
+                        values.insert(name.as_str(), value);
+                    }
+                    None => diagnostics.push(Diagnostic::InvalidValue {
+                        name: name.clone(),
+                        typ,
+                        value: raw.clone(),
+                    }),
+                },
+                None => diagnostics.push(Diagnostic::UnknownSymbol { name: name.clone() }),
+            }
+        }
+
+        let value = |name: &str| self.value(&values, name);
+
+        let is_set = |name: &str| match value(name) {
+            Some(Value::Tristate(tristate)) => {
+                matches!(tristate, Tristate::True | Tristate::Module)
+            }
+            _ => values.contains_key(name),
+        };
+
+        let selected_by = |name: &str| {
+            self.symbols().find(|symbol| {
+                is_set(&symbol.name)
+                    && symbol.selects.iter().any(|select| {
+                        select.value == name && select.condition.eval(&value) != Tristate::False
+                    })
+            })
+        };
+
+        for symbol in self.symbols() {
+            let name = symbol.name.as_str();
+
+            if is_set(name) {
+                if symbol.depends_on.eval(&value) == Tristate::False && selected_by(name).is_none()
+                {
+                    diagnostics.push(Diagnostic::UnmetDependencies {
+                        name: name.to_owned(),
+                        depends_on: symbol.depends_on.to_string(),
+                    });
+                }
+            } else if matches!(symbol.typ, Some(Type::Bool | Type::Tristate)) {
+                if let Some(selector) = selected_by(name) {
+                    diagnostics.push(Diagnostic::NotSelected {
+                        name: name.to_owned(),
+                        selected_by: selector.name.clone(),
+                    });
+                }
+            }
+
+            let number = match values.get(name) {
+                Some(Value::Int(number)) => Some(*number),
+                Some(Value::Hex(number)) => i64::try_from(*number).ok(),
+                _ => None,
+            };
+
+            let range = symbol
+                .ranges
+                .iter()
+                .find(|range| range.condition.eval(&value) != Tristate::False);
+
+            if let (Some(number), Some(range)) = (number, range) {
+                let (low, high) = (range.value.0.string(&value), range.value.1.string(&value));
+
+                let in_range = match (parse_number(&low), parse_number(&high)) {
+                    (Some(low), Some(high)) => (low..=high).contains(&number),
+                    _ => true,
+                };
+
+                if !in_range {
+                    diagnostics.push(Diagnostic::OutOfRange {
+                        name: name.to_owned(),
+                        value: config[name].clone(),
+                        low,
+                        high,
+                    });
+                }
+            }
+        }
+
+        for choice in self.choices() {
+            if choice.depends_on.eval(&value) == Tristate::False {
+                continue;
+            }
+
+            let names = choice
+                .symbols
+                .iter()
+                .filter(|name| matches!(value(name), Some(Value::Tristate(Tristate::True))))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if names.len() > 1 {
+                diagnostics.push(Diagnostic::ChoiceConflict {
+                    choice: choice.to_string(),
+                    names,
+                });
+            } else if names.is_empty() && !choice.optional {
+                diagnostics.push(Diagnostic::ChoiceNotSet {
+                    choice: choice.to_string(),
+                });
+            }
+        }
+
+        diagnostics
+    }
+
+    /// The value of the symbol `name` in `values` for evaluating expressions, or [`None`]
+    /// if it is not a symbol.
+    pub(super) fn value(&self, values: &HashMap<&str, Value>, name: &str) -> Option<Value> {
+        let typ = self.symbol(name)?.typ?;
+
+        Some(values.get(name).cloned().unwrap_or(match typ {
+            Type::Bool | Type::Tristate => Value::Tristate(Tristate::NotSet),
+            _ => Value::String(String::new()),
+        }))
+    }
+
+    /// Check the configuration in the `.config` file `path`, e.g. an `sdkconfig`, see
+    /// [`Kconfig::validate_with_renames`].
+    pub fn validate_config_file(
+        &self,
+        path: impl AsRef<Path>,
+        renames: &[Rename],
+    ) -> Result<Vec<Diagnostic>> {
+        let path = path.as_ref();
+
+        let file =
+            fs::File::open(path).map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?;
+
+        Ok(self.validate_with_renames(read_config_entries(file)?, renames))
+    }
+}
+
+/// Parse the value of a symbol of type `typ` in the syntax of `.config` files.
+pub(super) fn parse_value(typ: Type, raw: &str) -> Option<Value> {
+    match typ {
+        Type::Bool | Type::Tristate => match raw {
+            "y" => Some(Value::Tristate(Tristate::True)),
+            "n" => Some(Value::Tristate(Tristate::False)),
+            "m" if typ == Type::Tristate => Some(Value::Tristate(Tristate::Module)),
+            _ => None,
+        },
+        Type::String => {
+            let unquoted = raw.strip_prefix('"')?.strip_suffix('"')?;
+
+            let mut string = String::with_capacity(unquoted.len());
+            let mut chars = unquoted.chars();
+
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    string.extend(chars.next());
+                } else {
+                    string.push(c);
+                }
+            }
+
+            Some(Value::String(string))
+        }
+        Type::Int => {
+            let hex = raw
+                .trim_start_matches('-')
+                .get(..2)
+                .map_or(false, |prefix| prefix.eq_ignore_ascii_case("0x"));
+
+            parse_number(raw).filter(|_| !hex).map(Value::Int)
+        }
+        Type::Hex => {
+            let digits = raw.strip_prefix("0x").or_else(|| raw.strip_prefix("0X"))?;
+
+            u64::from_str_radix(digits, 16).ok().map(Value::Hex)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kconfig::expr::Expr;
+
+    const KCONFIG: &str = r#"
+mainmenu "Test"
+
+menu "Features"
+    config FEATURE
+        bool "Feature"
+        default y
+        select LOG
+        help
+            Enables the feature.
+
+            Really.
+
+    config FEATURE_LEVEL
+        int "Level"
+        depends on FEATURE && TARGET != "esp32c3"
+        range 1 10 if !BIG
+        range 1 100
+        default 5
+endmenu
+
+config LOG
+    bool
+
+config BIG
+    bool "Big"
+
+config SMALL_BUFFERS
+    bool "Small buffers"
+    depends on !BIG
+
+config TARGET
+    string
+    default "$IDF_TARGET"
+
+choice MODE
+    prompt "Mode"
+    default MODE_FAST
+
+    config MODE_FAST
+        bool "Fast"
+    config MODE_SLOW
+        bool "Slow"
+endchoice
+
+if BIG
+config BUFFER_ADDR
+    hex "Buffer address"
+    range 0x1000 0x2000
+endif
+"#;
+
+    #[test]
+    fn validate() {
+        let env = [("IDF_TARGET".to_owned(), "esp32".to_owned())]
+            .into_iter()
+            .collect();
+
+        let kconfig = Kconfig::from_source(KCONFIG, "", &env).unwrap();
+
+        assert_eq!(kconfig.mainmenu(), Some("Test"));
+        assert_eq!(
+            kconfig.symbol("FEATURE").unwrap().help.as_deref(),
+            Some("Enables the feature.\n\nReally.")
+        );
+        assert_eq!(
+            kconfig
+                .symbol("FEATURE_LEVEL")
+                .unwrap()
+                .depends_on
+                .to_string(),
+            "FEATURE && TARGET != \"esp32c3\""
+        );
+        assert_eq!(
+            kconfig.symbol("TARGET").unwrap().defaults[0].value,
+            Expr::String("esp32".to_owned())
+        );
+        assert_eq!(
+            kconfig
+                .symbol("BUFFER_ADDR")
+                .unwrap()
+                .depends_on
+                .to_string(),
+            "BIG"
+        );
+        assert_eq!(kconfig.choices()[0].symbols, ["MODE_FAST", "MODE_SLOW"]);
+        assert_eq!(kconfig.symbol("MODE_SLOW").unwrap().choice, Some(0));
+
+        let valid = [
+            ("CONFIG_FEATURE", "y"),
+            ("CONFIG_FEATURE_LEVEL", "7"),
+            ("CONFIG_LOG", "y"),
+            ("CONFIG_TARGET", "\"esp32\""),
+            ("CONFIG_MODE_FAST", "y"),
+            ("CONFIG_MODE_SLOW", "n"),
+        ];
+
+        assert_eq!(kconfig.validate(valid), []);
+
+        let invalid = [
+            ("CONFIG_FEATURE", "y"),
+            ("CONFIG_FEATURE_LEVEL", "170"),
+            ("CONFIG_BIG", "y"),
+            ("CONFIG_BUFFER_ADDR", "0x3000"),
+            ("CONFIG_SMALL_BUFFERS", "y"),
+            ("CONFIG_LOG", "yes"),
+            ("CONFIG_TARGET", "\"esp32\""),
+            ("CONFIG_MODE_FAST", "y"),
+            ("CONFIG_MODE_SLOW", "y"),
+            ("CONFIG_UNKNOWN", "y"),
+        ];
+
+        assert_eq!(
+            kconfig.validate(invalid),
+            [
+                Diagnostic::InvalidValue {
+                    name: "LOG".to_owned(),
+                    typ: Type::Bool,
+                    value: "yes".to_owned(),
+                },
+                Diagnostic::UnknownSymbol {
+                    name: "UNKNOWN".to_owned()
+                },
+                Diagnostic::OutOfRange {
+                    name: "BUFFER_ADDR".to_owned(),
+                    value: "0x3000".to_owned(),
+                    low: "0x1000".to_owned(),
+                    high: "0x2000".to_owned(),
+                },
+                Diagnostic::OutOfRange {
+                    name: "FEATURE_LEVEL".to_owned(),
+                    value: "170".to_owned(),
+                    low: "1".to_owned(),
+                    high: "100".to_owned(),
+                },
+                Diagnostic::NotSelected {
+                    name: "LOG".to_owned(),
+                    selected_by: "FEATURE".to_owned(),
+                },
+                Diagnostic::UnmetDependencies {
+                    name: "SMALL_BUFFERS".to_owned(),
+                    depends_on: "!BIG".to_owned(),
+                },
+                Diagnostic::ChoiceConflict {
+                    choice: "MODE".to_owned(),
+                    names: vec!["MODE_FAST".to_owned(), "MODE_SLOW".to_owned()],
+                },
+            ]
+        );
+
+        let renames = Rename::from_reader(
+            "# Deprecated options\n\nCONFIG_OLD_LEVEL CONFIG_FEATURE_LEVEL\nCONFIG_HUGE  !CONFIG_BIG\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            renames[1],
+            Rename {
+                old: "HUGE".to_owned(),
+                new: "BIG".to_owned(),
+                inverted: true,
+            }
+        );
+
+        let deprecated = [
+            ("CONFIG_FEATURE", "y"),
+            ("CONFIG_OLD_LEVEL", "50"),
+            ("CONFIG_HUGE", "n"),
+            ("CONFIG_LOG", "y"),
+            ("CONFIG_TARGET", "\"esp32\""),
+            ("CONFIG_MODE_FAST", "y"),
+        ];
+
+        assert_eq!(
+            kconfig.validate(deprecated),
+            [
+                Diagnostic::UnknownSymbol {
+                    name: "HUGE".to_owned()
+                },
+                Diagnostic::UnknownSymbol {
+                    name: "OLD_LEVEL".to_owned()
+                },
+            ]
+        );
+        // `HUGE=n` is `BIG=y`, with which the level may be up to 100
+        assert_eq!(kconfig.validate_with_renames(deprecated, &renames), []);
+        assert!(Rename::from_reader("CONFIG_A".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+//! GNU ld map files.
+//!
+//! Parses the map file written by GNU ld with `-Map=<file>` (see
+//! [`LinkArgsBuilder::map_file`](crate::build::LinkArgsBuilder::map_file)), which lists
+//! the memory configuration, every output section with the input sections it is made
+//! of, and the input sections discarded by the linker.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+/// The name of the catch-all memory region listed in every map file.
+pub const DEFAULT_MEMORY_REGION: &str = "*default*";
+
+/// The name of the pseudo input section used for padding.
+pub const FILL: &str = "*fill*";
+
+/// A memory region from the `Memory Configuration` of the map file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub origin: u64,
+    pub length: u64,
+    /// The attributes of the region, e.g. `xr`.
+    pub attributes: String,
+}
+
+impl MemoryRegion {
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.origin && addr - self.origin < self.length
+    }
+}
+
+/// The file an input section comes from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Origin {
+    /// The archive containing [`Origin::object`], if any.
+    pub archive: Option<String>,
+    /// The object file, or the archive member.
+    pub object: String,
+}
+
+impl Origin {
+    /// Parse an input file as written by ld, i.e. `path/to/libfoo.a(bar.o)` or
+    /// `path/to/bar.o`.
+    pub fn parse(file: &str) -> Self {
+        match file.strip_suffix(')').and_then(|file| file.split_once('(')) {
+            Some((archive, object)) if !archive.is_empty() => Self {
+                archive: Some(archive.to_owned()),
+                object: object.to_owned(),
+            },
+            _ => Self {
+                archive: None,
+                object: file.to_owned(),
+            },
+        }
+    }
+
+    /// The name of the library this input comes from: the crate name for Rust libraries
+    /// (`libfoo-0123456789abcdef.rlib` is `foo`), the component name for C archives
+    /// (`libesp_wifi.a` is `esp_wifi`), or the object file name if it is not part of an
+    /// archive.
+    pub fn library(&self) -> String {
+        let file = self.archive.as_deref().unwrap_or(&self.object);
+        let file_name = Path::new(file)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(file);
+
+        if self.archive.is_none() {
+            return file_name.to_owned();
+        }
+
+        let name = file_name.strip_prefix("lib").unwrap_or(file_name);
+
+        if let Some(name) = name.strip_suffix(".rlib") {
+            match name.rsplit_once('-') {
+                Some((name, hash)) if hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    name.to_owned()
+                }
+                _ => name.to_owned(),
+            }
+        } else {
+            name.strip_suffix(".a").unwrap_or(name).to_owned()
+        }
+    }
+}
+
+/// A symbol defined in an input section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+}
+
+/// An input section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputSection {
+    /// The name of the input section, or [`FILL`] for padding.
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+    /// The file the section comes from, [`None`] for padding and linker generated
+    /// sections.
+    pub origin: Option<Origin>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl InputSection {
+    /// Whether this is padding inserted by the linker.
+    pub fn is_fill(&self) -> bool {
+        self.name == FILL
+    }
+}
+
+/// An output section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputSection {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+    /// The load address, if it differs from [`OutputSection::addr`].
+    pub load_addr: Option<u64>,
+    pub input_sections: Vec<InputSection>,
+}
+
+/// A parsed GNU ld map file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkerMap {
+    /// The memory regions, including [`DEFAULT_MEMORY_REGION`].
+    pub memory: Vec<MemoryRegion>,
+    pub output_sections: Vec<OutputSection>,
+    pub discarded: Vec<InputSection>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Other,
+    Discarded,
+    Memory,
+    Map,
+}
+
+impl LinkerMap {
+    /// Parse the map file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let map = fs::read_to_string(path)
+            .with_context(|| anyhow!("Could not read {}", path.display()))?;
+
+        Self::parse(&map).with_context(|| anyhow!("Could not parse map file {}", path.display()))
+    }
+
+    /// Parse the contents of a map file.
+    pub fn parse(map: &str) -> Result<Self> {
+        let mut result = Self::default();
+        let mut part = Part::Other;
+
+        let lines = map.lines().collect::<Vec<_>>();
+        let mut index = 0;
+
+        while index < lines.len() {
+            let line = lines[index].trim_end();
+            index += 1;
+
+            match line {
+                "Discarded input sections" => part = Part::Discarded,
+                "Memory Configuration" => part = Part::Memory,
+                "Linker script and memory map" => part = Part::Map,
+                "Archive member included to satisfy reference by file (symbol)"
+                | "Allocating common symbols"
+                | "Cross Reference Table" => part = Part::Other,
+                _ => {}
+            }
+
+            if line.trim().is_empty() || part == Part::Other {
+                continue;
+            }
+
+            // Long section names are followed by their address and size on the next line
+            let mut columns = line.split_whitespace().collect::<Vec<_>>();
+            if columns.len() == 1
+                && !line.starts_with("  ")
+                && lines.get(index).map_or(false, |next| {
+                    let mut next = next.split_whitespace();
+                    next.next().and_then(parse_hex).is_some()
+                        && next.next().and_then(parse_hex).is_some()
+                })
+            {
+                columns.extend(lines[index].split_whitespace());
+                index += 1;
+            }
+
+            let numbers = (
+                columns.get(1).copied().and_then(parse_hex),
+                columns.get(2).copied().and_then(parse_hex),
+            );
+
+            match part {
+                Part::Memory => {
+                    if let (Some(origin), Some(length)) = numbers {
+                        result.memory.push(MemoryRegion {
+                            name: columns[0].to_owned(),
+                            origin,
+                            length,
+                            attributes: columns.get(3).copied().unwrap_or_default().to_owned(),
+                        });
+                    }
+                }
+                Part::Discarded => {
+                    if let Some(section) = input_section(&columns) {
+                        result.discarded.push(section);
+                    }
+                }
+                Part::Map if !line.starts_with(' ') => {
+                    if let (Some(addr), Some(size)) = numbers {
+                        let load_addr = match columns.get(3..6) {
+                            Some(["load", "address", load_addr]) => parse_hex(load_addr),
+                            _ => None,
+                        };
+
+                        result.output_sections.push(OutputSection {
+                            name: columns[0].to_owned(),
+                            addr,
+                            size,
+                            load_addr,
+                            input_sections: Vec::new(),
+                        });
+                    }
+                }
+                Part::Map => {
+                    let output_section = match result.output_sections.last_mut() {
+                        Some(output_section) => output_section,
+                        None => continue,
+                    };
+
+                    if let Some(section) = input_section(&columns) {
+                        output_section.input_sections.push(section);
+                    } else if let (Some(addr), [_, name]) = (parse_hex(columns[0]), &columns[..]) {
+                        if let Some(section) = output_section.input_sections.last_mut() {
+                            section.symbols.push(Symbol {
+                                name: (*name).to_owned(),
+                                addr,
+                            });
+                        }
+                    }
+                }
+                Part::Other => unreachable!(),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// The memory regions, without [`DEFAULT_MEMORY_REGION`].
+    pub fn memory_regions(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.memory
+            .iter()
+            .filter(|region| region.name != DEFAULT_MEMORY_REGION)
+    }
+
+    /// Get the memory region containing `addr`.
+    pub fn memory_region(&self, addr: u64) -> Option<&MemoryRegion> {
+        self.memory_regions().find(|region| region.contains(addr))
+    }
+
+    /// Get the output section `name`.
+    pub fn output_section(&self, name: &str) -> Option<&OutputSection> {
+        self.output_sections
+            .iter()
+            .find(|section| section.name == name)
+    }
+
+    /// The output sections that occupy target memory.
+    ///
+    /// These are the sections in one of the memory regions, or, if the map has no memory
+    /// regions, the sections with a non-zero address.
+    pub fn allocated_sections(&self) -> impl Iterator<Item = &OutputSection> {
+        let has_regions = self.memory_regions().next().is_some();
+
+        self.output_sections.iter().filter(move |section| {
+            if has_regions {
+                self.memory_region(section.addr).is_some()
+            } else {
+                section.addr != 0
+            }
+        })
+    }
+
+    /// Sum the sizes of the input sections of the allocated output sections by library
+    /// (see [`Origin::library`]), e.g. to attribute flash usage to crates and esp-idf
+    /// components.
+    ///
+    /// Padding and linker generated sections are ignored.
+    pub fn library_sizes(&self) -> BTreeMap<String, u64> {
+        let mut sizes = BTreeMap::new();
+
+        for section in self.allocated_sections() {
+            for input in &section.input_sections {
+                if let Some(origin) = &input.origin {
+                    *sizes.entry(origin.library()).or_default() += input.size;
+                }
+            }
+        }
+
+        sizes
+    }
+}
+
+/// Parse an input section line, i.e. `<name> <addr> <size> [file]`.
+fn input_section(columns: &[&str]) -> Option<InputSection> {
+    let name = *columns.first()?;
+    let addr = parse_hex(columns.get(1)?)?;
+    let size = parse_hex(columns.get(2)?)?;
+
+    let file = columns[3..].join(" ");
+    let origin = if file.is_empty() || name == FILL {
+        None
+    } else {
+        Some(Origin::parse(&file))
+    };
+
+    Some(InputSection {
+        name: name.to_owned(),
+        addr,
+        size,
+        origin,
+        symbols: Vec::new(),
+    })
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.strip_prefix("0x")?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "\
+Archive member included to satisfy reference by file (symbol)
+
+/tmp/libfoo.a(bar.o)          main.o (bar)
+
+Discarded input sections
+
+ .text          0x0000000000000000        0x0 main.o
+ .text.unused_function_with_long_name
+                0x0000000000000000       0x1c /tmp/libfoo.a(bar.o)
+
+Memory Configuration
+
+Name             Origin             Length             Attributes
+FLASH            0x0000000008000000 0x0000000000010000 xr
+RAM              0x0000000020000000 0x0000000000002000 xrw
+*default*        0x0000000000000000 0xffffffffffffffff
+
+Linker script and memory map
+
+LOAD main.o
+                0x0000000008000000                _flash_start = ORIGIN (FLASH)
+
+.text           0x0000000008000000       0x34
+ *(.text .text.*)
+ .text.main     0x0000000008000000       0x20 main.o
+                0x0000000008000000                main
+ .text.bar      0x0000000008000020       0x12 /tmp/libfoo-0123456789abcdef.rlib(foo-0123.foo.1-cgu.0.rcgu.o)
+                0x0000000008000020                bar
+ *fill*         0x0000000008000032        0x2
+
+.data           0x0000000020000000        0x8 load address 0x0000000008000034
+ .data.counter  0x0000000020000000        0x8 /tmp/libesp_wifi.a(wifi.o)
+
+.debug_info     0x0000000000000000      0x100
+ .debug_info    0x0000000000000000      0x100 main.o
+OUTPUT(app.elf elf32-littlearm)
+";
+
+    #[test]
+    fn parse() -> Result<()> {
+        let map = LinkerMap::parse(MAP)?;
+
+        assert_eq!(map.memory.len(), 3);
+        assert_eq!(
+            map.memory_regions()
+                .map(|r| &r.name[..])
+                .collect::<Vec<_>>(),
+            ["FLASH", "RAM"]
+        );
+
+        assert_eq!(map.discarded.len(), 2);
+        assert_eq!(map.discarded[1].size, 0x1c);
+        assert_eq!(
+            map.discarded[1].origin,
+            Some(Origin {
+                archive: Some("/tmp/libfoo.a".into()),
+                object: "bar.o".into()
+            })
+        );
+
+        let text = map.output_section(".text").unwrap();
+        assert_eq!((text.addr, text.size), (0x0800_0000, 0x34));
+        assert_eq!(text.input_sections.len(), 3);
+        assert_eq!(text.input_sections[0].symbols[0].name, "main");
+        assert!(text.input_sections[2].is_fill());
+
+        let data = map.output_section(".data").unwrap();
+        assert_eq!(data.load_addr, Some(0x0800_0034));
+
+        assert_eq!(map.allocated_sections().count(), 2);
+        assert_eq!(
+            map.library_sizes().into_iter().collect::<Vec<_>>(),
+            [
+                ("esp_wifi".to_owned(), 0x8),
+                ("foo".to_owned(), 0x12),
+                ("main.o".to_owned(), 0x20)
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -35,10 +35,17 @@ pub mod symgen;
 #[cfg(feature = "elf")]
 pub mod bingen;
 
+#[cfg(feature = "elf")]
+pub mod esp_image;
+
+#[cfg(feature = "elf")]
+pub mod size;
+
 pub mod build;
 pub mod cargo;
 pub mod cli;
 pub mod cmd;
 pub mod fs;
+pub mod ldmap;
 pub mod python;
 pub mod utils;
@@ -0,0 +1,655 @@
+//! Memory usage of a firmware ELF file.
+//!
+//! Attributes the allocated sections of an ELF file to the memory regions of the
+//! target, as declared in the `MEMORY` command of its linker scripts or supplied
+//! explicitly, and reports how much of each region is used.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use xmas_elf::program::Type;
+use xmas_elf::sections::{ShType, SHF_ALLOC};
+use xmas_elf::ElfFile;
+
+use crate::ldmap::LinkerMap;
+
+pub mod diff;
+
+/// The kind of memory a region is backed by.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RegionKind {
+    /// Instruction RAM.
+    Iram,
+    /// Data RAM.
+    Dram,
+    /// Flash, either memory-mapped or the load image of other regions.
+    Flash,
+    /// RTC memory, retained in deep sleep.
+    Rtc,
+    Other,
+}
+
+impl RegionKind {
+    /// Guess the kind of a region from its name in the linker script, e.g. `iram0_0_seg`,
+    /// `drom0_0_seg` or `FLASH`.
+    pub fn from_region_name(name: &str) -> Self {
+        let name = name.to_ascii_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|p| name.contains(p));
+
+        if has(&["rtc"]) {
+            Self::Rtc
+        } else if has(&["extern", "psram", "spiram"]) {
+            Self::Other
+        } else if has(&["flash", "irom", "drom", "iram0_2", "rom"]) {
+            Self::Flash
+        } else if has(&["iram"]) {
+            Self::Iram
+        } else if has(&["dram", "ram"]) {
+            Self::Dram
+        } else {
+            Self::Other
+        }
+    }
+}
+
+impl Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Iram => "IRAM",
+            Self::Dram => "DRAM",
+            Self::Flash => "Flash",
+            Self::Rtc => "RTC",
+            Self::Other => "Other",
+        })
+    }
+}
+
+/// A memory region of the target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub kind: RegionKind,
+    pub origin: u64,
+    pub length: u64,
+}
+
+impl MemoryRegion {
+    /// Create a region, guessing its kind from `name`.
+    pub fn new(name: impl Into<String>, origin: u64, length: u64) -> Self {
+        let name = name.into();
+
+        Self {
+            kind: RegionKind::from_region_name(&name),
+            name,
+            origin,
+            length,
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.origin + self.length
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.origin && addr < self.end()
+    }
+
+    /// Get the regions listed in the memory configuration of a linker map file.
+    pub fn from_linker_map(map: &LinkerMap) -> Vec<Self> {
+        map.memory_regions()
+            .map(|region| Self::new(&region.name, region.origin, region.length))
+            .collect()
+    }
+
+    /// Parse the regions declared in the `MEMORY` commands of the linker script at `path`.
+    pub fn from_linker_script_file(path: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let path = path.as_ref();
+
+        let script = fs::read_to_string(path)
+            .with_context(|| anyhow!("Could not read {}", path.display()))?;
+
+        Self::from_linker_script(&script)
+            .with_context(|| anyhow!("Could not parse linker script {}", path.display()))
+    }
+
+    /// Parse the regions declared in the `MEMORY` commands of a linker script.
+    ///
+    /// Origins and lengths may be expressions using the arithmetic operators, the `K`
+    /// and `M` suffixes, and `ORIGIN()` and `LENGTH()` of previously declared regions.
+    pub fn from_linker_script(script: &str) -> Result<Vec<Self>> {
+        let tokens = tokenize(&strip_comments(script))?;
+        let mut regions = Vec::new();
+
+        let mut index = 0;
+        while index < tokens.len() {
+            if tokens[index] == Token::Ident("MEMORY".into())
+                && tokens.get(index + 1) == Some(&Token::Punct('{'))
+            {
+                let mut parser = Parser {
+                    tokens: &tokens,
+                    pos: index + 2,
+                    regions: &mut regions,
+                };
+                parser.memory()?;
+                index = parser.pos;
+            } else {
+                index += 1;
+            }
+        }
+
+        Ok(regions)
+    }
+}
+
+/// The usage of a memory region.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionUsage {
+    pub region: MemoryRegion,
+    /// The bytes used by the sections located in this region, or loaded from it.
+    pub used: u64,
+}
+
+impl RegionUsage {
+    /// The bytes left in the region.
+    pub fn free(&self) -> u64 {
+        self.region.length.saturating_sub(self.used)
+    }
+
+    /// The used percentage of the region.
+    pub fn percent(&self) -> f64 {
+        if self.region.length == 0 {
+            0.0
+        } else {
+            self.used as f64 * 100.0 / self.region.length as f64
+        }
+    }
+}
+
+/// An allocated section of the ELF file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SectionUsage {
+    pub name: String,
+    /// The address of the section at runtime.
+    pub addr: u64,
+    /// The address the section is loaded from, if it differs from [`SectionUsage::addr`].
+    pub load_addr: Option<u64>,
+    pub size: u64,
+    /// Whether the section occupies space in the image (it is not `.bss`-like).
+    pub has_data: bool,
+    /// The name of the region containing [`SectionUsage::addr`].
+    pub region: Option<String>,
+    /// The name of the region containing [`SectionUsage::load_addr`].
+    pub load_region: Option<String>,
+}
+
+/// The memory usage of a firmware ELF file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SizeReport {
+    pub regions: Vec<RegionUsage>,
+    /// The allocated sections, sorted by address.
+    pub sections: Vec<SectionUsage>,
+}
+
+impl SizeReport {
+    /// Analyze the ELF file at `path` against `regions`.
+    pub fn from_elf_file(path: impl AsRef<Path>, regions: &[MemoryRegion]) -> Result<Self> {
+        let path = path.as_ref();
+
+        let data = fs::read(path).with_context(|| anyhow!("Could not read {}", path.display()))?;
+
+        Self::from_elf(&data, regions)
+            .with_context(|| anyhow!("Could not analyze {}", path.display()))
+    }
+
+    /// Analyze the ELF file `data` against `regions`.
+    ///
+    /// Every allocated section is attributed to the region containing its address and,
+    /// if it has data that is loaded from a different address (like `.data` copied from
+    /// flash at startup), also to the region containing its load address.
+    pub fn from_elf(data: &[u8], regions: &[MemoryRegion]) -> Result<Self> {
+        let elf = ElfFile::new(data).map_err(|e| anyhow!(e))?;
+
+        let mut report = Self {
+            regions: regions
+                .iter()
+                .map(|region| RegionUsage {
+                    region: region.clone(),
+                    used: 0,
+                })
+                .collect(),
+            sections: Vec::new(),
+        };
+
+        for section in elf.section_iter() {
+            if section.flags() & SHF_ALLOC == 0 || section.size() == 0 {
+                continue;
+            }
+
+            let ty = section.get_type().map_err(|e| anyhow!(e))?;
+            let name = section.get_name(&elf).map_err(|e| anyhow!(e))?;
+            let addr = section.address();
+            let has_data = ty != ShType::NoBits;
+
+            let load_addr = if has_data {
+                load_addr(&elf, addr).filter(|load_addr| *load_addr != addr)
+            } else {
+                None
+            };
+
+            let region = report.add_usage(addr, section.size());
+            let load_region =
+                load_addr.and_then(|load_addr| report.add_usage(load_addr, section.size()));
+
+            report.sections.push(SectionUsage {
+                name: name.to_owned(),
+                addr,
+                load_addr,
+                size: section.size(),
+                has_data,
+                region,
+                load_region,
+            });
+        }
+
+        report.sections.sort_by_key(|section| section.addr);
+
+        Ok(report)
+    }
+
+    /// Get the usage of the region `name`.
+    pub fn region(&self, name: &str) -> Option<&RegionUsage> {
+        self.regions.iter().find(|usage| usage.region.name == name)
+    }
+
+    /// Get the used and total bytes of all regions of `kind`.
+    pub fn kind_usage(&self, kind: RegionKind) -> (u64, u64) {
+        self.regions
+            .iter()
+            .filter(|usage| usage.region.kind == kind)
+            .fold((0, 0), |(used, total), usage| {
+                (used + usage.used, total + usage.region.length)
+            })
+    }
+
+    fn add_usage(&mut self, addr: u64, size: u64) -> Option<String> {
+        let usage = self
+            .regions
+            .iter_mut()
+            .find(|usage| usage.region.contains(addr))?;
+
+        usage.used += size;
+
+        Some(usage.region.name.clone())
+    }
+}
+
+impl Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.regions.is_empty() {
+            writeln!(
+                f,
+                "{:<20} {:<6} {:>10} {:>10} {:>10} {:>8}",
+                "Region", "Kind", "Used", "Free", "Size", "Usage"
+            )?;
+
+            for usage in &self.regions {
+                writeln!(
+                    f,
+                    "{:<20} {:<6} {:>10} {:>10} {:>10} {:>7.2}%",
+                    usage.region.name,
+                    usage.region.kind,
+                    usage.used,
+                    usage.free(),
+                    usage.region.length,
+                    usage.percent()
+                )?;
+            }
+
+            writeln!(f)?;
+        }
+
+        writeln!(
+            f,
+            "{:<28} {:>10} {:>10} {:<20}",
+            "Section", "Address", "Size", "Region"
+        )?;
+
+        for section in &self.sections {
+            let region = match (&section.region, &section.load_region) {
+                (Some(region), Some(load_region)) => format!("{region} (load: {load_region})"),
+                (Some(region), None) => region.clone(),
+                (None, _) => "-".to_owned(),
+            };
+
+            writeln!(
+                f,
+                "{:<28} {:>#10x} {:>10} {:<20}",
+                section.name, section.addr, section.size, region
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the load address of `addr` from the LOAD segment containing it.
+fn load_addr(elf: &ElfFile<'_>, addr: u64) -> Option<u64> {
+    elf.program_iter()
+        .filter(|header| header.get_type() == Ok(Type::Load))
+        .find(|header| {
+            addr >= header.virtual_addr() && addr < header.virtual_addr() + header.mem_size()
+        })
+        .map(|header| header.physical_addr() + (addr - header.virtual_addr()))
+}
+
+fn strip_comments(script: &str) -> String {
+    let mut result = String::with_capacity(script.len());
+    let mut rest = script;
+
+    while let Some(start) = rest.find("/*") {
+        result.push_str(&rest[..start]);
+        rest = rest[start + 2..]
+            .find("*/")
+            .map(|end| &rest[start + 2 + end + 2..])
+            .unwrap_or("");
+        result.push(' ');
+    }
+    result.push_str(rest);
+
+    result
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(u64),
+    Punct(char),
+}
+
+fn tokenize(script: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = script.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let mut end = start + c.len_utf8();
+        let mut take_while = |pred: fn(char) -> bool| {
+            while let Some((index, c)) = chars.peek().copied() {
+                if !pred(c) {
+                    break;
+                }
+                end = index + c.len_utf8();
+                chars.next();
+            }
+            end
+        };
+
+        if c.is_ascii_digit() {
+            let end = take_while(|c| c.is_ascii_alphanumeric());
+            tokens.push(Token::Number(parse_number(&script[start..end])?));
+        } else if c.is_alphabetic() || matches!(c, '_' | '.' | '$') {
+            let end = take_while(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '$'));
+            tokens.push(Token::Ident(script[start..end].to_owned()));
+        } else {
+            tokens.push(Token::Punct(c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(number: &str) -> Result<u64> {
+    let (digits, multiplier) = match number.as_bytes().last() {
+        Some(b'K' | b'k') => (&number[..number.len() - 1], 1 << 10),
+        Some(b'M' | b'm') => (&number[..number.len() - 1], 1 << 20),
+        _ => (number, 1),
+    };
+
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .with_context(|| anyhow!("Invalid number '{number}'"))?;
+
+    Ok(value * multiplier)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    regions: &'a mut Vec<MemoryRegion>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<&Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| anyhow!("Unexpected end of linker script"))?;
+        self.pos += 1;
+
+        Ok(token)
+    }
+
+    fn expect(&mut self, punct: char) -> Result<()> {
+        match self.next()? {
+            Token::Punct(c) if *c == punct => Ok(()),
+            token => bail!("Expected '{punct}', found {token:?}"),
+        }
+    }
+
+    fn is_punct(&self, punct: char) -> bool {
+        self.peek() == Some(&Token::Punct(punct))
+    }
+
+    /// Parse the body of a `MEMORY` command, after its opening brace.
+    fn memory(&mut self) -> Result<()> {
+        loop {
+            let name = match self.next()? {
+                Token::Punct('}') => return Ok(()),
+                Token::Ident(name) => name.clone(),
+                token => bail!("Expected a memory region name, found {token:?}"),
+            };
+
+            if self.is_punct('(') {
+                while !self.is_punct(')') {
+                    self.next()?;
+                }
+                self.next()?;
+            }
+
+            self.expect(':')?;
+
+            let origin = self
+                .attribute(&["ORIGIN", "org", "o"])
+                .with_context(|| anyhow!("Invalid origin of memory region '{name}'"))?;
+            if self.is_punct(',') {
+                self.next()?;
+            }
+            let length = self
+                .attribute(&["LENGTH", "len", "l"])
+                .with_context(|| anyhow!("Invalid length of memory region '{name}'"))?;
+            if self.is_punct(',') {
+                self.next()?;
+            }
+
+            self.regions.push(MemoryRegion::new(name, origin, length));
+        }
+    }
+
+    fn attribute(&mut self, names: &[&str]) -> Result<u64> {
+        match self.next()? {
+            Token::Ident(name) if names.contains(&name.as_str()) => (),
+            token => bail!("Expected '{}', found {token:?}", names[0]),
+        }
+
+        self.expect('=')?;
+        self.expr()
+    }
+
+    fn expr(&mut self) -> Result<u64> {
+        let mut value = self.term()?;
+
+        loop {
+            if self.is_punct('+') {
+                self.next()?;
+                value = value.wrapping_add(self.term()?);
+            } else if self.is_punct('-') {
+                self.next()?;
+                value = value.wrapping_sub(self.term()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<u64> {
+        let mut value = self.factor()?;
+
+        loop {
+            if self.is_punct('*') {
+                self.next()?;
+                value = value.wrapping_mul(self.factor()?);
+            } else if self.is_punct('/') {
+                self.next()?;
+                let divisor = self.factor()?;
+                ensure!(divisor != 0, "Division by zero");
+                value /= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<u64> {
+        match self.next()?.clone() {
+            Token::Number(value) => Ok(value),
+            Token::Punct('(') => {
+                let value = self.expr()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Token::Punct('-') => Ok(self.factor()?.wrapping_neg()),
+            Token::Ident(function) if function == "ORIGIN" || function == "LENGTH" => {
+                self.expect('(')?;
+                let name = match self.next()? {
+                    Token::Ident(name) => name.clone(),
+                    token => bail!("Expected a memory region name, found {token:?}"),
+                };
+                self.expect(')')?;
+
+                let region = self
+                    .regions
+                    .iter()
+                    .find(|region| region.name == name)
+                    .ok_or_else(|| anyhow!("Unknown memory region '{name}'"))?;
+
+                Ok(if function == "ORIGIN" {
+                    region.origin
+                } else {
+                    region.length
+                })
+            }
+            token => bail!("Unsupported expression {token:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRMWARE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/size/testdata/firmware");
+
+    #[test]
+    fn linker_script() -> Result<()> {
+        let script = "
+            /* esp-idf style */
+            MEMORY
+            {
+              iram0_0_seg (RX) : org = 0x40080000, len = 0x20000
+              iram0_2_seg (RX) : org = 0x400D0020, len = 0x330000-0x20
+              dram0_0_seg (RW) : org = 0x3FFB0000 + 0x100, len = (0x2c200 - 0x100)
+              rtc_slow_seg(RW) : org = 0x50000000, len = 8K
+              FLASH : ORIGIN = ORIGIN(iram0_2_seg), LENGTH = 4M
+            }
+
+            SECTIONS {}
+        ";
+
+        let regions = MemoryRegion::from_linker_script(script)?;
+
+        assert_eq!(
+            regions
+                .iter()
+                .map(|r| (r.name.as_str(), r.kind, r.origin, r.length))
+                .collect::<Vec<_>>(),
+            [
+                ("iram0_0_seg", RegionKind::Iram, 0x4008_0000, 0x20000),
+                ("iram0_2_seg", RegionKind::Flash, 0x400d_0020, 0x32_ffe0),
+                ("dram0_0_seg", RegionKind::Dram, 0x3ffb_0100, 0x2c100),
+                ("rtc_slow_seg", RegionKind::Rtc, 0x5000_0000, 0x2000),
+                ("FLASH", RegionKind::Flash, 0x400d_0020, 0x40_0000),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn elf() -> Result<()> {
+        let regions = MemoryRegion::from_linker_script_file(format!("{FIRMWARE}.ld"))?;
+        let report = SizeReport::from_elf_file(format!("{FIRMWARE}.elf"), &regions)?;
+
+        assert_eq!(
+            report
+                .sections
+                .iter()
+                .map(|s| (
+                    s.name.as_str(),
+                    s.addr,
+                    s.load_addr,
+                    s.size,
+                    s.has_data,
+                    s.region.as_deref(),
+                    s.load_region.as_deref()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (".text", 0x0800_0000, None, 0x1c, true, Some("FLASH"), None),
+                (
+                    ".data",
+                    0x2000_0000,
+                    Some(0x0800_001c),
+                    0x4,
+                    true,
+                    Some("RAM"),
+                    Some("FLASH")
+                ),
+                (".bss", 0x2000_0020, None, 0x100, false, Some("RAM"), None),
+            ]
+        );
+
+        // `.data` uses both the RAM and the flash it is loaded from
+        assert_eq!(report.region("FLASH").unwrap().used, 0x20);
+        assert_eq!(report.region("RAM").unwrap().used, 0x104);
+
+        assert_eq!(report.kind_usage(RegionKind::Flash), (0x20, 0x1_0000));
+        assert_eq!(report.kind_usage(RegionKind::Dram), (0x104, 0x4000));
+        assert_eq!(report.kind_usage(RegionKind::Iram), (0, 0));
+
+        Ok(())
+    }
+}
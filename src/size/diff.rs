@@ -0,0 +1,322 @@
+//! Size regressions between two builds of a firmware.
+//!
+//! Compares the memory usage of two ELF files (and optionally their linker map files)
+//! per memory region, section, symbol and library, and checks the deltas against
+//! configurable thresholds.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use xmas_elf::sections::{SectionData, ShType};
+use xmas_elf::symbol_table::{self, Entry};
+use xmas_elf::ElfFile;
+
+use super::{MemoryRegion, SizeReport};
+use crate::ldmap::LinkerMap;
+
+/// The sizes of one build of a firmware.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub report: SizeReport,
+    /// The sizes of all function and object symbols, by name.
+    pub symbols: BTreeMap<String, u64>,
+    /// The sizes by library (see [`crate::ldmap::Origin::library`]), if a map file was
+    /// supplied.
+    pub libraries: Option<BTreeMap<String, u64>>,
+}
+
+impl Snapshot {
+    /// Load the sizes of the ELF file at `elf` and of its optional linker `map_file`,
+    /// using `regions` as the memory regions of the target.
+    pub fn from_files(
+        elf: impl AsRef<Path>,
+        map_file: Option<&Path>,
+        regions: &[MemoryRegion],
+    ) -> Result<Self> {
+        let elf = elf.as_ref();
+
+        let data = fs::read(elf).with_context(|| anyhow!("Could not read {}", elf.display()))?;
+        let map = map_file.map(LinkerMap::from_file).transpose()?;
+
+        Self::new(&data, map.as_ref(), regions)
+            .with_context(|| anyhow!("Could not analyze {}", elf.display()))
+    }
+
+    /// Load the sizes of the ELF file `data` and of its optional linker `map`.
+    pub fn new(data: &[u8], map: Option<&LinkerMap>, regions: &[MemoryRegion]) -> Result<Self> {
+        let report = SizeReport::from_elf(data, regions)?;
+
+        let elf = ElfFile::new(data).map_err(|e| anyhow!(e))?;
+        let mut symbols = BTreeMap::new();
+
+        for header in elf.section_iter() {
+            if header.get_type() != Ok(ShType::SymTab) {
+                continue;
+            }
+
+            match header.get_data(&elf).map_err(|e| anyhow!(e))? {
+                SectionData::SymbolTable32(entries) => add_symbols(&elf, entries, &mut symbols)?,
+                SectionData::SymbolTable64(entries) => add_symbols(&elf, entries, &mut symbols)?,
+                _ => bail!("Invalid symbol table"),
+            }
+        }
+
+        Ok(Self {
+            report,
+            symbols,
+            libraries: map.map(LinkerMap::library_sizes),
+        })
+    }
+}
+
+fn add_symbols<'a>(
+    elf: &ElfFile<'a>,
+    entries: &'a [impl Entry],
+    symbols: &mut BTreeMap<String, u64>,
+) -> Result<()> {
+    for entry in entries {
+        let ty = entry.get_type().map_err(|e| anyhow!(e))?;
+
+        if entry.size() == 0
+            || entry.shndx() == 0
+            || !matches!(ty, symbol_table::Type::Func | symbol_table::Type::Object)
+        {
+            continue;
+        }
+
+        let name = entry.get_name(elf).map_err(|e| anyhow!(e))?;
+
+        // Local symbols of different objects may share a name
+        *symbols.entry(name.to_owned()).or_default() += entry.size();
+    }
+
+    Ok(())
+}
+
+/// The size of an item in the old and the new build.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delta {
+    pub name: String,
+    pub old: u64,
+    pub new: u64,
+}
+
+impl Delta {
+    /// The growth in bytes, negative if the item shrank.
+    pub fn delta(&self) -> i64 {
+        self.new as i64 - self.old as i64
+    }
+}
+
+/// The maximum growth in bytes allowed by [`SizeDiff::check`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Thresholds {
+    pub region: Option<u64>,
+    pub section: Option<u64>,
+    pub symbol: Option<u64>,
+    pub library: Option<u64>,
+}
+
+/// The changed sizes between two builds, sorted by decreasing absolute delta.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeDiff {
+    pub regions: Vec<Delta>,
+    pub sections: Vec<Delta>,
+    pub symbols: Vec<Delta>,
+    /// Empty if neither snapshot has library sizes.
+    pub libraries: Vec<Delta>,
+}
+
+impl SizeDiff {
+    /// Compare the `old` and the `new` snapshot.
+    ///
+    /// Either both or neither snapshot must have library sizes, i.e. have been loaded
+    /// with a map file.
+    pub fn new(old: &Snapshot, new: &Snapshot) -> Result<Self> {
+        let regions = |snapshot: &Snapshot| {
+            snapshot
+                .report
+                .regions
+                .iter()
+                .map(|usage| (usage.region.name.clone(), usage.used))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let sections = |snapshot: &Snapshot| {
+            let mut sections = BTreeMap::new();
+            for section in &snapshot.report.sections {
+                *sections.entry(section.name.clone()).or_default() += section.size;
+            }
+            sections
+        };
+
+        let libraries = match (&old.libraries, &new.libraries) {
+            (Some(old), Some(new)) => deltas(old, new),
+            (None, None) => Vec::new(),
+            (Some(_), None) | (None, Some(_)) => bail!(
+                "Library sizes can only be compared if the map files of both builds are supplied"
+            ),
+        };
+
+        Ok(Self {
+            regions: deltas(&regions(old), &regions(new)),
+            sections: deltas(&sections(old), &sections(new)),
+            symbols: deltas(&old.symbols, &new.symbols),
+            libraries,
+        })
+    }
+
+    /// Check that no item grew by more than its threshold.
+    ///
+    /// The error lists all items exceeding their threshold.
+    pub fn check(&self, thresholds: &Thresholds) -> Result<()> {
+        let mut exceeded = Vec::new();
+
+        for (kind, deltas, threshold) in [
+            ("Region", &self.regions, thresholds.region),
+            ("Section", &self.sections, thresholds.section),
+            ("Symbol", &self.symbols, thresholds.symbol),
+            ("Library", &self.libraries, thresholds.library),
+        ] {
+            let threshold = match threshold {
+                Some(threshold) => threshold as i64,
+                None => continue,
+            };
+
+            for delta in deltas.iter().filter(|delta| delta.delta() > threshold) {
+                exceeded.push(format!(
+                    "{kind} '{}' grew by {} bytes (threshold: {threshold} bytes)",
+                    delta.name,
+                    delta.delta()
+                ));
+            }
+        }
+
+        if !exceeded.is_empty() {
+            bail!("Size thresholds exceeded:\n{}", exceeded.join("\n"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for SizeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut empty = true;
+
+        for (title, deltas) in [
+            ("Region", &self.regions),
+            ("Section", &self.sections),
+            ("Library", &self.libraries),
+            ("Symbol", &self.symbols),
+        ] {
+            if deltas.is_empty() {
+                continue;
+            }
+
+            if !empty {
+                writeln!(f)?;
+            }
+            empty = false;
+
+            writeln!(
+                f,
+                "{:<40} {:>10} {:>10} {:>10}",
+                title, "Old", "New", "Delta"
+            )?;
+
+            for delta in deltas {
+                writeln!(
+                    f,
+                    "{:<40} {:>10} {:>10} {:>+10}",
+                    delta.name,
+                    delta.old,
+                    delta.new,
+                    delta.delta()
+                )?;
+            }
+        }
+
+        if empty {
+            writeln!(f, "No size changes")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn deltas(old: &BTreeMap<String, u64>, new: &BTreeMap<String, u64>) -> Vec<Delta> {
+    let mut deltas = old
+        .keys()
+        .chain(new.keys().filter(|name| !old.contains_key(*name)))
+        .map(|name| Delta {
+            name: name.clone(),
+            old: old.get(name).copied().unwrap_or(0),
+            new: new.get(name).copied().unwrap_or(0),
+        })
+        .filter(|delta| delta.old != delta.new)
+        .collect::<Vec<_>>();
+
+    deltas.sort_by(|a, b| {
+        b.delta()
+            .unsigned_abs()
+            .cmp(&a.delta().unsigned_abs())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    deltas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let old = Snapshot {
+            symbols: [("a", 10), ("b", 20), ("c", 30)]
+                .into_iter()
+                .map(|(name, size)| (name.to_owned(), size))
+                .collect(),
+            ..Default::default()
+        };
+        let new = Snapshot {
+            symbols: [("a", 10), ("b", 12), ("d", 50)]
+                .into_iter()
+                .map(|(name, size)| (name.to_owned(), size))
+                .collect(),
+            ..Default::default()
+        };
+
+        let diff = SizeDiff::new(&old, &new).unwrap();
+        assert_eq!(
+            diff.symbols
+                .iter()
+                .map(|delta| (&delta.name[..], delta.delta()))
+                .collect::<Vec<_>>(),
+            [("d", 50), ("c", -30), ("b", -8)]
+        );
+
+        assert!(diff
+            .check(&Thresholds {
+                symbol: Some(50),
+                ..Default::default()
+            })
+            .is_ok());
+        assert!(diff
+            .check(&Thresholds {
+                symbol: Some(49),
+                ..Default::default()
+            })
+            .is_err());
+
+        let new = Snapshot {
+            libraries: Some(BTreeMap::new()),
+            ..new
+        };
+        assert!(SizeDiff::new(&old, &new).is_err());
+    }
+}
@@ -0,0 +1,12 @@
+/* Test input of the size report, built with:
+ * gcc -Os -static -nostdlib -fno-pie -no-pie -fno-asynchronous-unwind-tables
+ *     -Wl,-T,firmware.ld -Wl,--build-id=none -o firmware.elf firmware.c
+ */
+
+const char message[] = "hello";
+int counter = 1;
+int buffer[64];
+
+void reset(void) {
+    buffer[counter] = message[counter];
+}
@@ -1,13 +1,21 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{env, fmt};
 
-use anyhow::{Error, Result};
-use xmas_elf::sections::{SectionData, ShType};
+use anyhow::{anyhow, Error, Result};
+use serde::Serialize;
+use xmas_elf::sections::{SectionData, ShType, SHF_EXECINSTR};
 use xmas_elf::symbol_table::{Binding, Visibility};
 use xmas_elf::{symbol_table, ElfFile};
 
+use self::dwarf::DwarfTypes;
+
+mod dwarf;
+mod names;
+
 pub const VAR_SYMBOLS_FILE: &str = "EMBUILD_GENERATED_SYMBOLS_FILE";
 
 #[derive(Debug)]
@@ -16,6 +24,10 @@ pub struct Symbol<'a> {
     section_name: Option<&'a str>,
     visible: bool,
     global: bool,
+    function: bool,
+    executable: bool,
+    rename: Option<&'a str>,
+    demangle: bool,
 }
 
 #[derive(Debug)]
@@ -64,15 +76,52 @@ impl<'a> Symbol<'a> {
         self.global
     }
 
+    /// Whether the symbol is a function, see [`Symgen::functions`].
+    pub fn function(&self) -> bool {
+        self.function
+    }
+
+    /// Whether the symbol's section contains executable code.
+    pub fn executable(&self) -> bool {
+        self.executable
+    }
+
+    /// Get the symbol's name, demangled if it is a Rust or C++ symbol.
+    pub fn demangled_name(&self) -> Cow<'a, str> {
+        names::demangle(self.name)
+    }
+
+    /// Get the symbol's name as a Rust identifier.
+    ///
+    /// This is its new name if it was renamed with [`Symgen::rename`]. Otherwise, with
+    /// [`Symgen::demangle`], it is its demangled name with all characters that are not
+    /// valid in an identifier replaced with `_`, e.g. `ns_Foo_bar` for `_ZN2ns3Foo3barEv`
+    /// and `foo_1234` for `foo.1234`, and else its name, which might not be valid.
+    pub fn identifier(&self) -> String {
+        match self.rename {
+            Some(rename) => rename.to_owned(),
+            None if self.demangle => names::sanitize(&self.demangled_name()),
+            None => self.name.to_owned(),
+        }
+    }
+
+    /// Select the global and visible symbols of a section.
+    ///
+    /// Without [`Symgen::demangle`], symbols whose names are not valid identifiers are
+    /// skipped, unless they were renamed.
     pub fn default_pointer_gen(&self) -> Option<RustPointer> {
         if self.section_name().is_some() && self.global() && self.visible() {
-            let valid_identifier = self.name().char_indices().all(|(index, ch)| {
-                ch == '_' || index == 0 && ch.is_alphabetic() || index > 0 && ch.is_alphanumeric()
-            });
+            let valid_identifier = self.rename.is_some()
+                || self.demangle
+                || self.name().char_indices().all(|(index, ch)| {
+                    ch == '_'
+                        || index == 0 && ch.is_alphabetic()
+                        || index > 0 && ch.is_alphanumeric()
+                });
 
             if valid_identifier {
                 return Some(RustPointer {
-                    name: self.name().to_owned(),
+                    name: self.identifier(),
                     mutable: true,
                     r#type: None,
                 });
@@ -82,8 +131,18 @@ impl<'a> Symbol<'a> {
         None
     }
 
+    /// Select the variables in `.bss` and `.data` and, if they are exported (see
+    /// [`Symgen::functions`]), the functions in executable sections.
     pub fn default_sections(&self) -> Option<RustPointer> {
-        self.sections(&[Section::data(".bss"), Section::data(".data")])
+        if self.function() {
+            if self.executable() {
+                self.default_pointer_gen()
+            } else {
+                None
+            }
+        } else {
+            self.sections(&[Section::data(".bss"), Section::data(".data")])
+        }
     }
 
     pub fn sections<'b>(
@@ -105,10 +164,79 @@ impl<'a> Symbol<'a> {
     }
 }
 
-#[derive(Debug, Clone)]
+/// How [`Symgen`] exports function symbols.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FunctionSymbols {
+    /// Skip function symbols.
+    Skip,
+    /// Functions returning the function pointer, e.g.
+    /// `pub fn foo() -> unsafe extern "C" fn(u32)`.
+    ///
+    /// These are functions rather than constants, as a function pointer cannot be
+    /// created from an address in a constant expression.
+    Pointers,
+    /// Declarations in an `extern "C"` block, e.g. `pub fn foo(arg0: u32);`, linked to
+    /// the name of the symbol in the ELF file.
+    ///
+    /// The addresses of these functions must be provided to the linker, e.g. with the
+    /// linker script generated by the same [`Symgen`] with [`Format::LinkerScript`].
+    ///
+    /// The block is not marked `unsafe`, which requires Rust 1.82, so it can only be
+    /// included by crates of the 2021 edition or earlier.
+    Extern,
+}
+
+impl Default for FunctionSymbols {
+    fn default() -> Self {
+        Self::Skip
+    }
+}
+
+/// The format of the file generated by [`Symgen`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Rust constants with the addresses of the symbols, see [`RustPointer`].
+    Rust,
+    /// A GNU ld script with a `PROVIDE(symbol = 0x...);` statement per symbol, for
+    /// linking an image against the symbols of another image.
+    ///
+    /// The symbols keep their names in the ELF file, as the linker resolves references
+    /// to those rather than to their [`RustPointer::name`].
+    LinkerScript,
+    /// A JSON manifest with the name, address, size and type of each symbol.
+    Json,
+}
+
+impl Format {
+    /// The extension of the generated file.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Rust => "rs",
+            Self::LinkerScript => "ld",
+            Self::Json => "json",
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::Rust
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct RustPointer {
     pub name: String,
     pub mutable: bool,
+    /// The pointee type, `core::ffi::c_void` if `None`.
+    ///
+    /// If set (and not an array type), a `{name}_LEN` constant and a `{name}_slice()`
+    /// function are generated for the symbol, treating it as an array of this type.
+    /// Types inferred with [`Symgen::dwarf_types`] are the type of the whole variable,
+    /// with the length of arrays, so they do not get these.
+    ///
+    /// For function symbols this is the function pointer type instead, e.g.
+    /// `unsafe extern "C" fn(u32) -> i32`, and `unsafe extern "C" fn()` if `None`.
     pub r#type: Option<String>,
 }
 
@@ -117,6 +245,11 @@ pub struct Symgen {
     elf: PathBuf,
     start_addr: u64,
     rust_pointer_gen: Box<dyn for<'a> Fn(&Symbol<'a>) -> Option<RustPointer>>,
+    dwarf_types: bool,
+    functions: FunctionSymbols,
+    format: Format,
+    renames: HashMap<String, String>,
+    demangle: bool,
 }
 
 impl Symgen {
@@ -133,11 +266,88 @@ impl Symgen {
             elf: elf.into(),
             start_addr,
             rust_pointer_gen: Box::new(rust_pointer_gen),
+            dwarf_types: false,
+            functions: FunctionSymbols::default(),
+            format: Format::default(),
+            renames: HashMap::new(),
+            demangle: false,
         }
     }
 
+    /// Infer the types of the pointers from the DWARF debug info of the ELF file
+    /// (defaults to `false`).
+    ///
+    /// Pointers without a type set by the `rust_pointer_gen` get the Rust equivalent of
+    /// the variable's C type, e.g. `u32`, `[u8; 16]` or a struct, for which a
+    /// `#[repr(C)]` definition is generated. Variables whose type cannot be represented
+    /// keep the `core::ffi::c_void` type.
+    ///
+    /// Function symbols get a function pointer type with the parameter and return
+    /// types of the function.
+    #[must_use]
+    pub fn dwarf_types(mut self, dwarf_types: bool) -> Self {
+        self.dwarf_types = dwarf_types;
+        self
+    }
+
+    /// Export the function symbols of the ELF file (defaults to
+    /// [`FunctionSymbols::Skip`]).
+    ///
+    /// This allows calling functions of a separately linked image, e.g. the ROM, a
+    /// bootloader or the firmware of another CPU. The `rust_pointer_gen` gets the
+    /// function symbols too (see [`Symbol::function`]), and the default one selects
+    /// those in executable sections (see [`Symbol::default_sections`]).
+    #[must_use]
+    pub fn functions(mut self, functions: FunctionSymbols) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    /// Name the symbol `symbol` of the ELF file `name` instead of its default name (see
+    /// [`Symbol::identifier`]).
+    #[must_use]
+    pub fn rename(mut self, symbol: impl Into<String>, name: impl Into<String>) -> Self {
+        self.renames.insert(symbol.into(), name.into());
+        self
+    }
+
+    /// Name the symbols after their demangled names, turned into valid identifiers
+    /// (defaults to `false`), see [`Symbol::identifier`].
+    ///
+    /// Symbols which end up with the same name are suffixed with `_1`, `_2`, ...
+    #[must_use]
+    pub fn demangle(mut self, demangle: bool) -> Self {
+        self.demangle = demangle;
+        self
+    }
+
+    /// Rename multiple symbols, see [`Symgen::rename`].
+    #[must_use]
+    pub fn renames(
+        mut self,
+        renames: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) -> Self {
+        self.renames.extend(
+            renames
+                .into_iter()
+                .map(|(symbol, name)| (symbol.into(), name.into())),
+        );
+        self
+    }
+
+    /// Set the format of the generated file (defaults to [`Format::Rust`]).
+    ///
+    /// All formats contain the same symbols, as selected by the `rust_pointer_gen`.
+    #[must_use]
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
     pub fn run(&self) -> Result<PathBuf> {
-        let output_file = PathBuf::from(env::var("OUT_DIR")?).join("symbols.rs");
+        let output_file = PathBuf::from(env::var("OUT_DIR")?)
+            .join("symbols")
+            .with_extension(self.format.extension());
 
         self.run_for_file(&output_file)?;
 
@@ -164,40 +374,123 @@ impl Symgen {
         let elf_data = fs::read(&self.elf)?;
         let elf = ElfFile::new(&elf_data).map_err(Error::msg)?;
 
+        let types = if self.dwarf_types {
+            Some(DwarfTypes::load(&elf)?)
+        } else {
+            None
+        };
+        let mut structs = BTreeSet::new();
+        let mut symbols = Vec::new();
+
         for symtable in self.get_symtables(&elf) {
             match symtable.1 {
-                SectionData::SymbolTable32(entries) => {
-                    self.write_symbols(&elf, symtable.0, entries.iter().enumerate(), output)?
+                SectionData::SymbolTable32(entries) => self.collect_symbols(
+                    &elf,
+                    symtable.0,
+                    entries.iter().enumerate(),
+                    types.as_ref(),
+                    &mut structs,
+                    &mut symbols,
+                )?,
+                SectionData::SymbolTable64(entries) => self.collect_symbols(
+                    &elf,
+                    symtable.0,
+                    entries.iter().enumerate(),
+                    types.as_ref(),
+                    &mut structs,
+                    &mut symbols,
+                )?,
+                _ => unimplemented!(),
+            }
+        }
+
+        resolve_collisions(&mut symbols);
+
+        match self.format {
+            Format::Rust => self.write_rust(&symbols, types.as_ref(), &structs, output),
+            Format::LinkerScript => {
+                for symbol in &symbols {
+                    writeln!(output, "PROVIDE({} = 0x{:x});", symbol.symbol, symbol.addr)?;
                 }
-                SectionData::SymbolTable64(entries) => {
-                    self.write_symbols(&elf, symtable.0, entries.iter().enumerate(), output)?
+
+                Ok(())
+            }
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut *output, &Manifest { symbols })?;
+                writeln!(output)?;
+
+                Ok(())
+            }
+        }
+    }
+
+    fn write_rust(
+        &self,
+        symbols: &[GeneratedSymbol],
+        types: Option<&DwarfTypes>,
+        structs: &BTreeSet<String>,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        let (externs, symbols): (Vec<_>, Vec<_>) = symbols
+            .iter()
+            .partition(|symbol| symbol.function && self.functions == FunctionSymbols::Extern);
+
+        for symbol in symbols {
+            symbol.write(output)?;
+        }
+
+        if !externs.is_empty() {
+            write!(
+                output,
+                "#[allow(dead_code, non_snake_case)]\nextern \"C\" {{\n"
+            )?;
+
+            for symbol in externs {
+                if symbol.pointer.name != symbol.symbol {
+                    writeln!(output, "    #[link_name = \"{}\"]", symbol.symbol)?;
                 }
-                _ => unimplemented!(),
+
+                writeln!(
+                    output,
+                    "    {}",
+                    extern_declaration(&symbol.pointer.name, symbol.typ())?
+                )?;
             }
+
+            writeln!(output, "}}")?;
+        }
+
+        if let Some(types) = types {
+            types.write_structs(structs, output)?;
         }
 
         Ok(())
     }
 
-    fn write_symbols<'a, W: Write>(
+    fn collect_symbols<'a>(
         &self,
         elf: &'a ElfFile<'a>,
         symtable_index: usize,
         symbols: impl Iterator<Item = (usize, &'a (impl symbol_table::Entry + fmt::Debug + 'a))>,
-        output: &mut W,
+        types: Option<&DwarfTypes>,
+        structs: &mut BTreeSet<String>,
+        output: &mut Vec<GeneratedSymbol>,
     ) -> Result<()> {
         for (_index, sym) in symbols {
             eprintln!("Found symbol: {sym:?}");
 
             let sym_type = sym.get_type().map_err(Error::msg)?;
+            let function = sym_type == symbol_table::Type::Func;
 
-            if sym_type == symbol_table::Type::Object || sym_type == symbol_table::Type::NoType {
+            if sym_type == symbol_table::Type::Object
+                || sym_type == symbol_table::Type::NoType
+                || function && self.functions != FunctionSymbols::Skip
+            {
                 let name = sym.get_name(elf).map_err(Error::msg)?;
 
-                let section_name = sym
-                    .get_section_header(elf, symtable_index)
-                    .and_then(|sh| sh.get_name(elf))
-                    .ok();
+                let section = sym.get_section_header(elf, symtable_index).ok();
+                let section_name = section.and_then(|sh| sh.get_name(elf).ok());
+                let executable = section.map_or(false, |sh| sh.flags() & SHF_EXECINSTR != 0);
 
                 let global = sym.get_binding().map_err(Error::msg)? == Binding::Global;
                 let visible = matches!(sym.get_other(), Visibility::Default);
@@ -207,20 +500,47 @@ impl Symgen {
                     section_name,
                     global,
                     visible,
+                    function,
+                    executable,
+                    rename: self.renames.get(name).map(String::as_str),
+                    demangle: self.demangle,
                 };
 
                 let pointer = (self.rust_pointer_gen)(&symbol);
 
-                if let Some(pointer) = pointer {
+                if let Some(mut pointer) = pointer {
+                    // Types inferred from DWARF already include the array length, if any
+                    let slice = pointer
+                        .r#type
+                        .as_ref()
+                        .map_or(false, |typ| !typ.starts_with('['));
+
+                    if pointer.r#type.is_none() {
+                        let ty = types.and_then(|types| {
+                            if function {
+                                types.function(name)
+                            } else {
+                                types.variable(name)
+                            }
+                        });
+
+                        if let Some(ty) = ty {
+                            pointer.r#type = Some(ty.name.clone());
+                            structs.extend(ty.structs.iter().cloned());
+                        }
+                    }
+
                     eprintln!("Writing symbol: {name} [{symbol:?}] as [{pointer:?}]");
-                    write!(
-                        output,
-                        "#[allow(dead_code, non_upper_case_globals)]\npub const {name}: *{mutable} {typ} = 0x{addr:x} as *{mutable} {typ};\n",
-                        name = pointer.name,
-                        mutable = if pointer.mutable { "mut" } else {"const" },
-                        typ = pointer.r#type.unwrap_or_else(|| "core::ffi::c_void".to_owned()),
-                        addr = self.start_addr + sym.value()
-                    )?;
+                    output.push(GeneratedSymbol {
+                        pointer,
+                        symbol: name.to_owned(),
+                        section: section_name.map(str::to_owned),
+                        addr: self.start_addr + sym.value(),
+                        size: sym.size(),
+                        size_const: sym.size() > 0 && !function,
+                        slice: slice && sym.size() > 0 && !function,
+                        function,
+                    });
                 } else {
                     eprintln!("Skipping symbol: {name} [{sym:?}]");
                 }
@@ -240,3 +560,400 @@ impl Symgen {
             .map(move |(index, header)| (index, header.get_data(elf).unwrap()))
     }
 }
+
+/// The symbols of the JSON manifest.
+#[derive(Serialize)]
+struct Manifest {
+    symbols: Vec<GeneratedSymbol>,
+}
+
+/// A symbol to be written to the generated file.
+#[derive(Serialize)]
+struct GeneratedSymbol {
+    #[serde(flatten)]
+    pointer: RustPointer,
+    /// The name of the symbol in the ELF file.
+    symbol: String,
+    section: Option<String>,
+    #[serde(rename = "address")]
+    addr: u64,
+    /// The size of the symbol in bytes, or `0` if unknown.
+    size: u64,
+    /// Whether to write the `_SIZE` constant, for symbols of a known size.
+    #[serde(skip)]
+    size_const: bool,
+    /// Whether to write a slice helper, for symbols of a known element type.
+    #[serde(skip)]
+    slice: bool,
+    function: bool,
+}
+
+impl GeneratedSymbol {
+    fn typ(&self) -> &str {
+        match &self.pointer.r#type {
+            Some(typ) => typ,
+            None if self.function => "unsafe extern \"C\" fn()",
+            None => "core::ffi::c_void",
+        }
+    }
+
+    fn write(&self, output: &mut impl Write) -> Result<()> {
+        let name = &self.pointer.name;
+        let typ = self.typ();
+
+        if self.function {
+            write!(
+                output,
+                "#[allow(dead_code, non_snake_case)]\n#[inline(always)]\npub fn {name}() -> {typ} {{\n    unsafe {{ core::mem::transmute::<usize, {typ}>(0x{addr:x}) }}\n}}\n",
+                addr = self.addr,
+            )?;
+
+            return Ok(());
+        }
+
+        let mutable = if self.pointer.mutable { "mut" } else { "const" };
+
+        write!(
+            output,
+            "#[allow(dead_code, non_upper_case_globals)]\npub const {name}: *{mutable} {typ} = 0x{addr:x} as *{mutable} {typ};\n",
+            addr = self.addr,
+        )?;
+
+        if self.size_const {
+            write!(
+                output,
+                "#[allow(dead_code, non_upper_case_globals)]\npub const {name}_SIZE: usize = {size};\n",
+                size = self.size,
+            )?;
+        }
+
+        if self.slice {
+            let from_raw_parts = if self.pointer.mutable {
+                "slice_from_raw_parts_mut"
+            } else {
+                "slice_from_raw_parts"
+            };
+
+            write!(
+                output,
+                "#[allow(dead_code, non_upper_case_globals)]\npub const {name}_LEN: usize = {name}_SIZE / core::mem::size_of::<{typ}>();\n\
+                 #[allow(dead_code, non_snake_case)]\npub fn {name}_slice() -> *{mutable} [{typ}] {{\n    core::ptr::{from_raw_parts}({name}, {name}_LEN)\n}}\n",
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sort the `symbols` by name and make their names unique.
+///
+/// Symbols of the same name at the same address (aliases) are written once. The other
+/// symbols sharing a name are suffixed with `_1`, `_2`, ... in the order of their ELF
+/// symbol names and addresses, so that the output does not depend on the order of the
+/// symbol table.
+///
+/// The names of the symbols take precedence over the `_SIZE`, `_LEN` and `_slice` items
+/// of other symbols, which are not written if they collide with one of them.
+fn resolve_collisions(symbols: &mut Vec<GeneratedSymbol>) {
+    let sort = |symbols: &mut Vec<GeneratedSymbol>| {
+        symbols.sort_by(|a, b| {
+            a.pointer
+                .name
+                .cmp(&b.pointer.name)
+                .then_with(|| a.symbol.cmp(&b.symbol))
+                .then(a.addr.cmp(&b.addr))
+        });
+    };
+
+    sort(symbols);
+    symbols.dedup_by(|a, b| a.pointer.name == b.pointer.name && a.addr == b.addr);
+
+    let mut names = symbols
+        .iter()
+        .map(|symbol| symbol.pointer.name.clone())
+        .collect::<HashSet<_>>();
+    let mut taken = HashSet::new();
+
+    let mut renamed = false;
+
+    for symbol in symbols.iter_mut() {
+        if !taken.insert(symbol.pointer.name.clone()) {
+            let name = symbol.pointer.name.clone();
+            let mut suffix = 1;
+
+            while names.contains(&format!("{name}_{suffix}")) {
+                suffix += 1;
+            }
+
+            symbol.pointer.name = format!("{name}_{suffix}");
+
+            eprintln!(
+                "Renaming symbol: {} to {}",
+                symbol.symbol, symbol.pointer.name
+            );
+            names.insert(symbol.pointer.name.clone());
+            taken.insert(symbol.pointer.name.clone());
+            renamed = true;
+        }
+    }
+
+    if renamed {
+        sort(symbols);
+    }
+
+    for symbol in symbols.iter_mut() {
+        let name = &symbol.pointer.name;
+
+        if symbol.size_const && names.contains(&format!("{name}_SIZE")) {
+            eprintln!("Skipping the size of symbol {name}, as it collides with symbol {name}_SIZE");
+            symbol.size_const = false;
+        }
+
+        if symbol.slice
+            && (!symbol.size_const
+                || names.contains(&format!("{name}_LEN"))
+                || names.contains(&format!("{name}_slice")))
+        {
+            eprintln!("Skipping the slice of symbol {name}, as it collides with another symbol");
+            symbol.slice = false;
+        }
+    }
+}
+
+/// Turn the function pointer type `typ`, e.g. `unsafe extern "C" fn(u32, ...) -> i32`,
+/// into the declaration of the function `name` in an `extern "C"` block.
+fn extern_declaration(name: &str, typ: &str) -> Result<String> {
+    let invalid = || anyhow!("Type '{typ}' of function '{name}' is not a function pointer type");
+
+    let params_start = typ.find("fn(").ok_or_else(invalid)? + 3;
+
+    let mut params = Vec::new();
+    let mut param_start = params_start;
+    let mut params_end = None;
+    let mut depth = 0;
+
+    for (index, c) in typ
+        .char_indices()
+        .skip_while(|(index, _)| *index < params_start)
+    {
+        match c {
+            '(' | '[' | '<' => depth += 1,
+            // Not the `>` of a `->`
+            ')' | ']' | '>' if depth > 0 && !typ[..index].ends_with('-') => depth -= 1,
+            ')' => {
+                params.push(&typ[param_start..index]);
+                params_end = Some(index + 1);
+                break;
+            }
+            ',' if depth == 0 => {
+                params.push(&typ[param_start..index]);
+                param_start = index + 1;
+            }
+            _ => (),
+        }
+    }
+
+    let ret = typ[params_end.ok_or_else(invalid)?..].trim();
+    if !ret.is_empty() && !ret.starts_with("->") {
+        return Err(invalid());
+    }
+
+    let params = params
+        .iter()
+        .map(|param| param.trim())
+        .filter(|param| !param.is_empty())
+        .enumerate()
+        .map(|(index, param)| {
+            if param == "..." {
+                param.to_owned()
+            } else {
+                format!("arg{index}: {param}")
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let ret = if ret.is_empty() {
+        String::new()
+    } else {
+        format!(" {ret}")
+    };
+
+    Ok(format!("pub fn {name}({}){ret};", params.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELF: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/symgen/testdata/symbols.elf"
+    );
+
+    fn generate(symgen: Symgen) -> String {
+        let mut output = Vec::new();
+        symgen.write(&mut output).unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    fn formats(format: Format) -> String {
+        generate(
+            Symgen::new_with_pointer_gen(ELF, 0x1000, |symbol| {
+                if ["matched", "counts"].contains(&symbol.name()) {
+                    symbol.default_sections()
+                } else {
+                    None
+                }
+            })
+            .dwarf_types(true)
+            .rename("matched", "MATCHED")
+            .format(format),
+        )
+    }
+
+    #[test]
+    fn rust_format() {
+        assert_eq!(
+            formats(Format::Rust),
+            r#"#[allow(dead_code, non_upper_case_globals)]
+pub const MATCHED: *mut match_ = 0x403008 as *mut match_;
+#[allow(dead_code, non_upper_case_globals)]
+pub const MATCHED_SIZE: usize = 4;
+#[allow(dead_code, non_upper_case_globals)]
+pub const counts: *mut [i32; 4] = 0x403010 as *mut [i32; 4];
+#[allow(dead_code, non_upper_case_globals)]
+pub const counts_SIZE: usize = 16;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code, non_camel_case_types, non_snake_case)]
+pub struct match_ {
+    pub a: i32,
+}
+"#
+        );
+    }
+
+    #[test]
+    fn linker_script_format() {
+        assert_eq!(
+            formats(Format::LinkerScript),
+            "PROVIDE(matched = 0x403008);\nPROVIDE(counts = 0x403010);\n"
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        assert_eq!(
+            formats(Format::Json),
+            r#"{
+  "symbols": [
+    {
+      "name": "MATCHED",
+      "mutable": true,
+      "type": "match_",
+      "symbol": "matched",
+      "section": ".data",
+      "address": 4206600,
+      "size": 4,
+      "function": false
+    },
+    {
+      "name": "counts",
+      "mutable": true,
+      "type": "[i32; 4]",
+      "symbol": "counts",
+      "section": ".bss",
+      "address": 4206608,
+      "size": 16,
+      "function": false
+    }
+  ]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn demangle() {
+        let output = generate(Symgen::new(ELF, 0));
+        assert!(output.contains("pub const _ZN2ns5valueE: "));
+
+        let output = generate(Symgen::new(ELF, 0).demangle(true));
+        assert!(output.contains("pub const ns_value: "));
+        assert!(!output.contains("_ZN2ns5valueE"));
+    }
+
+    #[test]
+    fn derived_names() {
+        let output = generate(Symgen::new_with_pointer_gen(ELF, 0, |symbol| {
+            symbol.default_sections().map(|mut pointer| {
+                if ["counts", "matched"].contains(&symbol.name()) {
+                    pointer.r#type = Some("i32".to_owned());
+                }
+
+                pointer
+            })
+        }));
+
+        assert!(output.contains("pub const matched: *mut i32 = 0x402008 as *mut i32;\n"));
+        assert!(output.contains("pub const matched_SIZE: usize = 4;\n"));
+        assert!(output.contains(
+            "pub const matched_LEN: usize = matched_SIZE / core::mem::size_of::<i32>();\n"
+        ));
+        assert!(output.contains(
+            "pub fn matched_slice() -> *mut [i32] {\n    core::ptr::slice_from_raw_parts_mut(matched, matched_LEN)\n}\n"
+        ));
+        // The `counts_SIZE` variable keeps its name, so `counts` has no size or slice
+        assert!(output.contains("pub const counts: *mut i32 = 0x402010 as *mut i32;\n"));
+        assert!(output.contains(
+            "pub const counts_SIZE: *mut core::ffi::c_void = 0x402004 as *mut core::ffi::c_void;\n"
+        ));
+        assert!(output.contains("pub const counts_SIZE_SIZE: usize = 4;\n"));
+        assert!(!output.contains("counts_LEN"));
+        assert!(!output.contains("counts_slice"));
+        assert!(!output.contains("counts_SIZE_1"));
+        // Symbols of an unknown size have no `_SIZE`
+        assert!(!output.contains("_end_SIZE"));
+    }
+
+    #[test]
+    fn functions() {
+        let output = generate(
+            Symgen::new(ELF, 0)
+                .dwarf_types(true)
+                .functions(FunctionSymbols::Extern)
+                .rename("add", "sum"),
+        );
+        assert!(output.contains(
+            "extern \"C\" {\n    #[link_name = \"add\"]\n    pub fn sum(arg0: i32, arg1: i32) -> i32;\n}\n"
+        ));
+
+        let output = generate(Symgen::new(ELF, 0).functions(FunctionSymbols::Pointers));
+        assert!(output.contains("pub fn add() -> unsafe extern \"C\" fn() {\n"));
+
+        let output = generate(Symgen::new(ELF, 0));
+        assert!(!output.contains("add"));
+    }
+
+    #[test]
+    fn extern_declarations() {
+        assert_eq!(
+            extern_declaration("foo", "unsafe extern \"C\" fn()").unwrap(),
+            "pub fn foo();"
+        );
+        assert_eq!(
+            extern_declaration("printf", "unsafe extern \"C\" fn(*const u8, ...) -> i32").unwrap(),
+            "pub fn printf(arg0: *const u8, ...) -> i32;"
+        );
+        assert_eq!(
+            extern_declaration(
+                "call",
+                "unsafe extern \"C\" fn(Option<unsafe extern \"C\" fn(u32) -> u32>, [u8; 4])"
+            )
+            .unwrap(),
+            "pub fn call(arg0: Option<unsafe extern \"C\" fn(u32) -> u32>, arg1: [u8; 4]);"
+        );
+        assert!(extern_declaration("foo", "u32").is_err());
+    }
+}
@@ -0,0 +1,787 @@
+//! Rust types of variables and functions, inferred from the DWARF debug info of an ELF file.
+
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use gimli::{constants, AttributeValue, EndianSlice, RunTimeEndian, SectionId, UnitOffset};
+use xmas_elf::header::Data;
+use xmas_elf::ElfFile;
+
+use super::names::sanitize;
+
+type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+type Unit<'a> = gimli::Unit<Reader<'a>>;
+
+const MAX_DEPTH: usize = 32;
+
+/// A Rust type with the layout of a C type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Type {
+    /// The Rust type, e.g. `[u32; 4]`.
+    pub name: String,
+    pub size: u64,
+    pub align: u64,
+    /// The generated structs this type refers to.
+    pub structs: BTreeSet<String>,
+}
+
+impl Type {
+    fn primitive(name: &str, size: u64) -> Self {
+        Self {
+            name: name.to_owned(),
+            size,
+            align: size.max(1),
+            structs: BTreeSet::new(),
+        }
+    }
+
+    fn bytes(size: u64) -> Self {
+        Self {
+            name: format!("[u8; {size}]"),
+            size,
+            align: 1,
+            structs: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct StructDef {
+    definition: String,
+    structs: BTreeSet<String>,
+}
+
+/// The types of the global variables and functions of an ELF file.
+#[derive(Debug, Default)]
+pub struct DwarfTypes {
+    variables: HashMap<String, Type>,
+    /// The function pointer types of the functions.
+    functions: HashMap<String, Type>,
+    structs: BTreeMap<String, StructDef>,
+}
+
+impl DwarfTypes {
+    /// Read the types of all global variables and functions from the DWARF info of `elf`.
+    ///
+    /// Variables whose type cannot be represented in Rust (bit fields, packed structs,
+    /// flexible array members, ...) are skipped, as are functions with such parameter
+    /// or return types.
+    pub fn load(elf: &ElfFile<'_>) -> Result<Self> {
+        let endian = match elf.header.pt1.data() {
+            Data::BigEndian => RunTimeEndian::Big,
+            _ => RunTimeEndian::Little,
+        };
+
+        let types = gimli::Dwarf::load(|id: SectionId| {
+            let data = elf
+                .find_section_by_name(id.name())
+                .map(|section| section.raw_data(elf))
+                .unwrap_or_default();
+
+            Ok::<_, gimli::Error>(EndianSlice::new(data, endian))
+        })
+        .and_then(|dwarf| {
+            let mut types = Self::default();
+            types.add_units(&dwarf)?;
+            Ok(types)
+        })
+        .map_err(|e| anyhow!("Could not read the DWARF info: {e}"))?;
+
+        Ok(types)
+    }
+
+    /// Get the type of the global variable `name`.
+    pub fn variable(&self, name: &str) -> Option<&Type> {
+        self.variables.get(name)
+    }
+
+    /// Get the function pointer type of the function `name`, e.g.
+    /// `unsafe extern "C" fn(u32, *mut u8) -> i32`.
+    pub fn function(&self, name: &str) -> Option<&Type> {
+        self.functions.get(name)
+    }
+
+    /// Write the definitions of `structs` and of all the structs they depend on.
+    pub fn write_structs<'a>(
+        &self,
+        structs: impl IntoIterator<Item = &'a String>,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        let mut pending = structs.into_iter().cloned().collect::<Vec<_>>();
+        let mut all = BTreeSet::new();
+
+        while let Some(name) = pending.pop() {
+            if all.insert(name.clone()) {
+                if let Some(def) = self.structs.get(&name) {
+                    pending.extend(def.structs.iter().cloned());
+                }
+            }
+        }
+
+        for name in all {
+            if let Some(def) = self.structs.get(&name) {
+                write!(output, "{}", def.definition)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_units(&mut self, dwarf: &gimli::Dwarf<Reader<'_>>) -> gimli::Result<()> {
+        let mut units = dwarf.units();
+
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let mut resolver = Resolver {
+                dwarf,
+                unit: &unit,
+                structs: &mut self.structs,
+                defined: Vec::new(),
+                cache: HashMap::new(),
+                in_progress: HashMap::new(),
+                depth: 0,
+            };
+
+            let mut entries = unit.entries();
+            let mut depth = 0;
+
+            while let Some((delta, entry)) = entries.next_dfs()? {
+                depth += delta;
+
+                let function = match entry.tag() {
+                    constants::DW_TAG_variable => false,
+                    // Only definitions, which refer to their declaration if any
+                    constants::DW_TAG_subprogram
+                        if entry.attr_value(constants::DW_AT_declaration)?.is_none() =>
+                    {
+                        true
+                    }
+                    _ => continue,
+                };
+
+                // A definition of a variable or function declared elsewhere
+                let decl = match entry.attr_value(constants::DW_AT_specification)? {
+                    Some(AttributeValue::UnitRef(offset)) => unit.entry(offset)?,
+                    _ => entry.clone(),
+                };
+
+                let linkage_name = match decl.attr_value(constants::DW_AT_linkage_name)? {
+                    Some(value) => Some(value),
+                    None => decl.attr_value(constants::DW_AT_MIPS_linkage_name)?,
+                };
+
+                // Only globals, not the static variables of functions
+                let name = match (linkage_name, depth) {
+                    (Some(name), _) => name,
+                    (None, 1) => match decl.attr_value(constants::DW_AT_name)? {
+                        Some(name) => name,
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                let name = dwarf
+                    .attr_string(&unit, name)?
+                    .to_string_lossy()
+                    .into_owned();
+
+                let target = match decl.attr_value(constants::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(offset)) => Some(offset),
+                    _ => None,
+                };
+
+                let types = if function {
+                    &mut self.functions
+                } else {
+                    &mut self.variables
+                };
+
+                if let hash_map::Entry::Vacant(vacant) = types.entry(name) {
+                    let ty = match (function, target) {
+                        (true, _) => resolver.function_type(entry.offset(), target)?,
+                        (false, Some(target)) => resolver.resolve(target)?,
+                        (false, None) => None,
+                    };
+
+                    if let Some(ty) = ty {
+                        vacant.insert(ty);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Resolver<'a, 'u> {
+    dwarf: &'a gimli::Dwarf<Reader<'u>>,
+    unit: &'a Unit<'u>,
+    structs: &'a mut BTreeMap<String, StructDef>,
+    /// The structs added to `structs` by this resolver, in order.
+    defined: Vec<String>,
+    cache: HashMap<UnitOffset, Option<Type>>,
+    /// The structs being resolved, to break reference cycles through pointers.
+    in_progress: HashMap<UnitOffset, Type>,
+    depth: usize,
+}
+
+impl Resolver<'_, '_> {
+    fn resolve(&mut self, offset: UnitOffset) -> gimli::Result<Option<Type>> {
+        if let Some(ty) = self.cache.get(&offset) {
+            return Ok(ty.clone());
+        }
+        if let Some(ty) = self.in_progress.get(&offset) {
+            return Ok(Some(ty.clone()));
+        }
+        if self.depth >= MAX_DEPTH {
+            return Ok(None);
+        }
+
+        self.depth += 1;
+        let ty = self.resolve_uncached(offset, None);
+        self.depth -= 1;
+
+        let ty = ty?;
+        self.cache.insert(offset, ty.clone());
+
+        Ok(ty)
+    }
+
+    fn resolve_uncached(
+        &mut self,
+        offset: UnitOffset,
+        typedef_name: Option<String>,
+    ) -> gimli::Result<Option<Type>> {
+        let entry = self.unit.entry(offset)?;
+        let byte_size = entry
+            .attr_value(constants::DW_AT_byte_size)?
+            .and_then(|value| value.udata_value());
+        let target = match entry.attr_value(constants::DW_AT_type)? {
+            Some(AttributeValue::UnitRef(offset)) => Some(offset),
+            _ => None,
+        };
+
+        let ty = match entry.tag() {
+            constants::DW_TAG_base_type => {
+                let encoding = match entry.attr_value(constants::DW_AT_encoding)? {
+                    Some(AttributeValue::Encoding(encoding)) => encoding,
+                    _ => return Ok(None),
+                };
+
+                byte_size.and_then(|size| base_type(encoding, size))
+            }
+            constants::DW_TAG_typedef => {
+                let target = match target {
+                    Some(target) => target,
+                    None => return Ok(None),
+                };
+
+                // `typedef struct { ... } name;`
+                let target_entry = self.unit.entry(target)?;
+                if target_entry.tag() == constants::DW_TAG_structure_type
+                    && target_entry.attr_value(constants::DW_AT_name)?.is_none()
+                {
+                    let name = self.name(&entry)?;
+                    self.resolve_uncached(target, name)?
+                } else {
+                    self.resolve(target)?
+                }
+            }
+            constants::DW_TAG_const_type
+            | constants::DW_TAG_volatile_type
+            | constants::DW_TAG_atomic_type
+            | constants::DW_TAG_restrict_type => match target {
+                Some(target) => self.resolve(target)?,
+                None => None,
+            },
+            constants::DW_TAG_pointer_type => {
+                let pointee = match target {
+                    Some(target) => self.resolve(target)?,
+                    None => None,
+                };
+                let pointee = pointee.unwrap_or_else(|| Type::primitive("core::ffi::c_void", 0));
+                let size = byte_size.unwrap_or(self.unit.encoding().address_size as u64);
+
+                Some(Type {
+                    name: format!("*mut {}", pointee.name),
+                    structs: pointee.structs,
+                    ..Type::primitive("", size)
+                })
+            }
+            constants::DW_TAG_array_type => {
+                let element = match target {
+                    Some(target) => self.resolve(target)?,
+                    None => None,
+                };
+
+                match (element, self.array_dimensions(offset)?) {
+                    (Some(element), Some(dimensions)) => Some(dimensions.iter().rev().fold(
+                        element,
+                        |element, count| Type {
+                            name: format!("[{}; {count}]", element.name),
+                            size: element.size * count,
+                            ..element
+                        },
+                    )),
+                    _ => None,
+                }
+            }
+            constants::DW_TAG_structure_type => {
+                let name = match typedef_name {
+                    Some(name) => Some(name),
+                    None => self.name(&entry)?,
+                };
+
+                match (name, byte_size) {
+                    (Some(name), Some(size))
+                        if entry.attr_value(constants::DW_AT_declaration)?.is_none() =>
+                    {
+                        self.struct_type(offset, &name, size)?
+                    }
+                    _ => None,
+                }
+            }
+            constants::DW_TAG_union_type => byte_size.map(Type::bytes),
+            constants::DW_TAG_enumeration_type => match target {
+                Some(target) => self.resolve(target)?,
+                None => byte_size.and_then(|size| base_type(constants::DW_ATE_unsigned, size)),
+            },
+            _ => None,
+        };
+
+        Ok(ty)
+    }
+
+    /// Get the function pointer type of the function at `offset`, returning `ret`.
+    fn function_type(
+        &mut self,
+        offset: UnitOffset,
+        ret: Option<UnitOffset>,
+    ) -> gimli::Result<Option<Type>> {
+        let mut params = Vec::new();
+        let mut variadic = false;
+
+        let mut tree = self.unit.entries_tree(Some(offset))?;
+        let mut children = tree.root()?.children();
+
+        while let Some(child) = children.next()? {
+            let entry = child.entry();
+
+            match entry.tag() {
+                constants::DW_TAG_formal_parameter => {
+                    match entry.attr_value(constants::DW_AT_type)? {
+                        Some(AttributeValue::UnitRef(offset)) => params.push(offset),
+                        _ => return Ok(None),
+                    }
+                }
+                constants::DW_TAG_unspecified_parameters => variadic = true,
+                _ => (),
+            }
+        }
+
+        // Rust requires at least one parameter before the variadic ones
+        if variadic && params.is_empty() {
+            return Ok(None);
+        }
+
+        let mut structs = BTreeSet::new();
+        let mut names = Vec::new();
+
+        for param in params {
+            match self.resolve(param)? {
+                Some(ty) => {
+                    structs.extend(ty.structs);
+                    names.push(ty.name);
+                }
+                None => return Ok(None),
+            }
+        }
+
+        if variadic {
+            names.push("...".to_owned());
+        }
+
+        let ret = match ret {
+            Some(ret) => match self.resolve(ret)? {
+                Some(ty) => {
+                    structs.extend(ty.structs);
+                    format!(" -> {}", ty.name)
+                }
+                None => return Ok(None),
+            },
+            None => String::new(),
+        };
+
+        let size = self.unit.encoding().address_size as u64;
+
+        Ok(Some(Type {
+            name: format!("unsafe extern \"C\" fn({}){ret}", names.join(", ")),
+            structs,
+            ..Type::primitive("", size)
+        }))
+    }
+
+    fn struct_type(
+        &mut self,
+        offset: UnitOffset,
+        name: &str,
+        byte_size: u64,
+    ) -> gimli::Result<Option<Type>> {
+        let name = sanitize(name);
+        let defined = self.defined.len();
+
+        self.in_progress.insert(
+            offset,
+            Type {
+                name: name.clone(),
+                structs: [name.clone()].into_iter().collect(),
+                ..Type::bytes(byte_size)
+            },
+        );
+        let ty = self.struct_definition(offset, name.clone(), byte_size);
+        self.in_progress.remove(&offset);
+
+        let ty = ty?;
+        if ty.is_none() {
+            self.reject(name, defined);
+        }
+
+        Ok(ty)
+    }
+
+    /// Forget the types which refer to the rejected struct `name`, i.e. pointers to it
+    /// resolved while its members were, including the structs defined since `defined`.
+    fn reject(&mut self, name: String, defined: usize) {
+        let mut rejected = [name].into_iter().collect::<BTreeSet<_>>();
+        let mut candidates = self.defined.split_off(defined);
+
+        loop {
+            let count = candidates.len();
+
+            candidates.retain(|candidate| {
+                let refers = self
+                    .structs
+                    .get(candidate)
+                    .map_or(false, |def| !def.structs.is_disjoint(&rejected));
+
+                if refers {
+                    self.structs.remove(candidate);
+                    rejected.insert(candidate.clone());
+                }
+
+                !refers
+            });
+
+            if candidates.len() == count {
+                break;
+            }
+        }
+
+        self.defined.extend(candidates);
+        self.cache.retain(|_, ty| {
+            ty.as_ref()
+                .map_or(true, |ty| ty.structs.is_disjoint(&rejected))
+        });
+    }
+
+    fn struct_definition(
+        &mut self,
+        offset: UnitOffset,
+        name: String,
+        byte_size: u64,
+    ) -> gimli::Result<Option<Type>> {
+        let members = match self.struct_members(offset)? {
+            Some(members) => members,
+            None => return Ok(None),
+        };
+
+        let (fields, align) = match layout(&members, byte_size) {
+            Some(layout) => layout,
+            None => return Ok(None),
+        };
+
+        let mut structs = members
+            .iter()
+            .flat_map(|(_, ty, _)| ty.structs.iter().cloned())
+            .collect::<BTreeSet<_>>();
+        structs.remove(&name);
+
+        let mut definition = format!(
+            "#[repr(C)]\n#[derive(Debug, Copy, Clone)]\n#[allow(dead_code, non_camel_case_types, non_snake_case)]\npub struct {name} {{\n"
+        );
+        for (field, ty) in fields {
+            definition.push_str(&format!("    pub {field}: {ty},\n"));
+        }
+        definition.push_str("}\n");
+
+        let def = StructDef {
+            definition,
+            structs: structs.clone(),
+        };
+
+        // Structs of the same name must have the same definition in all units
+        match self.structs.get(&name) {
+            Some(existing) if *existing != def => return Ok(None),
+            Some(_) => (),
+            None => {
+                self.structs.insert(name.clone(), def);
+                self.defined.push(name.clone());
+            }
+        }
+
+        structs.insert(name.clone());
+
+        Ok(Some(Type {
+            name,
+            size: byte_size,
+            align,
+            structs,
+        }))
+    }
+
+    fn struct_members(&mut self, offset: UnitOffset) -> gimli::Result<Option<Vec<Member>>> {
+        let mut members = Vec::new();
+
+        let mut tree = self.unit.entries_tree(Some(offset))?;
+        let mut children = tree.root()?.children();
+
+        let mut entries = Vec::new();
+        while let Some(child) = children.next()? {
+            if child.entry().tag() == constants::DW_TAG_member {
+                entries.push(child.entry().clone());
+            }
+        }
+
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.attr_value(constants::DW_AT_bit_size)?.is_some()
+                || entry
+                    .attr_value(constants::DW_AT_data_bit_offset)?
+                    .is_some()
+            {
+                return Ok(None);
+            }
+
+            let name = self
+                .name(entry)?
+                .map(|name| sanitize(&name))
+                .unwrap_or_else(|| format!("anon{index}"));
+
+            let member_offset = match entry.attr_value(constants::DW_AT_data_member_location)? {
+                Some(value) => match value.udata_value() {
+                    Some(offset) => offset,
+                    None => return Ok(None),
+                },
+                None => 0,
+            };
+
+            let ty = match entry.attr_value(constants::DW_AT_type)? {
+                Some(AttributeValue::UnitRef(offset)) => self.resolve(offset)?,
+                _ => None,
+            };
+
+            match ty {
+                Some(ty) => members.push((name, ty, member_offset)),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(members))
+    }
+
+    /// Get the element counts of the dimensions of an array, outermost first.
+    fn array_dimensions(&self, offset: UnitOffset) -> gimli::Result<Option<Vec<u64>>> {
+        let mut dimensions = Vec::new();
+
+        let mut tree = self.unit.entries_tree(Some(offset))?;
+        let mut children = tree.root()?.children();
+
+        while let Some(child) = children.next()? {
+            let entry = child.entry();
+            if entry.tag() != constants::DW_TAG_subrange_type {
+                continue;
+            }
+
+            let count = match entry.attr_value(constants::DW_AT_count)? {
+                Some(count) => count.udata_value(),
+                None => match entry.attr_value(constants::DW_AT_upper_bound)? {
+                    Some(AttributeValue::Sdata(bound)) if bound >= 0 => Some(bound as u64 + 1),
+                    Some(AttributeValue::Sdata(_)) => None,
+                    Some(bound) => bound.udata_value().map(|bound| bound + 1),
+                    None => None,
+                },
+            };
+
+            match count {
+                Some(count) => dimensions.push(count),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(if dimensions.is_empty() {
+            None
+        } else {
+            Some(dimensions)
+        })
+    }
+
+    fn name(
+        &self,
+        entry: &gimli::DebuggingInformationEntry<'_, '_, Reader<'_>>,
+    ) -> gimli::Result<Option<String>> {
+        match entry.attr_value(constants::DW_AT_name)? {
+            Some(name) => Ok(Some(
+                self.dwarf
+                    .attr_string(self.unit, name)?
+                    .to_string_lossy()
+                    .into_owned(),
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+type Member = (String, Type, u64);
+
+fn base_type(encoding: constants::DwAte, size: u64) -> Option<Type> {
+    let name = match (encoding, size) {
+        (constants::DW_ATE_boolean, 1) => "bool",
+        (constants::DW_ATE_float, 4) => "f32",
+        (constants::DW_ATE_float, 8) => "f64",
+        (constants::DW_ATE_signed | constants::DW_ATE_signed_char, _) => match size {
+            1 => "i8",
+            2 => "i16",
+            4 => "i32",
+            8 => "i64",
+            16 => "i128",
+            _ => return None,
+        },
+        (
+            constants::DW_ATE_unsigned
+            | constants::DW_ATE_unsigned_char
+            | constants::DW_ATE_boolean
+            | constants::DW_ATE_UTF,
+            _,
+        ) => match size {
+            1 => "u8",
+            2 => "u16",
+            4 => "u32",
+            8 => "u64",
+            16 => "u128",
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(Type::primitive(name, size))
+}
+
+/// Lay out the `members` of a C struct of `size` bytes as the fields of a `#[repr(C)]`
+/// Rust struct, adding padding fields where the C layout differs from the natural
+/// alignment.
+///
+/// Returns the fields and the alignment of the struct, or [`None`] if the struct is
+/// packed.
+fn layout(members: &[Member], size: u64) -> Option<(Vec<(String, String)>, u64)> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    let mut align = 1;
+
+    for (name, ty, offset) in members {
+        let offset = *offset;
+
+        if offset % ty.align != 0 || offset < align_up(pos, ty.align) {
+            return None;
+        }
+
+        if offset > align_up(pos, ty.align) {
+            fields.push((
+                format!("_pad{}", fields.len()),
+                Type::bytes(offset - pos).name,
+            ));
+        }
+
+        fields.push((name.clone(), ty.name.clone()));
+        pos = offset + ty.size;
+        align = align.max(ty.align);
+    }
+
+    if size % align != 0 || size < align_up(pos, align) {
+        return None;
+    }
+
+    if size > align_up(pos, align) {
+        fields.push((
+            format!("_pad{}", fields.len()),
+            Type::bytes(size - pos).name,
+        ));
+    }
+
+    Some((fields, align))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(name: &str, ty: &str, size: u64, offset: u64) -> Member {
+        (name.to_owned(), Type::primitive(ty, size), offset)
+    }
+
+    #[test]
+    fn struct_layout() {
+        // struct { uint8_t a; uint32_t b; uint16_t c; }
+        let natural = [
+            member("a", "u8", 1, 0),
+            member("b", "u32", 4, 4),
+            member("c", "u16", 2, 8),
+        ];
+        let (fields, align) = layout(&natural, 12).unwrap();
+        assert_eq!(align, 4);
+        assert_eq!(fields.len(), 3);
+
+        // struct { uint8_t a; uint8_t b __attribute__((aligned(8))); }
+        let aligned = [member("a", "u8", 1, 0), member("b", "u8", 1, 8)];
+        let (fields, _) = layout(&aligned, 16).unwrap();
+        assert_eq!(
+            fields,
+            [
+                ("a".to_owned(), "u8".to_owned()),
+                ("_pad1".to_owned(), "[u8; 7]".to_owned()),
+                ("b".to_owned(), "u8".to_owned()),
+                ("_pad3".to_owned(), "[u8; 7]".to_owned()),
+            ]
+        );
+
+        // struct __attribute__((packed)) { uint8_t a; uint32_t b; }
+        let packed = [member("a", "u8", 1, 0), member("b", "u32", 4, 1)];
+        assert_eq!(layout(&packed, 5), None);
+    }
+
+    #[test]
+    fn rejected_structs() {
+        let data = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/symgen/testdata/symbols.elf"
+        ))
+        .unwrap();
+        let types = DwarfTypes::load(&ElfFile::new(&data).unwrap()).unwrap();
+
+        // `struct A { struct A *next; int x : 3; }` has a bit field, so neither it nor the
+        // pointer to it, which was resolved along with the type of `head`, may be used
+        let list = types.variable("list").unwrap();
+        assert_eq!(list.name, "*mut core::ffi::c_void");
+        assert!(list.structs.is_empty());
+        assert!(!types.structs.contains_key("A"));
+
+        let matched = types.variable("matched").unwrap();
+        assert_eq!(matched.name, "match_");
+        assert!(types.structs["match_"]
+            .definition
+            .contains("pub struct match_ {"));
+    }
+}
@@ -0,0 +1,98 @@
+//! Rust identifiers for the names of symbols.
+
+use std::borrow::Cow;
+
+use cpp_demangle::DemangleOptions;
+
+/// The keywords of Rust, which cannot be used as identifiers.
+pub const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while", "abstract", "become", "box", "do", "final", "macro",
+    "override", "priv", "try", "typeof", "unsized", "virtual", "yield",
+];
+
+/// Demangle a Rust or C++ symbol name, without the hash of Rust symbols and without the
+/// parameters of C++ functions, e.g. `core::fmt::write` or `ns::Foo::bar`.
+///
+/// Returns the name unchanged if it is not mangled.
+pub fn demangle(name: &str) -> Cow<'_, str> {
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        return Cow::Owned(format!("{demangled:#}"));
+    }
+
+    if name.starts_with("_Z") {
+        let demangled = cpp_demangle::Symbol::new(name).ok().and_then(|symbol| {
+            symbol
+                .demangle(&DemangleOptions::new().no_params().no_return_type())
+                .ok()
+        });
+
+        if let Some(demangled) = demangled {
+            return Cow::Owned(demangled);
+        }
+    }
+
+    Cow::Borrowed(name)
+}
+
+/// Turn `name` into a valid Rust identifier.
+///
+/// Each run of characters other than ASCII letters, digits and `_` is replaced with a
+/// single `_` (and dropped at the start and the end of the name), names starting with a
+/// digit are prefixed with `_` and keywords are suffixed with `_`, e.g. `ns::Foo::bar`
+/// becomes `ns_Foo_bar` and `foo.1234` becomes `foo_1234`.
+pub fn sanitize(name: &str) -> String {
+    let mut ident = String::with_capacity(name.len());
+    let mut replaced = false;
+
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            if replaced && !ident.is_empty() {
+                ident.push('_');
+            }
+
+            ident.push(c);
+            replaced = false;
+        } else {
+            replaced = true;
+        }
+    }
+
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+
+    if ident == "_" || KEYWORDS.contains(&ident.as_str()) {
+        ident.push('_');
+    }
+
+    ident
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifiers() {
+        let ident = |name: &str| sanitize(&demangle(name));
+
+        assert_eq!(ident("__stack_start"), "__stack_start");
+        assert_eq!(ident("foo.1234"), "foo_1234");
+        assert_eq!(ident("foo.constprop.0"), "foo_constprop_0");
+        assert_eq!(
+            ident("_ZN4core3fmt5write17h0123456789abcdefE"),
+            "core_fmt_write"
+        );
+        assert_eq!(ident("_RNvCs1234_7mycrate3foo"), "mycrate_foo");
+        assert_eq!(ident("_ZN2ns3Foo3bazEif"), "ns_Foo_baz");
+        assert_eq!(ident("_ZTV3Foo"), "vtable_Foo");
+        assert_eq!(ident("1st"), "_1st");
+        assert_eq!(ident("type"), "type_");
+        assert_eq!(ident("Self"), "Self_");
+        assert_eq!(ident("1st.x"), "_1st_x");
+        assert_eq!(ident("."), "__");
+    }
+}
@@ -0,0 +1,31 @@
+/* Test input of symgen, built with:
+ * gcc -g -gdwarf-4 -Os -static -nostdlib -fno-pie -no-pie -fno-asynchronous-unwind-tables
+ *     -Wl,-e,0 -Wl,--build-id=none -o symbols.elf symbols.c
+ */
+
+/* Rejected because of the bit field, after its `next` member was resolved */
+struct A {
+    struct A *next;
+    int x : 3;
+};
+
+struct A head;
+struct A *list;
+
+/* A Rust keyword */
+struct match {
+    int a;
+};
+
+struct match matched = { 1 };
+
+/* Collides with the `counts_SIZE` constant of `counts` */
+int counts[4];
+int counts_SIZE = 2;
+
+int add(int a, int b) {
+    return a + b;
+}
+
+/* A mangled C++ name */
+int ns_value __asm__("_ZN2ns5valueE") = 3;